@@ -0,0 +1,61 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Memory aligned buffers, O_DIRECT reads and writes need buffers aligned to the logical block size.
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/// Zeroed byte buffer which start address is aligned, freed with the same alignment
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// It owns its memory like a Vec<u8>
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+/// Allocate size zeroed bytes aligned to align, which must be a power of two.
+/// Not a Vec<u8> as a Vec would free the memory with the alignment of u8.
+pub fn alloc_aligned(size: usize, align: usize) -> AlignedBuffer {
+    // Zero sized allocations are not allowed
+    let layout = Layout::from_size_align(size.max(1), align).expect("invalid buffer alignment");
+    let ptr = unsafe { alloc_zeroed(layout) };
+    match NonNull::new(ptr){
+        Some(ptr) => AlignedBuffer { ptr, len: size, layout },
+        None => handle_alloc_error(layout),
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
@@ -0,0 +1,82 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! ASCII map of the chunks a sync wrote, one character per chunk: `.` identical, `#` written, `E` error.
+//! A large device has more chunks than fit on a screen, then a character stands for several chunks.
+use crate::SyncStats;
+
+/// Height of the map at most, in lines
+const MAX_ROWS: usize = 32;
+
+/// What the sync did to a chunk, in increasing priority when chunks share a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum ChunkState {
+    #[default]
+    Identical,
+    Written,
+    Error,
+}
+
+impl ChunkState {
+    fn symbol(self) -> char {
+        match self{
+            ChunkState::Identical => '.',
+            ChunkState::Written => '#',
+            ChunkState::Error => 'E',
+        }
+    }
+}
+
+/// State of each chunk_size chunk from start to end in the written ranges and the error log of stats
+pub fn chunk_states(stats: &SyncStats, start: u64, end: u64, chunk_size: u64) -> Vec<ChunkState> {
+    let chunk_size = chunk_size.max(1);
+    let mut states = vec![ChunkState::Identical; end.saturating_sub(start).div_ceil(chunk_size) as usize];
+    let mut mark = |offset: u64, len: u64, state: ChunkState| {
+        if len == 0 || offset + len <= start || offset >= end{
+            return;
+        }
+        let first = (offset.max(start) - start) / chunk_size;
+        let last = ((offset + len).min(end) - start - 1) / chunk_size;
+        for chunk in &mut states[first as usize ..= last as usize]{
+            *chunk = (*chunk).max(state);
+        }
+    };
+    for &(offset, len) in stats.destinations.iter().flat_map(|dest| &dest.written_ranges){
+        mark(offset, len as u64, ChunkState::Written);
+    }
+    for &(offset, _) in &stats.error_log.entries{
+        mark(offset, 1, ChunkState::Error);
+    }
+    states
+}
+
+/// Number of chunks per character to fit states in terminal_width columns and MAX_ROWS lines
+pub fn chunks_per_char(chunks: usize, terminal_width: usize) -> usize {
+    chunks.div_ceil(terminal_width.max(1) * MAX_ROWS).max(1)
+}
+
+/// Grid of lines of terminal_width characters at most, a character shows the state with the highest priority
+/// of its chunks
+pub fn render_chunk_states(states: &[ChunkState], terminal_width: usize) -> String {
+    let width = terminal_width.max(1);
+    let per_char = chunks_per_char(states.len(), width);
+    let chars: Vec<char> = states.chunks(per_char).map(|chunks| chunks.iter().max().copied().unwrap_or_default().symbol()).collect();
+    chars.chunks(width).map(|line| line.iter().collect::<String>()).collect::<Vec<_>>().join("\n")
+}
+
+/// Grid of `.` for identical and `#` for written chunks, written[i] tells if chunk i was written
+pub fn render_block_map(written: &[bool], terminal_width: usize) -> String {
+    let states: Vec<ChunkState> = written.iter().map(|&written| if written {ChunkState::Written} else {ChunkState::Identical}).collect();
+    render_chunk_states(&states, terminal_width)
+}
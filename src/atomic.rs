@@ -0,0 +1,129 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Atomic mode: regular file destinations are synced through a temporary copy renamed over them at the end,
+//! so an interrupted or failed sync leaves them intact.
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use nix::{ioctl_write_int_bad, request_code_write};
+use crate::{is_block_device, sync_paths_multi, SyncError, SyncOptions, SyncStats};
+
+ioctl_write_int_bad!(ioctl_ficlone, request_code_write!(0x94, 9, std::mem::size_of::<nix::libc::c_int>())); // Defined in linux/fs.h
+
+/// Suffix of the temporary copy of a destination
+pub const TMP_SUFFIX: &str = ".localblocksync.tmp";
+
+/// Copy src to dst, sharing the data blocks (reflink) if the filesystem supports it, e.g. btrfs or XFS.
+/// Return true if cloned, false if the data was copied.
+pub fn reflink_copy(src: &Path, dst: &Path) -> io::Result<bool> {
+    let src_file = File::open(src)?;
+    let dst_file = File::create(dst)?;
+    match unsafe { ioctl_ficlone(dst_file.as_raw_fd(), src_file.as_raw_fd()) }{
+        Ok(_) => {
+            dst_file.set_permissions(src_file.metadata()?.permissions())?;
+            Ok(true)
+        },
+        // Not supported by the filesystem, or src and dst on different filesystems
+        Err(_) => {
+            drop(dst_file);
+            fs::copy(src, dst)?;
+            Ok(false)
+        },
+    }
+}
+
+/// Temporary copy path of a destination
+fn tmp_path(dst_path: &Path) -> PathBuf {
+    let mut tmp_path = OsString::from(dst_path);
+    tmp_path.push(TMP_SUFFIX);
+    PathBuf::from(tmp_path)
+}
+
+/// Flush the data of path and rename it to dst_path, then flush the directory so the rename is on disk
+fn commit(tmp_path: &Path, dst_path: &Path) -> io::Result<()> {
+    File::open(tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, dst_path)?;
+    let dir = match dst_path.parent(){
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Sync src_path to a temporary copy of each destination, rename each copy over its destination when it succeeded.
+/// If interrupted, nothing is renamed. Temporary copies not renamed are removed.
+pub(crate) fn sync_atomic(src_path: &Path, dst_paths: &[PathBuf], opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    if opts.dry_run{
+        // Nothing is written anyway
+        return sync_paths_multi(src_path, dst_paths, opts.atomic(false));
+    }
+    if opts.output_delta.is_some() || opts.write_journal.is_some() || opts.checkpoint_path.is_some(){
+        return Err(SyncError::InvalidOptions("atomic mode cannot be used with a delta file, a journal nor resume".to_string()));
+    }
+    if let Some(dst_path) = dst_paths.iter().find(|dst_path| is_block_device(dst_path)){
+        return Err(SyncError::InvalidOptions(format!("atomic mode needs regular file destinations, {} is a block device", dst_path.display())));
+    }
+    let mut tmp_paths = Vec::new();
+    for dst_path in dst_paths{
+        let tmp_path = tmp_path(dst_path);
+        if dst_path.exists(){
+            match reflink_copy(dst_path, &tmp_path){
+                Ok(true) => log::info!("Cloned {:?} to {:?}", dst_path, tmp_path),
+                Ok(false) => log::info!("Copied {:?} to {:?}", dst_path, tmp_path),
+                Err(err) => {
+                    remove_tmp_paths(&tmp_paths);
+                    let _ = fs::remove_file(&tmp_path);
+                    return Err(SyncError::DestOpenFailed(tmp_path, err));
+                },
+            }
+        }
+        tmp_paths.push(tmp_path);
+    }
+    let mut stats = match sync_paths_multi(src_path, &tmp_paths, opts.atomic(false)){
+        Ok(stats) => stats,
+        Err(err) => {
+            remove_tmp_paths(&tmp_paths);
+            return Err(err);
+        },
+    };
+    for ((dest, tmp_path), dst_path) in stats.destinations.iter_mut().zip(&tmp_paths).zip(dst_paths){
+        dest.path = dst_path.to_path_buf();
+        if stats.interrupted || dest.error.is_some(){
+            let _ = fs::remove_file(tmp_path);
+            continue;
+        }
+        if let Err(err) = commit(tmp_path, dst_path){
+            let _ = fs::remove_file(tmp_path);
+            if dst_paths.len() == 1{
+                return Err(SyncError::FlushFailed(dst_path.to_path_buf(), err));
+            }
+            log::error!("Failed to replace {} by {}: {}", dst_path.display(), tmp_path.display(), err);
+            dest.error = Some(SyncError::FlushFailed(dst_path.to_path_buf(), err).to_string());
+        }
+    }
+    if stats.interrupted{
+        log::warn!("Interrupted, destinations are left unchanged");
+    }
+    Ok(stats)
+}
+
+/// Remove temporary copies, best effort
+fn remove_tmp_paths(tmp_paths: &[PathBuf]){
+    for tmp_path in tmp_paths{
+        let _ = fs::remove_file(tmp_path);
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Raw read speed of source and destinations, to know if the hardware can sustain a sync. Nothing is written.
+use std::io::{self, prelude::*};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use crate::{SyncError, SyncOptions};
+
+/// Bytes read from one path and the time it took
+#[derive(Debug, Clone)]
+pub struct ReadBenchmark {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl ReadBenchmark {
+    /// Read speed in MB/s
+    pub fn rate_mb(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(0.000001) / 1024. / 1024.
+    }
+}
+
+/// Read speed of the source and of each destination
+#[derive(Debug, Clone)]
+pub struct BenchmarkStats {
+    pub source: ReadBenchmark,
+    pub destinations: Vec<ReadBenchmark>,
+}
+
+impl BenchmarkStats {
+    /// True if a destination reads slower than the source
+    pub fn destination_bottleneck(&self) -> bool {
+        self.destinations.iter().any(|dest| dest.rate_mb() < self.source.rate_mb())
+    }
+}
+
+/// Open path, drop its cached pages so the disk is really read, and place it at offset
+fn open_uncached(path: &Path, offset: u64, len: usize) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    // Best effort, page cache is only a hint
+    let _ = posix_fadvise(file.as_raw_fd(), offset as i64, len as i64, PosixFadviseAdvice::POSIX_FADV_DONTNEED);
+    file.seek(io::SeekFrom::Start(offset))?;
+    Ok(file)
+}
+
+/// Read until buf is full or end of file, return bytes read and elapsed time
+fn timed_read(mut file: &File, buf: &mut [u8]) -> (io::Result<usize>, Duration) {
+    let start_time = Instant::now();
+    let mut len = 0;
+    while len < buf.len(){
+        match file.read(&mut buf[len ..]){
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return (Err(err), start_time.elapsed()),
+        }
+    }
+    (Ok(len), start_time.elapsed())
+}
+
+/// Read one buffer from the source and from each destination at the offset of the options.
+/// In threaded mode, the source is read at the same time as the first destination like during a sync.
+pub fn benchmark_paths(src_path: &Path, dst_paths: &[PathBuf], opts: SyncOptions) -> Result<BenchmarkStats, SyncError> {
+    let SyncOptions { threaded, buffer_size, offset, .. } = opts;
+    if dst_paths.is_empty(){
+        return Err(SyncError::InvalidOptions("at least one destination is needed".to_string()));
+    }
+    let src_file = match open_uncached(src_path, offset, buffer_size){
+        Ok(src_file) => src_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    let mut dst_files = Vec::new();
+    for dst_path in dst_paths{
+        match open_uncached(dst_path, offset, buffer_size){
+            Ok(dst_file) => dst_files.push(dst_file),
+            Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+        }
+    }
+    let mut buffer_src = vec![0u8; buffer_size];
+    let mut buffer_dst = vec![0u8; buffer_size];
+    let (src_read, first_dst_read) = if threaded{
+        thread::scope(|scope| {
+            let thandle = scope.spawn(|| timed_read(&src_file, &mut buffer_src));
+            let dst_read = timed_read(&dst_files[0], &mut buffer_dst);
+            (thandle.join().unwrap(), dst_read)
+        })
+    }else{
+        (timed_read(&src_file, &mut buffer_src), timed_read(&dst_files[0], &mut buffer_dst))
+    };
+    let source = match src_read{
+        (Ok(bytes), elapsed) => ReadBenchmark { path: src_path.to_path_buf(), bytes: bytes as u64, elapsed },
+        (Err(err), _) => return Err(SyncError::SourceReadFailed { offset, source: err }),
+    };
+    let mut destinations = Vec::new();
+    let mut first_dst_read = Some(first_dst_read);
+    for (dst_path, dst_file) in dst_paths.iter().zip(&dst_files){
+        let dst_read = first_dst_read.take().unwrap_or_else(|| timed_read(dst_file, &mut buffer_dst));
+        match dst_read{
+            (Ok(bytes), elapsed) => destinations.push(ReadBenchmark { path: dst_path.to_path_buf(), bytes: bytes as u64, elapsed }),
+            (Err(err), _) => return Err(SyncError::DestReadFailed { offset, source: err }),
+        }
+    }
+    Ok(BenchmarkStats { source, destinations })
+}
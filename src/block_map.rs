@@ -0,0 +1,92 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Block map, the 512-byte sectors written by a sync, for audit trails.
+//!
+//! Formats:
+//! - binary: sorted sector offsets in bytes as little endian u64, without header
+//! - text: one sector offset in bytes per line
+//! - ranges: `start_byte,end_byte` lines (end excluded) of adjacent sectors coalesced, the format of --exclude-ranges
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*, BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Output format of a block map file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockMapFormat {
+    #[default]
+    Binary,
+    Text,
+    Ranges,
+}
+
+/// Set of written sectors, by offset in bytes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockMap {
+    pub sectors: BTreeSet<u64>,
+}
+
+impl BlockMap {
+    pub fn new() -> Self {
+        BlockMap::default()
+    }
+
+    /// Add every sector touched by the region of len bytes at offset
+    pub fn insert_range(&mut self, offset: u64, len: u64){
+        if len == 0{
+            return;
+        }
+        let first = offset / SECTOR_SIZE;
+        let last = (offset + len - 1) / SECTOR_SIZE;
+        self.sectors.extend((first ..= last).map(|sector| sector * SECTOR_SIZE));
+    }
+
+    /// Adjacent sectors coalesced in ranges (start, end), end excluded
+    pub fn to_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for &sector in &self.sectors{
+            match ranges.last_mut(){
+                Some((_, end)) if *end == sector => *end += SECTOR_SIZE,
+                _ => ranges.push((sector, sector + SECTOR_SIZE)),
+            }
+        }
+        ranges
+    }
+
+    /// Write the block map to path in the given format, replace the file if it exists
+    pub fn save(&self, path: &Path, format: BlockMapFormat) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format{
+            BlockMapFormat::Binary => {
+                for sector in &self.sectors{
+                    writer.write_all(&sector.to_le_bytes())?;
+                }
+            },
+            BlockMapFormat::Text => {
+                for sector in &self.sectors{
+                    writeln!(writer, "{}", sector)?;
+                }
+            },
+            BlockMapFormat::Ranges => {
+                for (start, end) in self.to_ranges(){
+                    writeln!(writer, "{},{}", start, end)?;
+                }
+            },
+        }
+        writer.flush()
+    }
+}
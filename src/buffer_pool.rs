@@ -0,0 +1,107 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Pool of sync buffers, to reuse the large buffer allocations when syncing many files in a row with the library
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::aligned::{alloc_aligned, AlignedBuffer};
+use crate::DIRECT_ALIGN;
+
+/// Buffers released by their guard, given again to the next acquire of the same size
+#[derive(Default)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<AlignedBuffer>>,
+    allocations: AtomicUsize,
+}
+
+impl std::fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let buffers = self.buffers.lock().unwrap();
+        f.debug_struct("BufferPool").field("cached", &buffers.len()).field("allocations", &self.allocations()).finish()
+    }
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Zeroed buffer of size bytes, a cached one if there is one of this size, else a new one.
+    /// It goes back to the pool when the guard is dropped.
+    pub fn acquire(self: &Arc<Self>, size: usize) -> BufferGuard {
+        let cached = {
+            let mut buffers = self.buffers.lock().unwrap();
+            buffers.iter().position(|buffer| buffer.len() == size).map(|index| buffers.swap_remove(index))
+        };
+        let buffer = match cached{
+            Some(mut buffer) => {
+                buffer.fill(0);
+                buffer
+            },
+            None => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                alloc_aligned(size, DIRECT_ALIGN)
+            },
+        };
+        BufferGuard { buffer: Some(buffer), pool: Some(Arc::clone(self)) }
+    }
+
+    /// Number of buffers allocated by the pool since it was created
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// Number of buffers cached, not acquired
+    pub fn cached(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+/// Buffer acquired from a pool, released to it on drop
+pub struct BufferGuard {
+    /// Always Some until dropped
+    buffer: Option<AlignedBuffer>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl Deref for BufferGuard {
+    type Target = AlignedBuffer;
+
+    fn deref(&self) -> &AlignedBuffer {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for BufferGuard {
+    fn deref_mut(&mut self) -> &mut AlignedBuffer {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for BufferGuard {
+    fn drop(&mut self) {
+        if let (Some(pool), Some(buffer)) = (self.pool.take(), self.buffer.take()){
+            pool.buffers.lock().unwrap().push(buffer);
+        }
+    }
+}
+
+/// Buffer from pool if there is one, else a new one freed on drop
+pub(crate) fn acquire_buffer(pool: Option<&Arc<BufferPool>>, size: usize) -> BufferGuard {
+    match pool{
+        Some(pool) => pool.acquire(size),
+        None => BufferGuard { buffer: Some(alloc_aligned(size, DIRECT_ALIGN)), pool: None },
+    }
+}
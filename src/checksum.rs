@@ -0,0 +1,203 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Checksum file, the digest of every source chunk read during a sync, to audit a copy later
+//! without the source.
+//!
+//! Format: a `# algorithm: <name>` header line, then one line per chunk: `offset\tdigest\tlength\n`, offset and
+//! length in bytes, digest in lowercase hex. Files without the header are SHA-256.
+use std::fmt;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use sha2::{Digest, Sha256};
+use crate::{read_full_at, SyncError};
+
+const ALGORITHM_HEADER: &str = "# algorithm: ";
+
+/// Hash algorithm of a checksum file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    /// Standard for audits, the slowest
+    #[default]
+    Sha256,
+    /// Cryptographic too and much faster
+    Blake3,
+    /// XXH3 128 bits, not cryptographic, fastest, for integrity checks only
+    Xxhash,
+}
+
+impl ChecksumAlgorithm {
+    /// Length of the digest in bytes
+    fn digest_len(self) -> usize {
+        match self{
+            ChecksumAlgorithm::Sha256 | ChecksumAlgorithm::Blake3 => 32,
+            ChecksumAlgorithm::Xxhash => 16,
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ChecksumAlgorithm::Sha256 => write!(f, "sha256"),
+            ChecksumAlgorithm::Blake3 => write!(f, "blake3"),
+            ChecksumAlgorithm::Xxhash => write!(f, "xxhash"),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    /// Parse sha256, blake3 or xxhash
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            "xxhash" => Ok(ChecksumAlgorithm::Xxhash),
+            _ => Err(format!("Invalid checksum algorithm {:?}, expected sha256, blake3 or xxhash", s)),
+        }
+    }
+}
+
+/// Digest of data with algorithm
+pub fn hash_chunk(algorithm: ChecksumAlgorithm, data: &[u8]) -> Vec<u8> {
+    match algorithm{
+        ChecksumAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+        ChecksumAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        ChecksumAlgorithm::Xxhash => xxhash_rust::xxh3::xxh3_128(data).to_be_bytes().to_vec(),
+    }
+}
+
+/// Digest of one source chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumEntry {
+    /// Offset of the chunk
+    pub offset: u64,
+    /// Length of the chunk, the chunk size except for the last one
+    pub length: usize,
+    /// Digest of the chunk, with the algorithm of its checksum file
+    pub digest: Vec<u8>,
+}
+
+impl ChecksumEntry {
+    /// Digest of data at offset
+    pub fn new(algorithm: ChecksumAlgorithm, offset: u64, data: &[u8]) -> Self {
+        ChecksumEntry { offset, length: data.len(), digest: hash_chunk(algorithm, data) }
+    }
+
+    /// Parse a `offset\tdigest\tlength` line
+    fn parse(line: &str, algorithm: ChecksumAlgorithm) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let offset = fields.next()?.parse().ok()?;
+        let hex = fields.next()?;
+        let length = fields.next()?.parse().ok()?;
+        if hex.len() != algorithm.digest_len() * 2 || fields.next().is_some(){
+            return None;
+        }
+        let mut digest = vec![0u8; algorithm.digest_len()];
+        for (i, byte) in digest.iter_mut().enumerate(){
+            *byte = u8::from_str_radix(hex.get(i * 2 .. i * 2 + 2)?, 16).ok()?;
+        }
+        Some(ChecksumEntry { offset, length, digest })
+    }
+}
+
+/// Write checksum entries to a checksum file
+pub struct ChecksumWriter {
+    inner: BufWriter<File>,
+    algorithm: ChecksumAlgorithm,
+}
+
+impl ChecksumWriter {
+    /// Create the checksum file with its header, replace it if it exists
+    pub fn create(path: &Path, algorithm: ChecksumAlgorithm) -> io::Result<Self> {
+        let mut inner = BufWriter::new(File::create(path)?);
+        writeln!(inner, "{}{}", ALGORITHM_HEADER, algorithm)?;
+        Ok(ChecksumWriter { inner, algorithm })
+    }
+
+    /// Write one line for every chunk of buffer, which starts at offset
+    pub fn write_chunks(&mut self, buffer: &[u8], offset: u64, chunk_size: usize) -> io::Result<()> {
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate(){
+            self.write_entry(&ChecksumEntry::new(self.algorithm, offset + (i * chunk_size) as u64, chunk))?;
+        }
+        Ok(())
+    }
+
+    pub fn write_entry(&mut self, entry: &ChecksumEntry) -> io::Result<()> {
+        writeln!(self.inner, "{}\t{}\t{}", entry.offset, to_hex(&entry.digest), entry.length)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read the algorithm and every entry of a checksum file
+pub fn read_checksums(checksum_path: &Path) -> Result<(ChecksumAlgorithm, Vec<ChecksumEntry>), SyncError> {
+    let checksum_error = |err| SyncError::ChecksumFailed(checksum_path.to_path_buf(), err);
+    let invalid_line = |i: usize| checksum_error(io::Error::new(io::ErrorKind::InvalidData, format!("invalid line {}", i + 1)));
+    let file = File::open(checksum_path).map_err(checksum_error)?;
+    let mut algorithm = ChecksumAlgorithm::Sha256;
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate(){
+        let line = line.map_err(checksum_error)?;
+        if let Some(name) = line.strip_prefix(ALGORITHM_HEADER){
+            if i > 0{
+                return Err(invalid_line(i));
+            }
+            algorithm = name.parse().map_err(|err| checksum_error(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+            continue;
+        }
+        match ChecksumEntry::parse(&line, algorithm){
+            Some(entry) => entries.push(entry),
+            None => return Err(invalid_line(i)),
+        }
+    }
+    Ok((algorithm, entries))
+}
+
+/// Check every chunk of the target against the checksum file, on_chunk is called with the expected entry and the
+/// digest of the target chunk for each one. Return the number of checked chunks and the entries that do not match.
+pub fn verify_checksums(checksum_path: &Path, target_path: &Path, mut on_chunk: impl FnMut(&ChecksumEntry, &[u8])) -> Result<(usize, Vec<ChecksumEntry>), SyncError> {
+    let (algorithm, entries) = read_checksums(checksum_path)?;
+    let target_file = match File::open(target_path){
+        Ok(target_file) => target_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(target_path.to_path_buf(), err)),
+    };
+    let mut buffer = Vec::new();
+    let mut mismatches = Vec::new();
+    for entry in &entries{
+        buffer.resize(entry.length, 0);
+        let len = match read_full_at(&target_file, &mut buffer, entry.offset){
+            Ok(len) => len,
+            Err(err) => return Err(SyncError::DestReadFailed { offset: entry.offset, source: err }),
+        };
+        // A short read means the target is too small, it cannot match
+        let digest = hash_chunk(algorithm, &buffer[0 .. len]);
+        if len != entry.length || digest != entry.digest{
+            mismatches.push(entry.clone());
+        }
+        on_chunk(entry, &digest);
+    }
+    Ok((entries.len(), mismatches))
+}
+
+/// Lowercase hex of a digest
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
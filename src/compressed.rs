@@ -0,0 +1,134 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Compressed source: a gzip or zstd compressed image is decompressed on the fly and synced like a stream, read once
+//! in order.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::str::FromStr;
+use flate2::read::MultiGzDecoder;
+use crate::{filesize, SyncError, SyncOptions, SyncStats};
+use crate::stream::sync_reader;
+
+/// Compression of a source image or of a delta file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileCompression {
+    /// Not compressed
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl fmt::Display for FileCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            FileCompression::None => write!(f, "none"),
+            FileCompression::Gzip => write!(f, "gz"),
+            FileCompression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for FileCompression {
+    type Err = String;
+
+    /// Parse none, gz or zstd
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "none" => Ok(FileCompression::None),
+            "gz" => Ok(FileCompression::Gzip),
+            "zstd" => Ok(FileCompression::Zstd),
+            _ => Err(format!("Invalid compression {:?}, expected none, gz or zstd", s)),
+        }
+    }
+}
+
+/// Decompressed size of a gzip file estimated from its ISIZE trailer, the size modulo 2^32 of its last member, too
+/// small for a file of several members (bgzip, concatenated files) which then only shows in the progress percentage.
+/// The destination size is used instead if it is the same modulo 2^32, e.g. for an image of more than 4GB already synced.
+/// None if there is no usable estimate.
+pub fn gzip_size_estimate(file: &File, dst_size: u64) -> io::Result<Option<u64>> {
+    let len = file.metadata()?.len();
+    if len < 18{
+        return Ok(None); // Smaller than an empty gzip member
+    }
+    let mut isize = [0u8; 4];
+    file.read_exact_at(&mut isize, len - 4)?;
+    let isize = u32::from_le_bytes(isize) as u64;
+    if dst_size > 0 && dst_size >= isize && (dst_size - isize).is_multiple_of(1 << 32){
+        Ok(Some(dst_size))
+    }else if isize > 0{
+        Ok(Some(isize))
+    }else{
+        Ok((dst_size > 0).then_some(dst_size))
+    }
+}
+
+/// Decompressed size of a zstd file from the Content_Size of its first frame, the destination size if it has none.
+/// Exact for a file of one frame, as written by the zstd tool from a regular file.
+pub fn zstd_size_estimate(file: &File, dst_size: u64) -> io::Result<Option<u64>> {
+    // Largest frame header
+    let mut header = [0u8; 18];
+    let len = file.read_at(&mut header, 0)?;
+    match zstd::zstd_safe::get_frame_content_size(&header[.. len]){
+        Ok(Some(size)) => Ok(Some(size)),
+        _ => Ok((dst_size > 0).then_some(dst_size)),
+    }
+}
+
+/// Open path for reading through the decoder of algo
+pub fn open_source_compressed(path: &Path, algo: FileCompression) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    Ok(match algo{
+        FileCompression::None => Box::new(BufReader::new(file)),
+        FileCompression::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+        FileCompression::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+    })
+}
+
+/// Sync the decompressed content of src_path, compressed with algo, to dst_path, only the differing chunks are written.
+/// The source cannot be seeked, so the sync is single threaded and the options of a stream apply.
+pub fn sync_compressed(src_path: &Path, dst_path: &Path, opts: SyncOptions, algo: FileCompression) -> Result<SyncStats, SyncError> {
+    let src_file = match File::open(src_path){
+        Ok(src_file) => src_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    let dst_size = filesize(dst_path)?;
+    let size_estimate = match algo{
+        FileCompression::None => Ok(Some(src_file.metadata()?.len())),
+        FileCompression::Gzip => gzip_size_estimate(&src_file, dst_size),
+        FileCompression::Zstd => zstd_size_estimate(&src_file, dst_size),
+    };
+    let size_estimate = match size_estimate{
+        Ok(size_estimate) => size_estimate,
+        Err(err) => return Err(SyncError::SourceReadFailed { offset: 0, source: err }),
+    };
+    if let Some(size) = size_estimate{
+        log::info!("{}: about {} [{:.1} MB] decompressed", src_path.display(), size, size as f64 / 1024. / 1024.);
+    }
+    let src = match open_source_compressed(src_path, algo){
+        Ok(src) => src,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    sync_reader(src, size_estimate, dst_path, opts, &format!("with a {} compressed source", algo))
+}
+
+/// Sync the decompressed content of the gzip file src_path to dst_path, see sync_compressed
+pub fn sync_gzip(src_path: &Path, dst_path: &Path, opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    sync_compressed(src_path, dst_path, opts, FileCompression::Gzip)
+}
@@ -0,0 +1,72 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Simulated source corruption, to test verify and the fault tolerance of the sync without bad hardware.
+//! Deterministic for a given seed.
+use std::io::{self, Read};
+
+/// Flips a random bit of a read with a given probability, from a seeded SplitMix64 generator
+#[derive(Debug, Clone)]
+pub struct Corrupter {
+    /// Probability that a read is corrupted, 0 to 1
+    rate: f64,
+    state: u64,
+}
+
+impl Corrupter {
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Corrupter { rate, state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Flip one random bit of buf with probability rate, return true if it did
+    pub fn corrupt(&mut self, buf: &mut [u8]) -> bool {
+        // 53 random bits give a uniform float in [0, 1)
+        let draw = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        if buf.is_empty() || draw >= self.rate{
+            return false;
+        }
+        let bit = self.next_u64() % (buf.len() as u64 * 8);
+        buf[(bit / 8) as usize] ^= 1 << (bit % 8);
+        log::debug!("Simulated corruption: bit {} of byte {} of the read flipped", bit % 8, bit / 8);
+        true
+    }
+}
+
+/// Reader returning data corrupted by a Corrupter, one draw per read call
+pub struct CorruptingReader<R> {
+    inner: R,
+    corrupter: Corrupter,
+}
+
+impl<R: Read> CorruptingReader<R> {
+    pub fn new(inner: R, rate: f64, seed: u64) -> Self {
+        CorruptingReader { inner, corrupter: Corrupter::new(rate, seed) }
+    }
+}
+
+impl<R: Read> Read for CorruptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.corrupter.corrupt(&mut buf[0 .. len]);
+        Ok(len)
+    }
+}
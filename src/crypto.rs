@@ -0,0 +1,207 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Encryption of the remote mode connection with ChaCha20-Poly1305 and a pre-shared 32 byte key file.
+//!
+//! Both sides start with a plain handshake: `[magic: 8 bytes "LBSCRYP1"][salt: 32 random bytes]`, the client first.
+//! Each direction then gets its own session key, SHA-256 of a direction label, the key file and both salts, so a
+//! key file can be used for any number of connections. After the handshake, the frames of the protocol module are
+//! sent in records `[length: u32][ciphertext: length bytes, with the 16 byte tag]`, the nonce of a record is its
+//! number in its direction. A record that does not decrypt ends the connection.
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use chacha20poly1305::aead::{Aead, Generate, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+
+pub const KEY_LEN: usize = 32;
+const CRYPTO_MAGIC: [u8; 8] = *b"LBSCRYP1";
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// Longest plaintext of one record, longer writes are split
+const MAX_RECORD_LEN: usize = 1024 * 1024;
+
+/// Pre-shared key of an encrypted connection
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+// Keep the key out of logs
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    /// Read a key file, which must hold exactly 32 bytes
+    pub fn read(path: &Path) -> io::Result<EncryptionKey> {
+        let bytes = fs::read(path)?;
+        let key = bytes.try_into().map_err(|bytes: Vec<u8>| io::Error::new(io::ErrorKind::InvalidData, format!("key file must hold {} bytes, not {}", KEY_LEN, bytes.len())))?;
+        Ok(EncryptionKey(key))
+    }
+
+    /// Write a new random key to path, readable only by its owner. An existing file is not overwritten.
+    pub fn generate(path: &Path) -> io::Result<()> {
+        let key = Key::generate();
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+        file.write_all(&key)?;
+        file.sync_all()
+    }
+
+    /// Session cipher of one direction
+    fn cipher(&self, label: &[u8], client_salt: &[u8], server_salt: &[u8]) -> ChaCha20Poly1305 {
+        let session_key: [u8; KEY_LEN] = Sha256::new()
+            .chain_update(label)
+            .chain_update(self.0)
+            .chain_update(client_salt)
+            .chain_update(server_salt)
+            .finalize()
+            .into();
+        ChaCha20Poly1305::new(&Key::from(session_key))
+    }
+}
+
+/// Nonce of the record number counter
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0 .. 8].copy_from_slice(&counter.to_le_bytes());
+    Nonce::from(nonce)
+}
+
+/// Send our magic and salt
+fn send_salt<W: Write>(writer: &mut W) -> io::Result<[u8; SALT_LEN]> {
+    let salt: [u8; SALT_LEN] = Key::generate().into();
+    writer.write_all(&CRYPTO_MAGIC)?;
+    writer.write_all(&salt)?;
+    writer.flush()?;
+    Ok(salt)
+}
+
+/// Read the magic and salt of the peer
+fn receive_salt<R: Read>(reader: &mut R) -> io::Result<[u8; SALT_LEN]> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != CRYPTO_MAGIC{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer does not encrypt, --encrypt is needed on both sides"));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    reader.read_exact(&mut salt)?;
+    Ok(salt)
+}
+
+/// Handshake of the client side, return the encrypted reader and writer of the connection
+pub fn client_handshake<R: Read, W: Write>(mut reader: R, mut writer: W, key: &EncryptionKey) -> io::Result<(EncryptedReader<R>, EncryptedWriter<W>)> {
+    let client_salt = send_salt(&mut writer)?;
+    let server_salt = receive_salt(&mut reader)?;
+    Ok((
+        EncryptedReader::new(reader, key.cipher(b"localblocksync server", &client_salt, &server_salt)),
+        EncryptedWriter::new(writer, key.cipher(b"localblocksync client", &client_salt, &server_salt)),
+    ))
+}
+
+/// Handshake of the server side, return the encrypted reader and writer of the connection.
+/// Nothing is sent if the client does not start the handshake, so it can be told why in plain text.
+pub fn server_handshake<R: Read, W: Write>(mut reader: R, mut writer: W, key: &EncryptionKey) -> io::Result<(EncryptedReader<R>, EncryptedWriter<W>)> {
+    let client_salt = receive_salt(&mut reader)?;
+    let server_salt = send_salt(&mut writer)?;
+    Ok((
+        EncryptedReader::new(reader, key.cipher(b"localblocksync client", &client_salt, &server_salt)),
+        EncryptedWriter::new(writer, key.cipher(b"localblocksync server", &client_salt, &server_salt)),
+    ))
+}
+
+/// Buffer what is written and send it as an encrypted record on flush, or when the buffer is full
+pub struct EncryptedWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    fn new(inner: W, cipher: ChaCha20Poly1305) -> Self {
+        EncryptedWriter { inner, cipher, counter: 0, buffer: Vec::new() }
+    }
+
+    /// Encrypt and send the buffer as one record
+    fn send_record(&mut self) -> io::Result<()> {
+        let ciphertext = self.cipher.encrypt(&nonce(self.counter), self.buffer.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.counter += 1;
+        self.inner.write_all(&(ciphertext.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_RECORD_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[0 .. len]);
+        if self.buffer.len() == MAX_RECORD_LEN{
+            self.send_record()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty(){
+            self.send_record()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Read encrypted records and give their plaintext
+pub struct EncryptedReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    plaintext: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> EncryptedReader<R> {
+    fn new(inner: R, cipher: ChaCha20Poly1305) -> Self {
+        EncryptedReader { inner, cipher, counter: 0, plaintext: Vec::new(), pos: 0 }
+    }
+}
+
+impl<R: Read> Read for EncryptedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // An empty record is not the end of the stream
+        while self.pos == self.plaintext.len(){
+            let mut len = [0u8; 4];
+            self.inner.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len) as usize;
+            if len > MAX_RECORD_LEN + TAG_LEN{
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("record length {} is above the maximum {}", len, MAX_RECORD_LEN + TAG_LEN)));
+            }
+            let mut ciphertext = vec![0u8; len];
+            self.inner.read_exact(&mut ciphertext)?;
+            self.plaintext = self.cipher.decrypt(&nonce(self.counter), ciphertext.as_slice())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed, the peer uses another key or the data was altered"))?;
+            self.counter += 1;
+            self.pos = 0;
+        }
+        let len = buf.len().min(self.plaintext.len() - self.pos);
+        buf[0 .. len].copy_from_slice(&self.plaintext[self.pos .. self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
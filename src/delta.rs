@@ -0,0 +1,265 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Delta file, the differing regions of a sync saved to be applied later on another machine.
+//!
+//! Format, all integers little endian:
+//! `[magic: 8 bytes "LBSDELT1"][source size: u64]` then `[offset: u64][length: u32][data: length bytes]` repeated.
+//!
+//! A compressed delta archive starts with a plain header
+//! `[magic: 8 bytes "LBSDELTZ"][algorithm: u8, 1 gzip 2 zstd][source size: u64][source path length: u32][source path]`
+//! followed by a delta file compressed with the algorithm.
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::Instant;
+use flate2::Compression as GzipLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use crate::{is_block_device, filesize, FileCompression, SyncError, SyncStats};
+
+pub const DELTA_MAGIC: [u8; 8] = *b"LBSDELT1";
+pub const COMPRESSED_DELTA_MAGIC: [u8; 8] = *b"LBSDELTZ";
+
+/// Header of a compressed delta archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaArchiveHeader {
+    pub compression: FileCompression,
+    pub src_size: u64,
+    pub src_path: String,
+}
+
+impl DeltaArchiveHeader {
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&COMPRESSED_DELTA_MAGIC)?;
+        writer.write_all(&[match self.compression{
+            FileCompression::Gzip => 1,
+            FileCompression::Zstd => 2,
+            FileCompression::None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "a delta archive needs a compression")),
+        }])?;
+        writer.write_all(&self.src_size.to_le_bytes())?;
+        writer.write_all(&(self.src_path.len() as u32).to_le_bytes())?;
+        writer.write_all(self.src_path.as_bytes())
+    }
+
+    /// Read the header after the magic
+    fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut algorithm = [0u8; 1];
+        reader.read_exact(&mut algorithm)?;
+        let compression = match algorithm[0]{
+            1 => FileCompression::Gzip,
+            2 => FileCompression::Zstd,
+            _ => return Err(invalid("unknown delta archive compression")),
+        };
+        let mut src_size = [0u8; 8];
+        reader.read_exact(&mut src_size)?;
+        let mut path_len = [0u8; 4];
+        reader.read_exact(&mut path_len)?;
+        let mut src_path = vec![0u8; u32::from_le_bytes(path_len) as usize];
+        reader.read_exact(&mut src_path)?;
+        let src_path = String::from_utf8(src_path).map_err(|_| invalid("delta archive source path is not UTF-8"))?;
+        Ok(DeltaArchiveHeader { compression, src_size: u64::from_le_bytes(src_size), src_path })
+    }
+}
+
+/// Output of a delta file, compressed for an archive
+pub enum DeltaOutput {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl DeltaOutput {
+    /// End the compressed stream and flush
+    pub fn finish(self) -> io::Result<()> {
+        match self{
+            DeltaOutput::Plain(mut writer) => writer.flush(),
+            DeltaOutput::Gzip(encoder) => encoder.finish()?.flush(),
+            DeltaOutput::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for DeltaOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self{
+            DeltaOutput::Plain(writer) => writer.write(buf),
+            DeltaOutput::Gzip(encoder) => encoder.write(buf),
+            DeltaOutput::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self{
+            DeltaOutput::Plain(writer) => writer.flush(),
+            DeltaOutput::Gzip(encoder) => encoder.flush(),
+            DeltaOutput::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// One differing region of the destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaEntry {
+    /// Offset of the region in the destination
+    pub offset: u64,
+    /// New content of the region, at most u32::MAX bytes
+    pub data: Vec<u8>,
+}
+
+/// Write a delta file entry by entry
+pub struct DeltaWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    /// Write the header, size is the source size the target will be truncated to
+    pub fn new(mut inner: W, size: u64) -> io::Result<Self> {
+        inner.write_all(&DELTA_MAGIC)?;
+        inner.write_all(&size.to_le_bytes())?;
+        Ok(DeltaWriter { inner })
+    }
+
+    /// Write a region, split in several entries if longer than u32::MAX
+    pub fn write_entry(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        for (i, part) in data.chunks(u32::MAX as usize).enumerate(){
+            self.inner.write_all(&(offset + i as u64 * u32::MAX as u64).to_le_bytes())?;
+            self.inner.write_all(&(part.len() as u32).to_le_bytes())?;
+            self.inner.write_all(part)?;
+        }
+        Ok(())
+    }
+
+    /// Flush and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Read a delta file entry by entry
+pub struct DeltaReader<R: Read> {
+    inner: R,
+    /// Source size the target should be truncated to
+    pub size: u64,
+    /// Header of a compressed delta archive
+    pub archive: Option<DeltaArchiveHeader>,
+}
+
+impl<R: Read> DeltaReader<R> {
+    /// Read and check the header
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if magic != DELTA_MAGIC{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a localblocksync delta file"));
+        }
+        let mut size = [0u8; 8];
+        inner.read_exact(&mut size)?;
+        Ok(DeltaReader { inner, size: u64::from_le_bytes(size), archive: None })
+    }
+
+    /// Next entry, None at end of file
+    pub fn next_entry(&mut self) -> io::Result<Option<DeltaEntry>> {
+        let mut offset = [0u8; 8];
+        match self.inner.read_exact(&mut offset){
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len)?;
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.inner.read_exact(&mut data)?;
+        Ok(Some(DeltaEntry { offset: u64::from_le_bytes(offset), data }))
+    }
+}
+
+/// Create a delta file for a source of the given size, a delta archive with its header if compressed
+pub(crate) fn create_delta(delta_path: &Path, src_path: &Path, size: u64, compression: FileCompression) -> Result<DeltaWriter<DeltaOutput>, SyncError> {
+    let open = || -> io::Result<DeltaWriter<DeltaOutput>> {
+        let mut writer = BufWriter::new(File::create(delta_path)?);
+        let output = match compression{
+            FileCompression::None => DeltaOutput::Plain(writer),
+            _ => {
+                let header = DeltaArchiveHeader { compression, src_size: size, src_path: std::fs::canonicalize(src_path)?.to_string_lossy().into_owned() };
+                header.write(&mut writer)?;
+                match compression{
+                    FileCompression::Gzip => DeltaOutput::Gzip(GzEncoder::new(writer, GzipLevel::default())),
+                    _ => DeltaOutput::Zstd(zstd::Encoder::new(writer, 0)?),
+                }
+            },
+        };
+        DeltaWriter::new(output, size)
+    };
+    open().map_err(|err| SyncError::DeltaFailed(delta_path.to_path_buf(), err))
+}
+
+/// Open a delta file or a compressed delta archive
+pub fn open_delta(delta_path: &Path) -> io::Result<DeltaReader<Box<dyn Read>>> {
+    let mut reader = BufReader::new(File::open(delta_path)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != COMPRESSED_DELTA_MAGIC{
+        let reader: Box<dyn Read> = Box::new(io::Cursor::new(magic).chain(reader));
+        return DeltaReader::new(reader);
+    }
+    let header = DeltaArchiveHeader::read(&mut reader)?;
+    let reader: Box<dyn Read> = match header.compression{
+        FileCompression::Gzip => Box::new(GzDecoder::new(reader)),
+        _ => Box::new(zstd::Decoder::with_buffer(reader)?),
+    };
+    let mut delta = DeltaReader::new(reader)?;
+    if delta.size != header.src_size{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "delta archive header and content sizes differ"));
+    }
+    delta.archive = Some(header);
+    Ok(delta)
+}
+
+/// Write every entry of the delta file to the target, truncate target to the source size if it is a regular file
+pub fn apply_delta(delta_path: &Path, target_path: &Path) -> Result<SyncStats, SyncError> {
+    let start_time = Instant::now();
+    let delta_error = |err| SyncError::DeltaFailed(delta_path.to_path_buf(), err);
+    let mut delta = open_delta(delta_path).map_err(delta_error)?;
+    if let Some(header) = &delta.archive{
+        log::info!("Delta archive of {} ({} bytes), {} compressed", header.src_path, header.src_size, header.compression);
+    }
+    let target_size = filesize(target_path)?;
+    let target_file = match OpenOptions::new().create(true).truncate(false).write(true).open(target_path){
+        Ok(target_file) => target_file,
+        Err(err) => return Err(SyncError::DestOpenFailed(target_path.to_path_buf(), err)),
+    };
+    if is_block_device(target_path){
+        if target_size < delta.size{
+            return Err(SyncError::DestTooSmall { src_size: delta.size, dst_size: target_size });
+        }
+    }else if target_size != delta.size{
+        log::info!("Truncate {:?} from {} to {} bytes", target_path, target_size, delta.size);
+        if let Err(err) = target_file.set_len(delta.size){
+            return Err(SyncError::TruncateFailed(target_path.to_path_buf(), err));
+        }
+    }
+    let mut stats = SyncStats::default();
+    while let Some(entry) = delta.next_entry().map_err(delta_error)?{
+        if let Err(err) = target_file.write_all_at(&entry.data, entry.offset){
+            return Err(SyncError::WriteFailed { offset: entry.offset, source: err });
+        }
+        stats.bytes_written += entry.data.len() as u64;
+    }
+    stats.elapsed = start_time.elapsed();
+    Ok(stats)
+}
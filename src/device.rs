@@ -0,0 +1,79 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Block device detection
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use nix::sys::stat::{stat, SFlag};
+
+/// True if path is a block device, symlinks are followed
+pub fn is_block_device_stat(path: &Path) -> Result<bool, nix::Error> {
+    let st = stat(path)?;
+    Ok(SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFBLK)
+}
+
+/// Undo the octal escapes of a /proc/mounts field, e.g. \040 for a space
+fn unescape_mount_field(field: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first(){
+        match (byte, tail.get(.. 3).and_then(|octal| u8::from_str_radix(std::str::from_utf8(octal).ok()?, 8).ok())){
+            (b'\\', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[3 ..];
+            },
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            },
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Whole disk of a partition, e.g. /dev/sdb for /dev/sdb1, from sysfs
+fn parent_disk(device: &Path) -> Option<PathBuf> {
+    let name = device.file_name()?;
+    let sys_path = fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()?;
+    // A partition is a subdirectory of its disk in sysfs
+    if !sys_path.join("partition").exists(){
+        return None;
+    }
+    Some(Path::new("/dev").join(sys_path.parent()?.file_name()?))
+}
+
+/// True if path is mounted, from /proc/mounts: a block device mounted, or one of its partitions, or a file or
+/// directory that is a mount point. Symlinks are followed, e.g. /dev/mapper/vg-lv or /dev/disk/by-id/...
+pub fn is_mounted(path: &Path) -> io::Result<bool> {
+    let path = fs::canonicalize(path)?;
+    let block_device = is_block_device_stat(&path).unwrap_or_default();
+    for line in fs::read_to_string("/proc/mounts")?.lines(){
+        let mut fields = line.split_whitespace();
+        let (Some(device), Some(mount_point)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if block_device && device.starts_with('/'){
+            let Ok(device) = fs::canonicalize(unescape_mount_field(device)) else {
+                continue;
+            };
+            if device == path || parent_disk(&device).is_some_and(|disk| disk == path){
+                return Ok(true);
+            }
+        }else if !block_device && Path::new(&unescape_mount_field(mount_point)) == path{
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,114 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Device-mapper snapshot of the destination, the sync writes to the snapshot and the destination keeps its
+//! previous state until the snapshot is merged into it.
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::platform;
+
+/// Chunk size of the snapshot in 512 byte sectors
+const CHUNK_SECTORS: u32 = 8;
+/// Wait between two checks of the merge progress
+const MERGE_POLL: Duration = Duration::from_millis(500);
+
+/// Device-mapper snapshot of an origin block device created with dmsetup, writes to it are stored on the
+/// copy-on-write device. It is left in place when dropped, merge or remove it.
+#[derive(Debug)]
+pub struct DmSnapshot {
+    name: String,
+    origin: PathBuf,
+    cow: PathBuf,
+    sectors: u64,
+    path: PathBuf,
+}
+
+/// Run dmsetup, return its stdout, or its stderr as error if it fails
+fn run_dmsetup(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("dmsetup").args(args).output().map_err(|err| io::Error::new(err.kind(), format!("failed to run dmsetup: {}", err)))?;
+    if !output.status.success(){
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("dmsetup {} failed: {}: {}", args.first().unwrap_or(&""), output.status, stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+impl DmSnapshot {
+    /// Snapshot origin as /dev/mapper/localblocksync_<timestamp>, storing the writes on the cow block device.
+    /// The header of cow is overwritten, its previous content is lost. The snapshot becomes invalid if cow fills up.
+    pub fn create(origin: &Path, cow: &Path) -> io::Result<DmSnapshot> {
+        let sectors = platform::get_device_size(origin)? / 512;
+        // A zero header makes a new persistent snapshot, any other content is rejected as a corrupt one
+        let cow_file = OpenOptions::new().write(true).open(cow)?;
+        cow_file.write_all_at(&[0u8; CHUNK_SECTORS as usize * 512], 0)?;
+        cow_file.sync_all()?;
+        drop(cow_file);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let name = format!("localblocksync_{}", timestamp);
+        let table = format!("0 {} snapshot {} {} P {}", sectors, origin.display(), cow.display(), CHUNK_SECTORS);
+        run_dmsetup(&["create", &name, "--table", &table])?;
+        log::info!("Created snapshot /dev/mapper/{} of {} on {}", name, origin.display(), cow.display());
+        Ok(DmSnapshot {
+            path: Path::new("/dev/mapper").join(&name),
+            name,
+            origin: origin.to_path_buf(),
+            cow: cow.to_path_buf(),
+            sectors,
+        })
+    }
+
+    /// Device path of the snapshot, /dev/mapper/<name>
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the data of the snapshot to the origin and remove the snapshot, waiting until the merge is done
+    pub fn merge(self) -> io::Result<()> {
+        run_dmsetup(&["remove", &self.name])?;
+        // The merge runs in the kernel while the snapshot-merge target is loaded
+        let merge_name = format!("{}_merge", self.name);
+        let table = format!("0 {} snapshot-merge {} {} P {}", self.sectors, self.origin.display(), self.cow.display(), CHUNK_SECTORS);
+        run_dmsetup(&["create", &merge_name, "--table", &table])?;
+        log::info!("Merging snapshot into {}...", self.origin.display());
+        loop{
+            // <start> <length> snapshot-merge <allocated>/<total> <metadata>, done when only metadata is left
+            let status = run_dmsetup(&["status", &merge_name])?;
+            let fields: Vec<&str> = status.split_whitespace().collect();
+            let allocated = fields.get(3).and_then(|usage| usage.split_once('/')).map(|(allocated, _)| allocated);
+            match (allocated, fields.get(4)){
+                (Some(allocated), Some(metadata)) if allocated == *metadata => break,
+                (Some(_), Some(_)) => thread::sleep(MERGE_POLL),
+                _ => {
+                    let _ = run_dmsetup(&["remove", &merge_name]);
+                    return Err(io::Error::other(format!("snapshot merge failed: {}", status.trim())));
+                },
+            }
+        }
+        run_dmsetup(&["remove", &merge_name])?;
+        log::info!("Merged snapshot into {}", self.origin.display());
+        Ok(())
+    }
+
+    /// Remove the snapshot without merging it, the origin is left as it was when the snapshot was created
+    pub fn remove(self) -> io::Result<()> {
+        run_dmsetup(&["remove", &self.name])?;
+        log::info!("Removed snapshot /dev/mapper/{}", self.name);
+        Ok(())
+    }
+}
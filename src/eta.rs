@@ -0,0 +1,111 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Remaining time estimate of the progress output.
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Number of recent samples the exponential estimate is made from
+const SAMPLES: usize = 10;
+
+/// How the remaining time is estimated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EtaAlgorithm {
+    /// From the average speed since the start
+    #[default]
+    Linear,
+    /// From an exponentially weighted moving average of the recent speed, follows speed changes
+    Exponential,
+}
+
+impl fmt::Display for EtaAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            EtaAlgorithm::Linear => write!(f, "linear"),
+            EtaAlgorithm::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+impl FromStr for EtaAlgorithm {
+    type Err = String;
+
+    /// Parse linear or exponential
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "linear" => Ok(EtaAlgorithm::Linear),
+            "exponential" => Ok(EtaAlgorithm::Exponential),
+            _ => Err(format!("Invalid ETA algorithm {:?}, expected linear or exponential", s)),
+        }
+    }
+}
+
+/// Speed from the last SAMPLES progress samples, each interval weighted alpha times more than the previous one
+#[derive(Debug)]
+pub struct SpeedEstimator {
+    /// (time, bytes done) oldest first
+    samples: VecDeque<(Instant, u64)>,
+    /// Weight of the newest speed in the average
+    alpha: f64,
+}
+
+impl SpeedEstimator {
+    /// Start counting from now with 0 bytes done
+    pub fn new() -> Self {
+        let mut samples = VecDeque::with_capacity(SAMPLES + 1);
+        samples.push_back((Instant::now(), 0));
+        SpeedEstimator { samples, alpha: 2. / (SAMPLES as f64 + 1.) }
+    }
+
+    /// Record the bytes done so far
+    pub fn update(&mut self, bytes: u64) {
+        self.samples.push_back((Instant::now(), bytes));
+        if self.samples.len() > SAMPLES{
+            self.samples.pop_front();
+        }
+    }
+
+    /// Recent speed in bytes per second, None before 2 samples
+    pub fn speed(&self) -> Option<f64> {
+        let mut average = None;
+        for ((time1, bytes1), (time2, bytes2)) in self.samples.iter().zip(self.samples.iter().skip(1)){
+            let secs = time2.duration_since(*time1).as_secs_f64();
+            if secs <= 0.{
+                continue;
+            }
+            let speed = bytes2.saturating_sub(*bytes1) as f64 / secs;
+            average = Some(match average{
+                Some(average) => self.alpha * speed + (1. - self.alpha) * average,
+                None => speed,
+            });
+        }
+        average
+    }
+
+    /// Seconds to do remaining bytes at the recent speed, infinite if unknown or stalled
+    pub fn eta_secs(&self, remaining: u64) -> f64 {
+        match self.speed(){
+            Some(speed) if speed > 0. => remaining as f64 / speed,
+            _ => f64::INFINITY,
+        }
+    }
+}
+
+impl Default for SpeedEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
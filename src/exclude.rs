@@ -0,0 +1,81 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Byte ranges known to be identical, skipped by the sync without reading nor writing them.
+use std::fs;
+use std::path::Path;
+use crate::SyncError;
+
+/// Range of bytes [start, end) to skip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExcludeRange {
+    pub start: u64,
+    /// End of the range, excluded
+    pub end: u64,
+}
+
+/// Sort ranges and merge the overlapping or adjacent ones, so they can be binary searched
+pub fn normalize_ranges(mut ranges: Vec<ExcludeRange>) -> Vec<ExcludeRange> {
+    ranges.retain(|range| range.start < range.end);
+    ranges.sort();
+    let mut merged: Vec<ExcludeRange> = Vec::with_capacity(ranges.len());
+    for range in ranges{
+        match merged.last_mut(){
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Read a CSV file of `start_byte,end_byte` lines, end excluded. Empty lines and lines starting with # are ignored.
+pub fn read_exclude_ranges(path: &Path) -> Result<Vec<ExcludeRange>, SyncError> {
+    let content = match fs::read_to_string(path){
+        Ok(content) => content,
+        Err(err) => return Err(SyncError::InvalidOptions(format!("failed to read exclude ranges {}: {}", path.display(), err))),
+    };
+    let mut ranges = Vec::new();
+    for (i, line) in content.lines().enumerate(){
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+        let range = line.split_once(',').and_then(|(start, end)| {
+            Some(ExcludeRange { start: start.trim().parse().ok()?, end: end.trim().parse().ok()? })
+        });
+        match range{
+            Some(range) if range.start < range.end => ranges.push(range),
+            _ => return Err(SyncError::InvalidOptions(format!("invalid exclude range at line {} of {}: {:?}", i + 1, path.display(), line))),
+        }
+    }
+    Ok(normalize_ranges(ranges))
+}
+
+/// Range containing offset, ranges must be normalized
+pub(crate) fn range_at(offset: u64, ranges: &[ExcludeRange]) -> Option<&ExcludeRange> {
+    // Last range starting at or before offset
+    let i = ranges.partition_point(|range| range.start <= offset);
+    ranges[.. i].last().filter(|range| offset < range.end)
+}
+
+/// True if offset is in one of the ranges, ranges must be normalized
+pub fn is_excluded(offset: u64, ranges: &[ExcludeRange]) -> bool {
+    range_at(offset, ranges).is_some()
+}
+
+/// Start of the first range after offset, ranges must be normalized
+pub(crate) fn next_start(offset: u64, ranges: &[ExcludeRange]) -> Option<u64> {
+    let i = ranges.partition_point(|range| range.start <= offset);
+    ranges.get(i).map(|range| range.start)
+}
@@ -0,0 +1,201 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Pluggable I/O backends of the sync loop reads and writes: blocking calls, tokio's blocking thread pool,
+//! or io_uring with the `io-uring` feature.
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::{alloc_aligned, DIRECT_ALIGN};
+
+/// Positioned reads and writes of one file
+pub trait IoBackend: Send + Sync {
+    /// Read at offset into buf, return the bytes read, less than buf only at the end of the file
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Write all of buf at offset
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+}
+
+/// Which backend does the I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoEngine {
+    /// Blocking calls in the sync thread
+    #[default]
+    Sync,
+    /// Blocking calls in the thread pool of a tokio runtime
+    Tokio,
+    /// io_uring submissions, Linux 5.6 and the `io-uring` feature
+    IoUring,
+}
+
+impl fmt::Display for IoEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            IoEngine::Sync => write!(f, "sync"),
+            IoEngine::Tokio => write!(f, "tokio"),
+            IoEngine::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
+
+impl FromStr for IoEngine {
+    type Err = String;
+
+    /// Parse sync, tokio or io-uring
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "sync" => Ok(IoEngine::Sync),
+            "tokio" => Ok(IoEngine::Tokio),
+            "io-uring" => Ok(IoEngine::IoUring),
+            _ => Err(format!("Invalid I/O engine {:?}, expected sync, tokio or io-uring", s)),
+        }
+    }
+}
+
+impl IoEngine {
+    /// Backend of this engine doing the I/O of file, which is duplicated
+    pub fn open(self, file: &File) -> io::Result<Box<dyn IoBackend>> {
+        let file = file.try_clone()?;
+        match self{
+            IoEngine::Sync => Ok(Box::new(SyncBackend(file))),
+            IoEngine::Tokio => Ok(Box::new(TokioBackend::new(file)?)),
+            #[cfg(feature = "io-uring")]
+            IoEngine::IoUring => Ok(Box::new(uring::UringBackend::new(file)?)),
+            #[cfg(not(feature = "io-uring"))]
+            IoEngine::IoUring => Err(io::Error::new(io::ErrorKind::Unsupported, "built without the io-uring feature")),
+        }
+    }
+}
+
+/// Blocking calls, what the sync loop does without a backend
+struct SyncBackend(File);
+
+impl IoBackend for SyncBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        crate::read_full_at(&self.0, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.0.write_all_at(buf, offset)
+    }
+}
+
+/// Runs each call with spawn_blocking and waits for it, the data is copied to and from the blocking thread through
+/// a buffer aligned for direct I/O
+struct TokioBackend {
+    file: Arc<File>,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl TokioBackend {
+    fn new(file: File) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        Ok(TokioBackend { file: Arc::new(file), runtime })
+    }
+}
+
+impl IoBackend for TokioBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let file = Arc::clone(&self.file);
+        let len = buf.len();
+        let task = self.runtime.spawn_blocking(move || {
+            let mut data = alloc_aligned(len, DIRECT_ALIGN);
+            crate::read_full_at(&file, &mut data, offset).map(|read| (data, read))
+        });
+        let (data, read) = self.runtime.block_on(task).map_err(io::Error::other)??;
+        buf[0 .. read].copy_from_slice(&data[0 .. read]);
+        Ok(read)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let file = Arc::clone(&self.file);
+        let mut data = alloc_aligned(buf.len(), DIRECT_ALIGN);
+        data.copy_from_slice(buf);
+        let task = self.runtime.spawn_blocking(move || file.write_all_at(&data, offset));
+        self.runtime.block_on(task).map_err(io::Error::other)?
+    }
+}
+
+#[cfg(feature = "io-uring")]
+mod uring {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Mutex;
+    use io_uring::{opcode, types, IoUring};
+    use super::IoBackend;
+
+    /// One read or write submitted at a time, waiting for its completion
+    pub struct UringBackend {
+        file: File,
+        ring: Mutex<IoUring>,
+    }
+
+    impl UringBackend {
+        pub fn new(file: File) -> io::Result<Self> {
+            Ok(UringBackend { file, ring: Mutex::new(IoUring::new(8)?) })
+        }
+
+        /// Submit entry and return its result, the buffer it points to must live until then
+        fn submit(&self, entry: io_uring::squeue::Entry) -> io::Result<usize> {
+            let mut ring = self.ring.lock().unwrap();
+            // The buffer is borrowed by the caller until the completion is received below
+            unsafe{
+                ring.submission().push(&entry).map_err(io::Error::other)?;
+            }
+            ring.submit_and_wait(1)?;
+            let result = match ring.completion().next(){
+                Some(completion) => completion.result(),
+                None => return Err(io::Error::other("io_uring completion missing")),
+            };
+            if result < 0{
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            Ok(result as usize)
+        }
+    }
+
+    impl IoBackend for UringBackend {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let fd = types::Fd(self.file.as_raw_fd());
+            let mut len = 0;
+            while len < buf.len(){
+                let part = &mut buf[len ..];
+                let entry = opcode::Read::new(fd, part.as_mut_ptr(), part.len().min(u32::MAX as usize) as u32).offset(offset + len as u64).build();
+                match self.submit(entry)?{
+                    0 => break,
+                    read => len += read,
+                }
+            }
+            Ok(len)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let fd = types::Fd(self.file.as_raw_fd());
+            let mut len = 0;
+            while len < buf.len(){
+                let part = &buf[len ..];
+                let entry = opcode::Write::new(fd, part.as_ptr(), part.len().min(u32::MAX as usize) as u32).offset(offset + len as u64).build();
+                match self.submit(entry)?{
+                    0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    written => len += written,
+                }
+            }
+            Ok(())
+        }
+    }
+}
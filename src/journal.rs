@@ -0,0 +1,163 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Write journal, the destination content overwritten by a sync, to undo it.
+//!
+//! Format, all integers little endian:
+//! `[magic: 8 bytes "LBSJRNL1"][destination size before the sync: u64]` then
+//! `[offset: u64][length: u32][old data: length bytes]` for each write, in the order of the writes.
+use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use crate::{sync_paths_multi, SyncError, SyncOptions, SyncStats};
+
+pub const JOURNAL_MAGIC: [u8; 8] = *b"LBSJRNL1";
+
+/// Write a journal entry by entry
+pub struct JournalWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> JournalWriter<W> {
+    /// Write the header, size is the destination size before the sync, restored by undo
+    pub fn new(mut inner: W, size: u64) -> io::Result<Self> {
+        inner.write_all(&JOURNAL_MAGIC)?;
+        inner.write_all(&size.to_le_bytes())?;
+        inner.flush()?;
+        Ok(JournalWriter { inner })
+    }
+
+    /// Record the old data of a region before it is overwritten, flushed so it is saved before the write
+    pub fn write_entry(&mut self, offset: u64, old_data: &[u8]) -> io::Result<()> {
+        for (i, part) in old_data.chunks(u32::MAX as usize).enumerate(){
+            self.inner.write_all(&(offset + i as u64 * u32::MAX as u64).to_le_bytes())?;
+            self.inner.write_all(&(part.len() as u32).to_le_bytes())?;
+            self.inner.write_all(part)?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Create a journal for a destination of the given size
+pub(crate) fn create_journal(journal_path: &Path, size: u64) -> Result<JournalWriter<BufWriter<File>>, SyncError> {
+    File::create(journal_path)
+        .and_then(|file| JournalWriter::new(BufWriter::new(file), size))
+        .map_err(|err| SyncError::JournalFailed(journal_path.to_path_buf(), err))
+}
+
+/// Entry of a journal, its data is not read
+struct JournalEntry {
+    offset: u64,
+    len: u32,
+    /// Position of the old data in the journal
+    position: u64,
+}
+
+/// Read the header and every entry, return the destination size before the sync and the entries
+fn read_entries<R: Read + Seek>(reader: &mut R) -> io::Result<(u64, Vec<JournalEntry>)> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != JOURNAL_MAGIC{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a localblocksync write journal"));
+    }
+    let mut size = [0u8; 8];
+    reader.read_exact(&mut size)?;
+    let mut entries = Vec::new();
+    loop{
+        let mut offset = [0u8; 8];
+        match reader.read_exact(&mut offset){
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        let position = reader.stream_position()?;
+        reader.seek(SeekFrom::Current(len as i64))?;
+        entries.push(JournalEntry { offset: u64::from_le_bytes(offset), len, position });
+    }
+    Ok((u64::from_le_bytes(size), entries))
+}
+
+/// Write back the old data of every entry of the journal to dst, the last one first, then truncate dst to its size
+/// before the sync if it is a regular file. Return the bytes restored.
+pub fn rollback_journal(journal_path: &Path, dst: &File) -> Result<u64, SyncError> {
+    let journal_error = |err| SyncError::JournalFailed(journal_path.to_path_buf(), err);
+    let journal_file = File::open(journal_path).map_err(journal_error)?;
+    let journal_len = journal_file.metadata().map_err(journal_error)?.len();
+    let (size, entries) = read_entries(&mut BufReader::new(&journal_file)).map_err(journal_error)?;
+    // Seeking past the end does not fail, check the last entry is complete before writing anything
+    if entries.last().is_some_and(|entry| entry.position + entry.len as u64 > journal_len){
+        return Err(journal_error(io::Error::new(io::ErrorKind::UnexpectedEof, "journal entry cut short")));
+    }
+    let mut bytes_restored = 0;
+    let mut data = Vec::new();
+    for &JournalEntry { offset, len, position } in entries.iter().rev(){
+        data.resize(len as usize, 0);
+        journal_file.read_exact_at(&mut data, position).map_err(journal_error)?;
+        if let Err(err) = dst.write_all_at(&data, offset){
+            return Err(SyncError::WriteFailed { offset, source: err });
+        }
+        bytes_restored += len as u64;
+    }
+    log::info!("Restored {} regions, {} bytes [{:.1} MB]", entries.len(), bytes_restored, bytes_restored as f64 / 1024. / 1024.);
+    let metadata = dst.metadata()?;
+    if metadata.file_type().is_file() && metadata.len() != size{
+        log::info!("Truncate from {} to {} bytes", metadata.len(), size);
+        dst.set_len(size)?;
+    }
+    Ok(bytes_restored)
+}
+
+/// Undo a sync recorded in a journal written with write_journal, see rollback_journal
+pub fn undo_journal(journal_path: &Path, target_path: &Path) -> Result<SyncStats, SyncError> {
+    let start_time = Instant::now();
+    let target_file = match OpenOptions::new().write(true).open(target_path){
+        Ok(target_file) => target_file,
+        Err(err) => return Err(SyncError::DestOpenFailed(target_path.to_path_buf(), err)),
+    };
+    let bytes_written = rollback_journal(journal_path, &target_file)?;
+    Ok(SyncStats { bytes_written, elapsed: start_time.elapsed(), ..Default::default() })
+}
+
+/// Sync with a write journal and roll the destination back from it if the sync fails
+pub(crate) fn sync_with_rollback(src_path: &Path, dst_paths: &[PathBuf], opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    let journal_path = match (&opts.write_journal, dst_paths){
+        (Some(journal_path), [_]) => journal_path.clone(),
+        _ => return Err(SyncError::InvalidOptions("rollback on error needs a write journal and one destination".to_string())),
+    };
+    // A journal left by a previous sync is only rolled back if this sync wrote it again
+    let journal_created = Arc::new(AtomicBool::new(false));
+    let opts = SyncOptions { journal_created: Some(Arc::clone(&journal_created)), ..opts.rollback_on_error(false) };
+    let err = match sync_paths_multi(src_path, dst_paths, opts){
+        Ok(stats) => return Ok(stats),
+        Err(err) => err,
+    };
+    if !journal_created.load(Ordering::Relaxed){
+        return Err(err);
+    }
+    log::warn!("Warning: sync failed, rolling {:?} back from journal {:?}", dst_paths[0], journal_path);
+    let rollback = OpenOptions::new().write(true).open(&dst_paths[0])
+        .map_err(|err| SyncError::DestOpenFailed(dst_paths[0].to_path_buf(), err))
+        .and_then(|dst_file| rollback_journal(&journal_path, &dst_file));
+    if let Err(rollback_err) = rollback{
+        log::error!("Rollback failed, {:?} is partly synced: {}", dst_paths[0], rollback_err);
+    }
+    Err(err)
+}
@@ -0,0 +1,2731 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Sync engine of localblocksync, usable from other programs.
+//! Messages (sizes, truncation, warnings) are emitted with the `log` crate, install a logger to see them.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use localblocksync::{sync_paths, SyncOptions};
+//!
+//! let opts = SyncOptions::new().threaded(true).quiet(true);
+//! let stats = sync_paths(Path::new("/dev/sda1"), Path::new("backup-sda1.img"), opts).unwrap();
+//! println!("{} bytes written", stats.bytes_written);
+//! ```
+use std::{fmt,fs,io,thread};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use nix::{ioctl_none, ioctl_read_bad, ioctl_write_ptr_bad, request_code_none};
+use nix::fcntl::{fallocate, fcntl, posix_fadvise, FallocateFlags, FcntlArg, OFlag, PosixFadviseAdvice};
+use nix::unistd::{fdatasync, fsync};
+use nix::sys::sendfile::sendfile;
+use std::os::unix::io::RawFd;
+use std::os::unix::io::AsRawFd;
+use std::io::{prelude::*, stdout, BufWriter, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use memmap2::{Mmap, MmapOptions};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::prelude::*;
+
+pub mod aligned;
+pub mod ascii_map;
+pub mod atomic;
+pub mod benchmark;
+pub mod block_map;
+pub mod buffer_pool;
+pub mod checksum;
+pub mod compressed;
+pub mod corrupt;
+pub mod crypto;
+pub mod delta;
+pub mod eta;
+pub mod device;
+pub mod dm_snapshot;
+pub mod exclude;
+pub mod io_backend;
+pub mod journal;
+pub mod log_file;
+pub mod lvm;
+pub mod metadata;
+pub mod metrics;
+mod pipeline;
+pub mod platform;
+pub mod priority;
+pub mod protocol;
+pub mod rate_limit;
+pub mod remote;
+pub mod schedule;
+pub mod segment;
+pub mod stream;
+pub mod tui;
+pub mod watch;
+pub use aligned::{alloc_aligned, AlignedBuffer};
+pub use ascii_map::{chunk_states, chunks_per_char, render_block_map, render_chunk_states, ChunkState};
+pub use atomic::reflink_copy;
+pub use benchmark::{benchmark_paths, BenchmarkStats, ReadBenchmark};
+pub use block_map::{BlockMap, BlockMapFormat};
+pub use buffer_pool::{BufferGuard, BufferPool};
+pub use checksum::{hash_chunk, to_hex, verify_checksums, ChecksumAlgorithm, ChecksumEntry};
+pub use corrupt::{Corrupter, CorruptingReader};
+pub use compressed::{gzip_size_estimate, open_source_compressed, sync_compressed, sync_gzip, zstd_size_estimate, FileCompression};
+pub use crypto::EncryptionKey;
+pub use delta::{apply_delta, open_delta, DeltaArchiveHeader, DeltaEntry};
+pub use device::is_mounted;
+pub use dm_snapshot::DmSnapshot;
+pub use eta::{EtaAlgorithm, SpeedEstimator};
+pub use exclude::{is_excluded, read_exclude_ranges, ExcludeRange};
+pub use io_backend::{IoBackend, IoEngine};
+pub use journal::{rollback_journal, undo_journal, JournalWriter};
+pub use log_file::LogTee;
+pub use lvm::LvmSnapshot;
+pub use metadata::{sync_metadata, MetadataChanges};
+pub use metrics::MetricsServer;
+pub use priority::{set_io_priority, set_nice, IoPriority};
+pub use rate_limit::{IopsLimiter, RateLimiter};
+pub use protocol::Compression;
+pub use remote::{serve, sync_remote};
+pub use schedule::{build_offset_schedule, read_priority_regions};
+pub use segment::SegmentCheckpoint;
+pub use stream::{copy_to_stream, sync_stream};
+pub use watch::SourceWatcher;
+use buffer_pool::acquire_buffer;
+use checksum::ChecksumWriter;
+use pipeline::{ReadPipeline, ReadPlan};
+use delta::{DeltaOutput, DeltaWriter};
+
+/// How source and destination chunks are compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashMode {
+    /// Byte for byte comparison, source and destination buffers are both in RAM
+    #[default]
+    Bytes,
+    /// Compare SHA-256 digest of each chunk, only one destination chunk is in RAM
+    Sha256,
+}
+
+/// How destinations are flushed to disk after all writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushMode {
+    /// Leave it to the kernel, writes may still be in its buffers when the sync returns
+    #[default]
+    None,
+    /// fsync, flush data and metadata
+    Fsync,
+    /// fdatasync, flush data and only the metadata needed to read it back
+    Fdatasync,
+}
+
+/// Options of a sync, built with chained setters from `SyncOptions::new()`
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    threaded: bool,
+    parallel_compare: bool,
+    compare_threads: usize,
+    buffer_size: usize,
+    buffer_count: usize,
+    chunk_size: usize,
+    align: usize,
+    quiet: bool,
+    no_progress: bool,
+    progress_bar: bool,
+    color: bool,
+    progress_file: Option<PathBuf>,
+    progress_interval: Duration,
+    eta_algorithm: EtaAlgorithm,
+    io_engine: IoEngine,
+    json: bool,
+    hash_mode: HashMode,
+    flush_mode: FlushMode,
+    block_size_auto: bool,
+    sparse: bool,
+    trim: bool,
+    direct: bool,
+    read_ahead: Option<usize>,
+    mmap: bool,
+    compression: Option<Compression>,
+    encryption: Option<EncryptionKey>,
+    atomic: bool,
+    dry_run: bool,
+    output_delta: Option<PathBuf>,
+    delta_compression: FileCompression,
+    write_journal: Option<PathBuf>,
+    rollback_on_error: bool,
+    checksum_file: Option<PathBuf>,
+    checksum_algorithm: ChecksumAlgorithm,
+    block_map: Option<(PathBuf, BlockMapFormat)>,
+    rate_limit: Option<u64>,
+    max_bytes_written: Option<u64>,
+    max_diff_ratio: Option<f64>,
+    diff_filter: usize,
+    iops_limit: Option<u32>,
+    verify: bool,
+    write_verify_each: bool,
+    write_barrier: bool,
+    use_sendfile: bool,
+    count_differing_bytes: bool,
+    ignore_errors: bool,
+    retries: u32,
+    seed_zeros: bool,
+    truncate_dest: bool,
+    create_sparse_dest: bool,
+    metadata: bool,
+    swap_endian: Option<usize>,
+    /// Rate and seed of the simulated source corruption, set only in debug builds
+    simulate_corruption: Option<(f64, u64)>,
+    zero_unwritten: bool,
+    checkpoint_path: Option<PathBuf>,
+    checkpoint_interval: u64,
+    segment_size: Option<u64>,
+    stop_flag: Option<Arc<AtomicBool>>,
+    /// Set once the write journal is created, for sync_with_rollback
+    journal_created: Option<Arc<AtomicBool>>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    /// Stop after the first buffer with a difference, for compare_paths
+    stop_at_difference: bool,
+    events: Option<Sender<SyncEvent>>,
+    offset: u64,
+    length: Option<u64>,
+    source_offset: u64,
+    exclude_ranges: Vec<ExcludeRange>,
+    priority_regions: Vec<(u64, u64)>,
+    seed_from: Option<PathBuf>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            threaded: false,
+            parallel_compare: false,
+            compare_threads: 0,
+            buffer_size: 100*1024*1024,
+            buffer_count: 1,
+            chunk_size: 1024*1024,
+            align: 1,
+            quiet: false,
+            no_progress: false,
+            progress_bar: false,
+            color: false,
+            progress_file: None,
+            progress_interval: Duration::from_secs(2),
+            eta_algorithm: EtaAlgorithm::Linear,
+            io_engine: IoEngine::Sync,
+            json: false,
+            hash_mode: HashMode::Bytes,
+            flush_mode: FlushMode::None,
+            block_size_auto: false,
+            sparse: false,
+            trim: false,
+            direct: false,
+            read_ahead: None,
+            mmap: false,
+            compression: None,
+            encryption: None,
+            atomic: false,
+            dry_run: false,
+            output_delta: None,
+            delta_compression: FileCompression::None,
+            write_journal: None,
+            rollback_on_error: false,
+            checksum_file: None,
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            block_map: None,
+            rate_limit: None,
+            max_bytes_written: None,
+            max_diff_ratio: None,
+            diff_filter: 0,
+            iops_limit: None,
+            verify: false,
+            write_verify_each: false,
+            write_barrier: false,
+            use_sendfile: false,
+            count_differing_bytes: false,
+            ignore_errors: false,
+            retries: 0,
+            seed_zeros: false,
+            truncate_dest: true,
+            create_sparse_dest: false,
+            metadata: false,
+            swap_endian: None,
+            simulate_corruption: None,
+            zero_unwritten: false,
+            checkpoint_path: None,
+            checkpoint_interval: 60,
+            segment_size: None,
+            stop_flag: None,
+            journal_created: None,
+            buffer_pool: None,
+            stop_at_difference: false,
+            events: None,
+            offset: 0,
+            length: None,
+            source_offset: 0,
+            exclude_ranges: Vec::new(),
+            priority_regions: Vec::new(),
+            seed_from: None,
+        }
+    }
+}
+
+impl SyncOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read source and destination at the same time in 2 threads. Do not use if they are on the same physical disk.
+    pub fn threaded(mut self, threaded: bool) -> Self {
+        self.threaded = threaded;
+        self
+    }
+
+    /// Compare the chunks of each buffer in parallel on all CPU cores, writes stay sequential. Byte mode only, SHA-256 mode ignores it.
+    pub fn parallel_compare(mut self, parallel_compare: bool) -> Self {
+        self.parallel_compare = parallel_compare;
+        self
+    }
+
+    /// Number of threads comparing chunks with parallel_compare, 0 for one per CPU core
+    pub fn compare_threads(mut self, compare_threads: usize) -> Self {
+        self.compare_threads = compare_threads;
+        self
+    }
+
+    /// Read buffer size in bytes (Need 2x this in RAM)
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Number of read buffers, 2 or more reads the next buffers in a thread while the current one is compared and written.
+    /// Needs buffer_count times the buffers in RAM. Not with ignore_errors.
+    pub fn buffer_count(mut self, buffer_count: usize) -> Self {
+        self.buffer_count = buffer_count;
+        self
+    }
+
+    /// Chunk size in bytes, granularity of the comparison and of the writes
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Expand each write to this alignment in bytes on both ends, for storage that performs best with aligned writes
+    pub fn align(mut self, align: usize) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Do not display the interactive progress, use the log level to silence the other messages
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Do not display progress during the sync, other details are still logged
+    pub fn no_progress(mut self, no_progress: bool) -> Self {
+        self.no_progress = no_progress;
+        self
+    }
+
+    /// Display progress with an indicatif progress bar instead of the plain `\r` line
+    pub fn progress_bar(mut self, progress_bar: bool) -> Self {
+        self.progress_bar = progress_bar;
+        self
+    }
+
+    /// Color the progress output with ANSI escape codes: done part green, current part yellow, error count red
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Write progress as a JSON object to this file every progress interval, even in quiet mode, for external monitoring.
+    /// The file is removed when the sync completes, kept if it fails or is interrupted.
+    pub fn progress_file(mut self, progress_file: Option<PathBuf>) -> Self {
+        self.progress_file = progress_file;
+        self
+    }
+
+    /// Time between progress updates
+    pub fn progress_interval(mut self, progress_interval: Duration) -> Self {
+        self.progress_interval = progress_interval;
+        self
+    }
+
+    /// How the remaining time of the progress line is estimated
+    pub fn eta_algorithm(mut self, eta_algorithm: EtaAlgorithm) -> Self {
+        self.eta_algorithm = eta_algorithm;
+        self
+    }
+
+    /// Backend of the source and destination reads and of the writes of the sync loop, default blocking calls.
+    /// Not with threaded, buffer_count nor mmap, which have their own way to overlap the I/O.
+    pub fn io_engine(mut self, io_engine: IoEngine) -> Self {
+        self.io_engine = io_engine;
+        self
+    }
+
+    /// Print progress as newline-delimited JSON objects to stdout instead of the interactive display
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// How chunks are compared, byte for byte or by SHA-256 digest
+    pub fn hash_mode(mut self, hash_mode: HashMode) -> Self {
+        self.hash_mode = hash_mode;
+        self
+    }
+
+    /// Flush destinations to disk with fsync or fdatasync after all writes, ignored in dry run or delta mode
+    pub fn flush_mode(mut self, flush_mode: FlushMode) -> Self {
+        self.flush_mode = flush_mode;
+        self
+    }
+
+    /// Use the physical block size of block device destinations as chunk size, the chunk size is kept for regular files.
+    /// With several block devices, the largest physical block size is used.
+    pub fn block_size_auto(mut self, block_size_auto: bool) -> Self {
+        self.block_size_auto = block_size_auto;
+        self
+    }
+
+    /// Punch holes in the destination for differing chunks that are all zeros in the source, instead of writing the zeros.
+    /// Keeps a regular file destination sparse, falls back to writing if the destination does not support it.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Discard (TRIM) the ranges of a block device destination where the source is all zeros instead of writing the zeros.
+    /// Regular file destinations get the zeros written, or punched with sparse.
+    pub fn trim(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Open source and destinations with O_DIRECT to bypass the page cache.
+    /// Buffer and chunk sizes are rounded up to the logical block size, at least 4096.
+    /// Falls back to the page cache with a warning where O_DIRECT is not supported.
+    pub fn direct(mut self, direct: bool) -> Self {
+        self.direct = direct;
+        self
+    }
+
+    /// Tell the kernel the files are read sequentially, and to read this many bytes ahead of each buffer
+    /// while it is compared, for disks with a small default read-ahead
+    pub fn read_ahead(mut self, read_ahead: Option<usize>) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    /// Map source and destinations in memory and compare the mapped slices instead of reading them in buffers.
+    /// Source must be a regular file or a block device. Files must not be truncated by another program during the sync.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Compress the data sent to a remote destination, chunk by chunk
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypt the connection to a remote destination with this pre-shared key, the server needs the same key
+    pub fn encryption(mut self, encryption: Option<EncryptionKey>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Sync regular file destinations through a temporary copy, cloned if possible, renamed over them only once
+    /// fully written and flushed. An interrupted sync leaves the destinations unchanged.
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Read and compare everything but do not write nor truncate the destination
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Write differing regions to this delta file instead of the destination, which is left untouched
+    pub fn output_delta(mut self, output_delta: Option<PathBuf>) -> Self {
+        self.output_delta = output_delta;
+        self
+    }
+
+    /// Compress the delta file into a delta archive, with the source path and size in its header
+    pub fn delta_compression(mut self, delta_compression: FileCompression) -> Self {
+        self.delta_compression = delta_compression;
+        self
+    }
+
+    /// Save the destination data before each write to this journal, to undo the sync with undo_journal
+    pub fn write_journal(mut self, write_journal: Option<PathBuf>) -> Self {
+        self.write_journal = write_journal;
+        self
+    }
+
+    /// Restore the destination from the write journal if the sync fails, as it was before the sync. Not when stopped.
+    pub fn rollback_on_error(mut self, rollback_on_error: bool) -> Self {
+        self.rollback_on_error = rollback_on_error;
+        self
+    }
+
+    /// Write the 512-byte sectors written (or that would be in dry run) to this file, the union of all destinations
+    pub fn block_map(mut self, block_map: Option<(PathBuf, BlockMapFormat)>) -> Self {
+        self.block_map = block_map;
+        self
+    }
+
+    /// Write the digest of every source chunk read to this checksum file, to check a copy later with `verify_checksums`
+    pub fn checksum_file(mut self, checksum_file: Option<PathBuf>) -> Self {
+        self.checksum_file = checksum_file;
+        self
+    }
+
+    /// Hash algorithm of the checksum file, SHA-256 by default
+    pub fn checksum_algorithm(mut self, checksum_algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum_algorithm = checksum_algorithm;
+        self
+    }
+
+    /// Limit write bandwidth to this many bytes per second
+    pub fn rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Abort the sync with an error before the bytes written to a destination exceed this limit, e.g. when source
+    /// and destination were swapped by mistake. What is already written is kept.
+    pub fn max_bytes_written(mut self, max_bytes_written: Option<u64>) -> Self {
+        self.max_bytes_written = max_bytes_written;
+        self
+    }
+
+    /// Abort the sync with an error when more than this fraction (0 to 1) of the data compared so far had to be
+    /// written to a destination, which usually means a wrong source or destination. Checked after each buffer.
+    pub fn max_diff_ratio(mut self, max_diff_ratio: Option<f64>) -> Self {
+        self.max_diff_ratio = max_diff_ratio;
+        self
+    }
+
+    /// Leave as is the differing regions of a buffer shorter than this many bytes, e.g. metadata that changes on every
+    /// read. A region split across two buffers is filtered as two regions. 0 writes every differing region.
+    pub fn diff_filter(mut self, diff_filter: usize) -> Self {
+        self.diff_filter = diff_filter;
+        self
+    }
+
+    /// Limit the number of write calls per second, can be combined with rate_limit. A punched hole counts as a write.
+    pub fn iops_limit(mut self, iops_limit: Option<u32>) -> Self {
+        self.iops_limit = iops_limit;
+        self
+    }
+
+    /// Count the bytes that actually differ in the written chunks, the minimum that could be written, in
+    /// SyncStats::bytes_would_write_minimal to measure the write amplification of the chunk size and alignment
+    pub fn count_differing_bytes(mut self, count_differing_bytes: bool) -> Self {
+        self.count_differing_bytes = count_differing_bytes;
+        self
+    }
+
+    /// After sync, re-read every written region and check it matches the source
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Read back every region right after writing it and check it matches, a mismatch is a write error, retried and
+    /// skipped with ignore_errors like the others. Without direct I/O the data is read back from the page cache.
+    pub fn write_verify_each(mut self, write_verify_each: bool) -> Self {
+        self.write_verify_each = write_verify_each;
+        self
+    }
+
+    /// Flush the cache of block device destinations after each write, so that writes reach the device in order.
+    /// Much slower, no effect on file destinations.
+    pub fn write_barrier(mut self, write_barrier: bool) -> Self {
+        self.write_barrier = write_barrier;
+        self
+    }
+
+    /// Copy the regions beyond the previous end of regular file destinations from the source with sendfile(2),
+    /// in the kernel, instead of writing them from the read buffer. Falls back to writes if it is not supported.
+    pub fn use_sendfile(mut self, use_sendfile: bool) -> Self {
+        self.use_sendfile = use_sendfile;
+        self
+    }
+
+    /// Log read and write errors and go on with the next chunk instead of failing, for degraded drives.
+    /// A chunk the source cannot read is left as is in the destinations. Errors are in `SyncStats::error_log`.
+    pub fn ignore_errors(mut self, ignore_errors: bool) -> Self {
+        self.ignore_errors = ignore_errors;
+        self
+    }
+
+    /// Retry a failed read or write up to this many times, waiting 100 ms then twice longer each time, before giving up
+    /// (or going on with ignore_errors). For flaky drives and network storage.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Resize a regular file destination of another size to the source size, default true.
+    /// If false, fail instead, in case the destination is wrong. A new destination file is always created.
+    pub fn truncate_dest(mut self, truncate_dest: bool) -> Self {
+        self.truncate_dest = truncate_dest;
+        self
+    }
+
+    /// Create a destination which does not exist as a sparse file of the source size, by writing only its last byte
+    pub fn create_sparse_dest(mut self, create_sparse_dest: bool) -> Self {
+        self.create_sparse_dest = create_sparse_dest;
+        self
+    }
+
+    /// After the data, also sync the permissions, the ownership (needs root) and the extended attributes of regular
+    /// file destinations, block devices are skipped. The differences are counted in `SyncStats`.
+    pub fn metadata(mut self, metadata: bool) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Byte-swap every word of this size (2, 4 or 8 bytes) of the source before comparing, to sync an image to one of
+    /// the other endianness. The data written to the destination is the swapped source, not the source as is.
+    /// Not with mmap nor ignore_errors, offset and excluded ranges must be multiples of the word size.
+    pub fn swap_endian(mut self, word_size: Option<usize>) -> Self {
+        self.swap_endian = word_size;
+        self
+    }
+
+    /// Flip a random bit of a source buffer read with this probability, from a generator seeded with the seed, to
+    /// test verify without bad hardware. The destination receives the corrupted data. Debug builds only, not with mmap.
+    #[cfg(debug_assertions)]
+    pub fn simulate_corruption(mut self, rate_seed: Option<(f64, u64)>) -> Self {
+        self.simulate_corruption = rate_seed;
+        self
+    }
+
+    /// Do not read the destinations, assume they are all zeros and write only the non-zero chunks of the source.
+    /// Halves the reads for a new zeroed device, but leaves garbage where the source is zero if it is not zeroed.
+    pub fn seed_zeros(mut self, seed_zeros: bool) -> Self {
+        self.seed_zeros = seed_zeros;
+        self
+    }
+
+    /// After the sync, write zeros to the part of a block device destination beyond the source size,
+    /// which is otherwise left as is as a device cannot be truncated
+    pub fn zero_unwritten(mut self, zero_unwritten: bool) -> Self {
+        self.zero_unwritten = zero_unwritten;
+        self
+    }
+
+    /// Save synced offset in this file regularly and resume from it if it exists
+    pub fn checkpoint(mut self, checkpoint_path: Option<PathBuf>) -> Self {
+        self.checkpoint_path = checkpoint_path;
+        self
+    }
+
+    /// Interval in seconds between checkpoint saves
+    pub fn checkpoint_interval(mut self, checkpoint_interval: u64) -> Self {
+        self.checkpoint_interval = checkpoint_interval;
+        self
+    }
+
+    /// Sync in segments of this many bytes, each flushed and recorded in the checkpoint file when done, skipped on resume.
+    /// Needs a checkpoint file, a multiple of the chunk size.
+    pub fn checkpointed_write(mut self, segment_size: Option<u64>) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Start the sync at this offset of source and destination, must be a multiple of the chunk size
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Sync only this many bytes from the offset, None for up to the end of the source
+    pub fn length(mut self, length: Option<u64>) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// Read the source from this position, synced to the start of the destination, e.g. to extract a partition
+    /// of a whole disk image. With a length, the source ends at source_offset + offset + length.
+    pub fn source_offset(mut self, source_offset: u64) -> Self {
+        self.source_offset = source_offset;
+        self
+    }
+
+    /// Byte ranges [start, end) to sync first in this order, e.g. to make a VM image bootable sooner, then the rest
+    /// from the start. They are expanded to the chunk size. Not with a checkpoint nor buffer_count.
+    pub fn priority_regions(mut self, priority_regions: Vec<(u64, u64)>) -> Self {
+        self.priority_regions = priority_regions;
+        self
+    }
+
+    /// Byte ranges known to be identical, neither read nor written
+    pub fn exclude_ranges(mut self, exclude_ranges: Vec<ExcludeRange>) -> Self {
+        self.exclude_ranges = exclude::normalize_ranges(exclude_ranges);
+        self
+    }
+
+    /// Base image, e.g. the previous backup, copied to destinations which size differs from it before the sync.
+    /// The source is then read only once to write what changed since the base.
+    pub fn seed_from(mut self, seed_from: Option<PathBuf>) -> Self {
+        self.seed_from = seed_from;
+        self
+    }
+
+    /// Stop cleanly after the current buffer when this flag is set, e.g. by a signal handler
+    pub fn stop_flag(mut self, stop_flag: Option<Arc<AtomicBool>>) -> Self {
+        self.stop_flag = stop_flag;
+        self
+    }
+
+    /// Take the read buffers from this pool and give them back at the end, to not allocate them again for each
+    /// file when syncing many files in a row with the same buffer sizes
+    pub fn buffer_pool(mut self, buffer_pool: Option<Arc<BufferPool>>) -> Self {
+        self.buffer_pool = buffer_pool;
+        self
+    }
+
+    /// Send progress and error events to this channel during the sync, e.g. for a user interface in another thread
+    pub fn events(mut self, events: Option<Sender<SyncEvent>>) -> Self {
+        self.events = events;
+        self
+    }
+}
+
+/// Event of a running sync, sent to the `SyncOptions::events` channel
+#[derive(Debug)]
+pub enum SyncEvent {
+    /// Sent after each buffer: offset reached, in the synced range from start to end
+    Progress { offset: u64, start: u64, end: u64, bytes_compared: u64, bytes_written: u64, chunks_different: u64 },
+    /// An I/O error skipped with ignore_errors, or a destination failing in a multi destination sync
+    Error { offset: u64, message: String },
+    /// End of the sync, not sent by the sync itself but by whoever runs it in a thread
+    Done(Result<SyncStats, SyncError>),
+}
+
+/// Result of a sync
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    /// Bytes written to destinations (or that would be written in dry run)
+    pub bytes_written: u64,
+    /// Bytes read and compared from source
+    pub bytes_compared: u64,
+    /// Bytes skipped because they are in an exclude range
+    pub bytes_skipped: u64,
+    /// Bytes copied from the seed base image to destinations before the sync
+    pub bytes_seeded: u64,
+    /// Bytes of data sent to a remote destination, less than bytes written when compressed
+    pub bytes_sent: u64,
+    /// Bytes that actually differed in the written chunks, summed over destinations, with count_differing_bytes only
+    pub bytes_would_write_minimal: u64,
+    /// Metadata differences found with the metadata option, summed over destinations
+    pub metadata_differences: u64,
+    /// Metadata differences applied, fewer than found in dry run or without the permission to apply some
+    pub metadata_applied: u64,
+    /// Duration of the read, compare and write loop
+    pub elapsed: Duration,
+    /// Part of elapsed spent writing, including rate limit waits, the rest is reading and comparing
+    pub write_elapsed: Duration,
+    /// Duration of the final fsync or fdatasync, zero without flush mode
+    pub flush_elapsed: Duration,
+    /// True if nothing was written because of dry run
+    pub dry_run: bool,
+    /// True if the sync was stopped by the stop flag before the end of the source
+    pub interrupted: bool,
+    /// Offset in the source where the sync stopped, use it as offset to continue an interrupted sync
+    pub end_offset: u64,
+    /// Number of regions checked by verify, 0 if verify is not enabled
+    pub verified_regions: usize,
+    /// Duration of the verify pass, not included in elapsed
+    pub verify_elapsed: Duration,
+    /// Compared chunks identical in source and destination, summed over destinations
+    pub chunks_identical: u64,
+    /// Compared chunks that differed and were written, summed over destinations
+    pub chunks_different: u64,
+    /// Number of writes (or hole punches) to destinations, adjacent differing chunks are written in one call
+    pub write_calls: u64,
+    /// Number of write calls by write size in bytes
+    pub write_sizes: BTreeMap<usize, u64>,
+    /// Details of each destination
+    pub destinations: Vec<DestinationStats>,
+    /// I/O errors skipped with ignore_errors
+    pub error_log: ErrorLog,
+}
+
+impl SyncStats {
+    /// Add the stats of a sync of the next part of the same range to the same destinations
+    pub(crate) fn merge(&mut self, other: SyncStats){
+        self.bytes_written += other.bytes_written;
+        self.bytes_compared += other.bytes_compared;
+        self.bytes_skipped += other.bytes_skipped;
+        self.bytes_seeded += other.bytes_seeded;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_would_write_minimal += other.bytes_would_write_minimal;
+        self.metadata_differences += other.metadata_differences;
+        self.metadata_applied += other.metadata_applied;
+        self.elapsed += other.elapsed;
+        self.write_elapsed += other.write_elapsed;
+        self.flush_elapsed += other.flush_elapsed;
+        self.dry_run = other.dry_run;
+        self.interrupted = other.interrupted;
+        self.end_offset = other.end_offset;
+        self.verified_regions += other.verified_regions;
+        self.verify_elapsed += other.verify_elapsed;
+        self.chunks_identical += other.chunks_identical;
+        self.chunks_different += other.chunks_different;
+        self.write_calls += other.write_calls;
+        for (size, count) in other.write_sizes{
+            *self.write_sizes.entry(size).or_default() += count;
+        }
+        if self.destinations.is_empty(){
+            self.destinations = other.destinations;
+        }else{
+            for (dest, other) in self.destinations.iter_mut().zip(other.destinations){
+                dest.bytes_written += other.bytes_written;
+                dest.written_ranges.extend(other.written_ranges);
+                dest.error = dest.error.take().or(other.error);
+            }
+        }
+        self.error_log.entries.extend(other.error_log.entries);
+    }
+}
+
+/// I/O errors skipped during a sync with ignore_errors, (offset, message) in the order they happened
+#[derive(Debug, Clone, Default)]
+pub struct ErrorLog {
+    pub entries: Vec<(u64, String)>,
+}
+
+impl ErrorLog {
+    /// Log the error and keep it
+    fn record(&mut self, offset: u64, message: String){
+        log::error!("I/O error at offset {}: {}, skipped.", offset, message);
+        self.entries.push((offset, message));
+    }
+}
+
+/// Result of a sync for one destination
+#[derive(Debug, Clone, Default)]
+pub struct DestinationStats {
+    pub path: PathBuf,
+    /// Bytes written to this destination
+    pub bytes_written: u64,
+    /// Written regions (offset, len) in order, adjacent ones merged
+    pub written_ranges: Vec<(u64, usize)>,
+    /// Why this destination was skipped, only with several destinations as a single one fails the whole sync
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    SourceOpenFailed(PathBuf, io::Error),
+    DestOpenFailed(PathBuf, io::Error),
+    DeviceSizeFailed(PathBuf, io::Error),
+    DestTooSmall { src_size: u64, dst_size: u64 },
+    TruncateFailed(PathBuf, io::Error),
+    TruncateRefused { path: PathBuf, dst_size: u64, src_size: u64 },
+    SourceReadFailed { offset: u64, source: io::Error },
+    DestReadFailed { offset: u64, source: io::Error },
+    WriteFailed { offset: u64, source: io::Error },
+    ReadMismatch { offset: u64, src_len: usize, dst_len: usize },
+    InvalidCheckpoint(PathBuf),
+    CheckpointMismatch { checkpoint_path: PathBuf, src_path: String, dst_path: String },
+    CheckpointWriteFailed(PathBuf, io::Error),
+    FlushFailed(PathBuf, io::Error),
+    DeltaFailed(PathBuf, io::Error),
+    ChecksumFailed(PathBuf, io::Error),
+    BlockMapFailed(PathBuf, io::Error),
+    MetadataFailed(PathBuf, io::Error),
+    JournalFailed(PathBuf, io::Error),
+    InvalidOptions(String),
+    RemoteFailed(String, io::Error),
+    VerifyFailed { mismatches: Vec<(u64, usize)>, regions: usize },
+    WriteLimitExceeded { bytes_written: u64, limit: u64 },
+    DiffRatioExceeded { ratio: f64, limit: f64 },
+    Io(io::Error),
+}
+
+impl From<io::Error> for SyncError {
+    fn from(err: io::Error) -> Self {
+        SyncError::Io(err)
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::SourceOpenFailed(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            SyncError::DestOpenFailed(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            SyncError::DeviceSizeFailed(path, err) => write!(f, "Failed to get size of block device {}: {}", path.display(), err),
+            SyncError::DestTooSmall { src_size, dst_size } => write!(f, "Destination is a block device and is too small ({} < {} bytes).", dst_size, src_size),
+            SyncError::TruncateFailed(path, err) => write!(f, "Failed to truncate {}: {}", path.display(), err),
+            SyncError::TruncateRefused { path, dst_size, src_size } => write!(f, "Refusing to truncate {} from {} to {} bytes, the source size, wrong destination ?", path.display(), dst_size, src_size),
+            SyncError::SourceReadFailed { offset, source } => write!(f, "Failed to read source at offset {}: {}", offset, source),
+            SyncError::DestReadFailed { offset, source } => write!(f, "Failed to read destination at offset {}: {}", offset, source),
+            SyncError::WriteFailed { offset, source } => write!(f, "Failed to write destination at offset {}: {}", offset, source),
+            SyncError::ReadMismatch { offset, src_len, dst_len } => write!(f, "Read len are not equal at offset {} ! (source {}, destination {})", offset, src_len, dst_len),
+            SyncError::InvalidCheckpoint(path) => write!(f, "Invalid checkpoint file {}.", path.display()),
+            SyncError::CheckpointMismatch { checkpoint_path, src_path, dst_path } => write!(f, "Checkpoint {} was made for {} to {}, refusing to resume.", checkpoint_path.display(), src_path, dst_path),
+            SyncError::CheckpointWriteFailed(path, err) => write!(f, "Failed to write checkpoint {}: {}", path.display(), err),
+            SyncError::FlushFailed(path, err) => write!(f, "Failed to flush {} to disk: {}", path.display(), err),
+            SyncError::DeltaFailed(path, err) => write!(f, "Failed to read or write delta file {}: {}", path.display(), err),
+            SyncError::ChecksumFailed(path, err) => write!(f, "Failed to read or write checksum file {}: {}", path.display(), err),
+            SyncError::BlockMapFailed(path, err) => write!(f, "Failed to write block map {}: {}", path.display(), err),
+            SyncError::MetadataFailed(path, err) => write!(f, "Failed to sync metadata of {}: {}", path.display(), err),
+            SyncError::JournalFailed(path, err) => write!(f, "Failed to read or write journal {}: {}", path.display(), err),
+            SyncError::InvalidOptions(msg) => write!(f, "Invalid options: {}", msg),
+            SyncError::RemoteFailed(addr, err) => write!(f, "Remote {} failed: {}", addr, err),
+            SyncError::VerifyFailed { mismatches, regions } => {
+                for (offset, len) in mismatches{
+                    writeln!(f, "Verify failed: {} bytes at offset {} differ from source.", len, offset)?;
+                }
+                write!(f, "Verify failed: {} of {} written regions do not match !", mismatches.len(), regions)
+            },
+            SyncError::WriteLimitExceeded { bytes_written, limit } => write!(f, "Write limit exceeded: {} bytes written, the next write would go above the limit of {} bytes, aborting.", bytes_written, limit),
+            SyncError::DiffRatioExceeded { ratio, limit } => write!(f, "Difference ratio exceeded: {:.1}% of the data compared differs, above the limit of {:.1}%, wrong source or destination ? Aborting.", ratio * 100., limit * 100.),
+            SyncError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::SourceOpenFailed(_, err)
+            | SyncError::DestOpenFailed(_, err)
+            | SyncError::DeviceSizeFailed(_, err)
+            | SyncError::TruncateFailed(_, err)
+            | SyncError::CheckpointWriteFailed(_, err)
+            | SyncError::FlushFailed(_, err)
+            | SyncError::DeltaFailed(_, err)
+            | SyncError::ChecksumFailed(_, err)
+            | SyncError::BlockMapFailed(_, err)
+            | SyncError::MetadataFailed(_, err)
+            | SyncError::JournalFailed(_, err)
+            | SyncError::RemoteFailed(_, err)
+            | SyncError::Io(err) => Some(err),
+            SyncError::SourceReadFailed { source, .. }
+            | SyncError::DestReadFailed { source, .. }
+            | SyncError::WriteFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Generate ioctl function
+const BLK_IOCTL_CODE: u8 = 0x12; // Defined in linux/fs.h
+const BLKSSZGET_SEQ: u8 = 104;
+const BLKPBSZGET_SEQ: u8 = 123;
+const BLKDISCARD_SEQ: u8 = 119;
+const BLKFLSBUF_SEQ: u8 = 97;
+ioctl_read_bad!(ioctl_blksszget, request_code_none!(BLK_IOCTL_CODE, BLKSSZGET_SEQ), nix::libc::c_int); // Logical sector size
+ioctl_read_bad!(ioctl_blkpbszget, request_code_none!(BLK_IOCTL_CODE, BLKPBSZGET_SEQ), nix::libc::c_uint); // Physical block size
+ioctl_write_ptr_bad!(ioctl_blkdiscard, request_code_none!(BLK_IOCTL_CODE, BLKDISCARD_SEQ), [u64; 2]); // Discard [offset, len]
+ioctl_none!(ioctl_blkflsbuf, BLK_IOCTL_CODE, BLKFLSBUF_SEQ); // Flush buffers to the device
+
+/// Physical block size of a block device, or its logical sector size if unknown. None if not a block device.
+pub fn get_physical_block_size(fd: RawFd) -> Option<u32> {
+    let mut pbs: nix::libc::c_uint = 0;
+    if unsafe { ioctl_blkpbszget(fd, &mut pbs) }.is_ok() && pbs > 0{
+        return Some(pbs);
+    }
+    get_logical_block_size(fd)
+}
+
+/// Logical sector size of a block device, the smallest unit it can address. None if not a block device.
+pub fn get_logical_block_size(fd: RawFd) -> Option<u32> {
+    let mut lbs: nix::libc::c_int = 0;
+    if unsafe { ioctl_blksszget(fd, &mut lbs) }.is_ok() && lbs > 0{
+        return Some(lbs as u32);
+    }
+    None
+}
+
+/// Minimal alignment of O_DIRECT buffers, offsets and lengths, the block size of most filesystems
+pub(crate) const DIRECT_ALIGN: usize = 4096;
+/// ANSI escape codes of the colored progress output
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+/// First wait before retrying a failed read or write
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Open path with O_DIRECT if direct, without it with a warning if the filesystem does not support it
+fn open_direct(options: &OpenOptions, path: &Path, direct: bool) -> io::Result<File> {
+    if !direct{
+        return options.open(path);
+    }
+    match options.clone().custom_flags(nix::libc::O_DIRECT).open(path){
+        Err(err) if err.raw_os_error() == Some(nix::libc::EINVAL) => {
+            log::warn!("Warning: O_DIRECT is not supported for {}, using the page cache.", path.display());
+            options.open(path)
+        },
+        result => result,
+    }
+}
+
+/// Remove O_DIRECT from an open file, for unaligned reads and writes
+fn clear_direct(file: &File) -> io::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(flags - OFlag::O_DIRECT))?;
+    Ok(())
+}
+
+/// Deallocate len bytes at offset of the file, which then read as zeros. The file size is not changed.
+pub fn punch_hole(fd: RawFd, offset: u64, len: u64) -> nix::Result<()> {
+    fallocate(fd, FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE, offset as i64, len as i64)
+}
+
+/// Tell the kernel the whole file is read sequentially, which makes its read-ahead more aggressive
+pub fn advise_sequential(fd: RawFd) -> nix::Result<()> {
+    posix_fadvise(fd, 0, 0, PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL)
+}
+
+/// Ask the kernel to start reading len bytes at offset of the files in the background, best effort
+fn advise_read_ahead<'a>(files: impl Iterator<Item = &'a File>, offset: u64, len: usize) {
+    for file in files{
+        let _ = posix_fadvise(file.as_raw_fd(), offset as i64, len as i64, PosixFadviseAdvice::POSIX_FADV_WILLNEED);
+    }
+}
+
+/// Write zeros to len bytes at offset of the file, block_size bytes at a time
+pub fn zero_range(file: &File, offset: u64, len: u64, block_size: usize) -> io::Result<()> {
+    let zeros = alloc_aligned(block_size.min(len as usize).max(1), DIRECT_ALIGN);
+    let end = offset + len;
+    let mut pos = offset;
+    while pos < end{
+        let size = zeros.len().min((end - pos) as usize);
+        file.write_all_at(&zeros[0 .. size], pos)?;
+        pos += size as u64;
+    }
+    Ok(())
+}
+
+/// Discard (TRIM) len bytes at offset of a block device, offset and len must be multiples of its logical sector size
+pub fn discard_range(fd: RawFd, offset: u64, len: u64) -> nix::Result<()> {
+    unsafe { ioctl_blkdiscard(fd, &[offset, len]) }.map(drop)
+}
+
+/// Flush the writes of a block device from the caches to the device, with fdatasync if it is not a block device
+pub fn flush_cache(fd: RawFd) -> nix::Result<()> {
+    match unsafe { ioctl_blkflsbuf(fd) }{
+        Err(nix::errno::Errno::ENOTTY) => fdatasync(fd),
+        result => result.map(drop),
+    }
+}
+
+/// Determine block device size
+fn get_device_size(path: &Path) -> Result<u64, SyncError> {
+    match platform::get_device_size(path){
+        Ok(size) => Ok(size),
+        Err(err) => Err(SyncError::DeviceSizeFailed(path.to_path_buf(), err)),
+    }
+}
+
+pub fn is_block_device(path: &std::path::Path) -> bool {
+    match fs::canonicalize(path){
+        Ok(path_abs) => device::is_block_device_stat(&path_abs).unwrap_or_default(),
+        Err(_e) => false,
+    }
+}
+
+/// Size of a file or a block device, 0 if it does not exist
+pub fn filesize(path: &std::path::Path) -> Result<u64, SyncError> {
+    match fs::canonicalize(path){
+        Ok(path_abs) => {
+            if is_block_device(&path_abs) {
+                return get_device_size(&path_abs);
+            }
+            match path_abs.metadata(){
+                Ok(m) => Ok(m.len()),
+                Err(_e) => Ok(0)
+            }
+        },
+        Err(_e) => Ok(0)
+    }
+}
+
+/// Average speed in MB/s and remaining seconds at that speed after elapsed time, no remaining time before anything is read
+pub fn average_speed_eta(file_cursor_pos: f64, src_size: f64, elapsed: Duration) -> (f64, Option<f64>) {
+    // Floor avoids a division by zero right after the start
+    let elapsed = elapsed.as_secs_f64().max(0.001);
+    let speed_mb = file_cursor_pos / elapsed / 1024. / 1024.;
+    let remaining_secs = (file_cursor_pos > 0.).then(|| elapsed * (src_size - file_cursor_pos) / file_cursor_pos);
+    (speed_mb, remaining_secs)
+}
+
+/// Display the progress line, with the count of I/O errors skipped if any, colored with ANSI escape codes if color.
+/// The remaining time is estimated from the recent speed with speed_estimator, from the average speed without.
+pub fn display_progress(file_cursor_pos: f64, src_size: f64, start_time: Instant, speed_estimator: Option<&mut SpeedEstimator>, errors: usize, color: bool){
+    display_progress_prefixed("", file_cursor_pos, src_size, start_time, speed_estimator, errors, color);
+}
+
+/// Display the progress line after prefix, which tells the pass apart
+fn display_progress_prefixed(prefix: &str, file_cursor_pos: f64, src_size: f64, start_time: Instant, speed_estimator: Option<&mut SpeedEstimator>, errors: usize, color: bool){
+    let mut stdout = stdout();
+    let progress = file_cursor_pos / src_size;
+    let progress_round = (progress*10.).ceil();
+    // The last cell is the one in progress
+    let cells_done = (progress*10.).floor() as usize;
+    let cells_started = progress_round as usize - cells_done;
+    let progress_txt = if color{
+        format!("{}{}{}{}{}", GREEN, "#".repeat(cells_done), YELLOW, "#".repeat(cells_started), RESET)
+    }else{
+        format!("{:#<1$}","", progress_round as usize)
+    };
+    let progress_pc = (progress * 100.).ceil();
+    let (speed_mb, average_remaining_secs) = average_speed_eta(file_cursor_pos, src_size, start_time.elapsed());
+    let remaining_secs = match speed_estimator{
+        Some(speed_estimator) => {
+            speed_estimator.update(file_cursor_pos as u64);
+            Some(speed_estimator.eta_secs((src_size - file_cursor_pos) as u64))
+        },
+        None => average_remaining_secs,
+    };
+    // No estimate before anything is read
+    let remaining_txt = match remaining_secs{
+        Some(remaining_secs) if file_cursor_pos > 0. && remaining_secs.is_finite() => format_hms(remaining_secs as u64),
+        _ => "--:--:--".to_string(),
+    };
+    let errors_txt = match (errors, color){
+        (0, _) => String::new(),
+        (_, true) => format!(" - {}{} errors{}", RED, errors, RESET),
+        (_, false) => format!(" - {} errors", errors),
+    };
+    // Escape codes take no room on screen, pad as if they were not there
+    let pad = progress_txt.len() - progress_round as usize;
+    print!("\r{}[{:-<width$}] {}% - {:.3} MB/s - Remaining {}{}          ", prefix, progress_txt, progress_pc, speed_mb, remaining_txt, errors_txt, width = 10 + pad);
+    let _ = stdout.flush();
+}
+
+/// Progress bar of len bytes drawn on stdout, redrawn at most every interval. Its prefix, empty by default, is drawn first.
+fn new_progress_bar(len: u64, interval: Duration, color: bool) -> ProgressBar {
+    let refresh_rate = (1. / interval.as_secs_f64().max(0.05)).ceil() as u8;
+    let progress_bar = ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::stdout_with_hz(refresh_rate));
+    let bar = if color {"{wide_bar:.green/yellow}"} else {"{wide_bar}"};
+    let style = ProgressStyle::with_template(&format!("{{prefix}}[{{elapsed_precise}}] [{}] {{bytes}}/{{total_bytes}} - {{binary_bytes_per_sec}} - Remaining {{eta_precise}}", bar))
+        .unwrap_or_else(|_| ProgressStyle::default_bar());
+    progress_bar.set_style(style.progress_chars("#>-"));
+    progress_bar
+}
+
+/// Format seconds as HH:MM:SS, hours can go above 99
+fn format_hms(secs: u64) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Add region (start, len) to dirty ranges, merged with the last one if adjacent
+pub(crate) fn push_dirty_range(dirty_ranges: &mut Vec<(usize, usize)>, start: usize, len: usize){
+    match dirty_ranges.last_mut(){
+        Some((last_start, last_len)) if *last_start + *last_len == start => *last_len += len,
+        _ => dirty_ranges.push((start, len)),
+    }
+}
+
+/// Regions (start, len) of at least min bytes, adjacent regions are already merged
+pub fn filter_short_runs(ranges: &[(usize, usize)], min: usize) -> Vec<(usize, usize)> {
+    ranges.iter().copied().filter(|&(_, len)| len >= min).collect()
+}
+
+/// Reverse the bytes of every word_size bytes word of buf, a last partial word is left as is
+pub fn swap_endian_inplace(buf: &mut [u8], word_size: usize){
+    for word in buf.chunks_exact_mut(word_size){
+        word.reverse();
+    }
+}
+
+/// Expand region start .. end to the nearest alignment boundaries on both ends
+pub fn align_range(start: usize, end: usize, alignment: usize) -> (usize, usize) {
+    (start / alignment * alignment, end.div_ceil(alignment) * alignment)
+}
+
+/// Print a progress line as a JSON object
+pub fn json_progress(offset: u64, total: u64, bytes_written: u64, start_time: Instant){
+    let progress = serde_json::json!({
+        "type": "progress",
+        "offset": offset,
+        "total": total,
+        "bytes_written": bytes_written,
+        "elapsed_secs": start_time.elapsed().as_secs_f64(),
+    });
+    println!("{}", progress);
+    let _ = stdout().flush();
+}
+
+/// Compare source and destination buffers chunk by chunk and return the differing regions (start, len).
+/// Adjacent differing chunks are merged in one region.
+fn find_dirty_ranges(buffer_src: &[u8], buffer_dst: &[u8], chunk_size: usize) -> Vec<(usize, usize)> {
+    let mut dirty_ranges = Vec::new();
+    if buffer_src == buffer_dst{
+        return dirty_ranges;
+    }
+    for (i, (chunk_src, chunk_dst)) in buffer_src.chunks(chunk_size).zip(buffer_dst.chunks(chunk_size)).enumerate(){
+        if chunk_src != chunk_dst{
+            push_dirty_range(&mut dirty_ranges, i * chunk_size, chunk_src.len());
+        }
+    }
+    dirty_ranges
+}
+
+/// Same as find_dirty_ranges but the chunks are compared in parallel, in the current rayon thread pool
+pub fn compare_buffer_parallel(src: &[u8], dst: &[u8], chunk_size: usize) -> Vec<(usize, usize)> {
+    let dirty_chunks: Vec<usize> = src.par_chunks(chunk_size).zip(dst.par_chunks(chunk_size)).enumerate()
+        .filter(|(_, (chunk_src, chunk_dst))| chunk_src != chunk_dst)
+        .map(|(i, _)| i)
+        .collect();
+    let mut dirty_ranges = Vec::new();
+    for i in dirty_chunks{
+        push_dirty_range(&mut dirty_ranges, i * chunk_size, chunk_size.min(src.len() - i * chunk_size));
+    }
+    dirty_ranges
+}
+
+/// Number of bytes that differ between src and dst in the given regions
+fn differing_bytes(src: &[u8], dst: &[u8], ranges: &[(usize, usize)]) -> u64 {
+    ranges.iter().map(|&(start, len)| {
+        src[start .. start + len].iter().zip(&dst[start .. start + len]).filter(|(byte_src, byte_dst)| byte_src != byte_dst).count() as u64
+    }).sum()
+}
+
+/// Regions of the buffer made of chunks that are not all zeros, the ones to write to a zeroed destination
+fn find_nonzero_ranges(buffer_src: &[u8], chunk_size: usize) -> Vec<(usize, usize)> {
+    let mut dirty_ranges = Vec::new();
+    for (i, chunk_src) in buffer_src.chunks(chunk_size).enumerate(){
+        if chunk_src.iter().any(|&byte| byte != 0){
+            push_dirty_range(&mut dirty_ranges, i * chunk_size, chunk_src.len());
+        }
+    }
+    dirty_ranges
+}
+
+/// Run an I/O operation, retry it up to max_retries times while it fails, waiting RETRY_DELAY then twice longer each time
+pub fn retry_io<F, T>(mut op: F, max_retries: u32) -> io::Result<T>
+where F: FnMut() -> io::Result<T> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop{
+        match op(){
+            Err(err) if attempt < max_retries => {
+                attempt += 1;
+                log::warn!("Warning: {}, retry {} of {} in {} ms", err, attempt, max_retries, delay.as_millis());
+                thread::sleep(delay);
+                delay *= 2;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Write data at offset then read it back, fail with InvalidData if it differs
+pub fn write_and_verify(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    file.write_all_at(data, offset)?;
+    read_back(file, data, offset)
+}
+
+/// Read the region written with data at offset, fail with InvalidData if it differs
+fn read_back(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    // Aligned for direct I/O
+    let mut buffer = alloc_aligned(data.len(), DIRECT_ALIGN);
+    let len = read_full_at(file, &mut buffer, offset)?;
+    if buffer[0 .. len] != *data{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "data read back differs from the data written"));
+    }
+    Ok(())
+}
+
+/// Read until buf is full or end of file is reached, return bytes read
+pub(crate) fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len(){
+        match file.read_at(&mut buf[len ..], offset + len as u64){
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Same as find_dirty_ranges but compare SHA-256 digests of each chunk.
+/// Destination chunks are read one at a time at file offset fp + chunk position into buffer_chunk, which size is the chunk size.
+fn find_dirty_ranges_sha256(buffer_src: &[u8], dst_file: &File, fp: u64, buffer_chunk: &mut [u8], dry_run: bool, retries: u32, mut bytes_differing: Option<&mut u64>) -> Result<Vec<(usize, usize)>, SyncError> {
+    let chunk_size = buffer_chunk.len();
+    let mut dirty_ranges = Vec::new();
+    for (i, chunk_src) in buffer_src.chunks(chunk_size).enumerate(){
+        let offset = fp + (i * chunk_size) as u64;
+        let chunk_dst = &mut buffer_chunk[0 .. chunk_src.len()];
+        let dst_len = match retry_io(|| read_full_at(dst_file, chunk_dst, offset), retries){
+            Ok(dst_len) => dst_len,
+            Err(err) => return Err(SyncError::DestReadFailed { offset, source: err }),
+        };
+        if dst_len < chunk_src.len(){
+            if !dry_run{
+                return Err(SyncError::ReadMismatch { offset, src_len: chunk_src.len(), dst_len });
+            }
+            // Destination is not truncated in dry run, pad like it would be
+            chunk_dst[dst_len ..].fill(0);
+        }
+        if Sha256::digest(chunk_src) != Sha256::digest(&*chunk_dst){
+            push_dirty_range(&mut dirty_ranges, i * chunk_size, chunk_src.len());
+            if let Some(bytes_differing) = bytes_differing.as_deref_mut(){
+                *bytes_differing += differing_bytes(chunk_src, chunk_dst, &[(0, chunk_src.len())]);
+            }
+        }
+    }
+    Ok(dirty_ranges)
+}
+
+/// Copy len bytes at src_offset of src_file to dst_offset of dst_file with sendfile(2), without copying them to
+/// user space. The file position of dst_file is kept. Fails with EINVAL if the files do not support it.
+pub fn sendfile_range(src_file: &File, dst_file: &File, src_offset: u64, dst_offset: u64, len: usize) -> io::Result<()> {
+    // sendfile writes at the file position of the destination
+    let mut dst = dst_file;
+    let position = dst.stream_position()?;
+    dst.seek(SeekFrom::Start(dst_offset))?;
+    let mut src_offset = src_offset as nix::libc::off_t;
+    let mut sent = 0;
+    let result = loop{
+        if sent == len{
+            break Ok(());
+        }
+        match sendfile(dst_file.as_raw_fd(), src_file.as_raw_fd(), Some(&mut src_offset), len - sent){
+            Ok(0) => break Err(io::Error::new(io::ErrorKind::UnexpectedEof, "source ended during sendfile")),
+            Ok(n) => sent += n,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => break Err(err.into()),
+        }
+    };
+    dst.seek(SeekFrom::Start(position))?;
+    result
+}
+
+/// Source of the regions a destination did not have yet, copied with sendfile
+#[derive(Clone, Copy)]
+struct SendfileSource<'a> {
+    src_file: &'a File,
+    /// Offset of the synced data in the source file
+    source_offset: u64,
+    /// Size of the destination before the sync, the regions from there are sent
+    dst_size: u64,
+}
+
+/// Where differing regions go: the destination, a delta file, or nowhere in dry run
+struct RegionWriter<'a> {
+    dst_file: &'a File,
+    /// Writes go through it with an I/O engine other than sync
+    backend: Option<Box<dyn IoBackend>>,
+    dry_run: bool,
+    /// Retries of a failed write
+    retries: u32,
+    /// Alignment of the written regions in file offsets
+    align: usize,
+    /// Differing regions shorter than this are not written
+    diff_filter: usize,
+    /// Read back and check each write
+    verify_each: bool,
+    /// Flush the device cache after each write
+    barrier: bool,
+    /// Regions beyond the previous end of the destination are sent from there
+    sendfile: Option<SendfileSource<'a>>,
+    delta: Option<(&'a Path, DeltaWriter<DeltaOutput>)>,
+    /// The destination data is saved there before it is overwritten
+    journal: Option<(&'a Path, JournalWriter<BufWriter<File>>)>,
+    rate_limiter: Option<RateLimiter>,
+    iops_limiter: Option<IopsLimiter>,
+    /// Fail before more than this is written
+    max_bytes_written: Option<u64>,
+    /// Punch holes for zero chunks instead of writing them
+    sparse: bool,
+    /// Deallocate zero chunks with a discard instead of a punched hole, for block devices
+    trim: bool,
+    bytes_written: u64,
+    /// Bytes that differ in the written chunks, with count_differing
+    bytes_differing: u64,
+    write_elapsed: Duration,
+    chunks_identical: u64,
+    chunks_different: u64,
+    /// Number of write calls by size
+    write_sizes: BTreeMap<usize, u64>,
+    /// Each written region (offset, len)
+    written_ranges: Vec<(u64, usize)>,
+}
+
+impl RegionWriter<'_> {
+    /// Write data at offset. In dry run mode, nothing is written but it is counted the same.
+    fn write(&mut self, data: &[u8], offset: u64) -> Result<(), SyncError> {
+        self.check_limit(data.len())?;
+        if let Some((delta_path, delta)) = &mut self.delta{
+            if let Err(err) = delta.write_entry(offset, data){
+                return Err(SyncError::DeltaFailed(delta_path.to_path_buf(), err));
+            }
+        }else if !self.dry_run{
+            self.save_old_data(offset, data.len())?;
+            let sent = match self.sendfile{
+                Some(source) if offset >= source.dst_size => match retry_io(|| sendfile_range(source.src_file, self.dst_file, source.source_offset + offset, offset, data.len()), self.retries){
+                    Ok(()) => true,
+                    Err(err) if err.raw_os_error() == Some(nix::libc::EINVAL) => {
+                        log::warn!("Warning: sendfile is not supported for this source and destination, writing instead.");
+                        self.sendfile = None;
+                        false
+                    },
+                    Err(err) => return Err(SyncError::WriteFailed { offset, source: err }),
+                },
+                _ => false,
+            };
+            let result = match &self.backend{
+                _ if sent => Ok(()),
+                Some(backend) if self.verify_each => retry_io(|| backend.write_at(data, offset).and_then(|()| read_back(self.dst_file, data, offset)), self.retries),
+                Some(backend) => retry_io(|| backend.write_at(data, offset), self.retries),
+                None if self.verify_each => retry_io(|| write_and_verify(self.dst_file, data, offset), self.retries),
+                None => retry_io(|| self.dst_file.write_all_at(data, offset), self.retries),
+            };
+            let result = result.and_then(|()| self.flush_barrier());
+            if let Err(err) = result{
+                return Err(SyncError::WriteFailed { offset, source: err });
+            }
+        }
+        if let (Some(rate_limiter), false) = (&mut self.rate_limiter, self.dry_run){
+            rate_limiter.consume(data.len());
+        }
+        if let (Some(iops_limiter), false) = (&mut self.iops_limiter, self.dry_run){
+            iops_limiter.consume();
+        }
+        self.written_ranges.push((offset, data.len()));
+        self.bytes_written += data.len() as u64;
+        *self.write_sizes.entry(data.len()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Flush the device cache with write barrier, before the next write
+    fn flush_barrier(&self) -> io::Result<()> {
+        if !self.barrier{
+            return Ok(());
+        }
+        retry_io(|| flush_cache(self.dst_file.as_raw_fd()).map_err(io::Error::from), self.retries)
+    }
+
+    /// Save the destination region about to be overwritten to the journal, if any
+    fn save_old_data(&mut self, offset: u64, len: usize) -> Result<(), SyncError> {
+        if let Some((journal_path, journal)) = &mut self.journal{
+            let mut old_data = vec![0u8; len];
+            // Short beyond the end of the destination, undo truncates it back
+            let read = match read_full_at(self.dst_file, &mut old_data, offset){
+                Ok(read) => read,
+                Err(err) => return Err(SyncError::DestReadFailed { offset, source: err }),
+            };
+            if let Err(err) = journal.write_entry(offset, &old_data[.. read]){
+                return Err(SyncError::JournalFailed(journal_path.to_path_buf(), err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if writing len more bytes would go above max_bytes_written
+    fn check_limit(&self, len: usize) -> Result<(), SyncError> {
+        match self.max_bytes_written{
+            Some(limit) if self.bytes_written + len as u64 > limit => Err(SyncError::WriteLimitExceeded { bytes_written: self.bytes_written, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make the region at offset read as zeros by punching a hole or discarding it, write the zeros if it is not supported
+    fn punch(&mut self, data: &[u8], offset: u64) -> Result<(), SyncError> {
+        if self.delta.is_some() || self.dry_run{
+            return self.write(data, offset);
+        }
+        self.check_limit(data.len())?;
+        self.save_old_data(offset, data.len())?;
+        let result = if self.trim{
+            discard_range(self.dst_file.as_raw_fd(), offset, data.len() as u64)
+        }else{
+            punch_hole(self.dst_file.as_raw_fd(), offset, data.len() as u64)
+        };
+        match result{
+            Ok(()) => (),
+            Err(nix::errno::Errno::EOPNOTSUPP) => {
+                self.sparse = false;
+                return self.write(data, offset);
+            },
+            // Discard needs a range aligned to the logical sector size, e.g. not the end of a smaller source
+            Err(nix::errno::Errno::EINVAL) if self.trim => return self.write(data, offset),
+            Err(err) => return Err(SyncError::WriteFailed { offset, source: err.into() }),
+        }
+        if let Err(err) = self.flush_barrier(){
+            return Err(SyncError::WriteFailed { offset, source: err });
+        }
+        if let Some(iops_limiter) = &mut self.iops_limiter{
+            iops_limiter.consume();
+        }
+        self.written_ranges.push((offset, data.len()));
+        self.bytes_written += data.len() as u64;
+        *self.write_sizes.entry(data.len()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Write the given regions of the source buffer, which starts at file offset fp. Adjacent differing chunks are
+    /// already merged by push_dirty_range, so a contiguous region is written in one call. In sparse or trim mode, runs of zero chunks are punched or discarded instead of written.
+    fn write_ranges(&mut self, buffer_src: &[u8], dirty_ranges: &[(usize, usize)], fp: usize, chunk_size: usize) -> Result<(), SyncError> {
+        let filtered_ranges;
+        let dirty_ranges = if self.diff_filter > 0{
+            filtered_ranges = filter_short_runs(dirty_ranges, self.diff_filter);
+            &filtered_ranges
+        }else{
+            dirty_ranges
+        };
+        let chunks_different: u64 = dirty_ranges.iter().map(|&(_, len)| len.div_ceil(chunk_size) as u64).sum();
+        self.chunks_different += chunks_different;
+        self.chunks_identical += buffer_src.len().div_ceil(chunk_size) as u64 - chunks_different;
+        let aligned_ranges;
+        let dirty_ranges = if self.align > 1{
+            aligned_ranges = self.align_ranges(dirty_ranges, fp, buffer_src.len());
+            &aligned_ranges
+        }else{
+            dirty_ranges
+        };
+        let write_start = Instant::now();
+        for &(start, len) in dirty_ranges{
+            let region = &buffer_src[start .. start + len];
+            if !self.sparse{
+                self.write(region, fp as u64 + start as u64)?;
+                continue;
+            }
+            let is_zero = |chunk: &[u8]| chunk.iter().all(|&byte| byte == 0);
+            let mut run_start = 0;
+            while run_start < len{
+                let zero = is_zero(&region[run_start .. (run_start + chunk_size).min(len)]);
+                let mut run_end = run_start;
+                while run_end < len && is_zero(&region[run_end .. (run_end + chunk_size).min(len)]) == zero{
+                    run_end = (run_end + chunk_size).min(len);
+                }
+                let offset = fp as u64 + (start + run_start) as u64;
+                if zero{
+                    self.punch(&region[run_start .. run_end], offset)?;
+                }else{
+                    self.write(&region[run_start .. run_end], offset)?;
+                }
+                run_start = run_end;
+            }
+        }
+        self.write_elapsed += write_start.elapsed();
+        Ok(())
+    }
+
+    /// Expand regions of the buffer at file offset fp to the alignment, within the buffer, and merge the ones that overlap
+    fn align_ranges(&self, dirty_ranges: &[(usize, usize)], fp: usize, buffer_len: usize) -> Vec<(usize, usize)> {
+        let mut aligned_ranges: Vec<(usize, usize)> = Vec::new();
+        for &(start, len) in dirty_ranges{
+            let (aligned_start, aligned_end) = align_range(fp + start, fp + start + len, self.align);
+            let start = aligned_start.max(fp) - fp;
+            let end = aligned_end.min(fp + buffer_len) - fp;
+            match aligned_ranges.last_mut(){
+                Some((last_start, last_len)) if *last_start + *last_len >= start => *last_len = end - *last_start,
+                _ => aligned_ranges.push((start, end - start)),
+            }
+        }
+        aligned_ranges
+    }
+
+    /// Flush the delta file if any, return the written regions
+    fn finish(self) -> Result<Vec<(u64, usize)>, SyncError> {
+        if let Some((delta_path, delta)) = self.delta{
+            if let Err(err) = delta.finish().and_then(DeltaOutput::finish){
+                return Err(SyncError::DeltaFailed(delta_path.to_path_buf(), err));
+            }
+        }
+        Ok(self.written_ranges)
+    }
+}
+
+/// Read next buffers of source and destination, at the same time in 2 threads if threaded, each read retried up to retries times
+fn read_buffers(mut src_file: &File, mut dst_file: &File, buffer_src: &mut [u8], buffer_dst: &mut [u8], threaded: bool, retries: u32) -> (io::Result<usize>, io::Result<usize>) {
+    if threaded{
+        thread::scope(|scope| {
+            let thandle = scope.spawn(move || retry_io(|| src_file.read(buffer_src), retries));
+            let dst_read = retry_io(|| dst_file.read(buffer_dst), retries);
+            // Wait thread to finish, a panic is a read error so the sync stops instead of going on out of step
+            let src_read = thandle.join().unwrap_or_else(|_| Err(io::Error::other("source read thread panicked")));
+            (src_read, dst_read)
+        })
+    }else{
+        (retry_io(|| src_file.read(buffer_src), retries), retry_io(|| dst_file.read(buffer_dst), retries))
+    }
+}
+
+/// In dry run mode the destination is not truncated, so make its read length match the source
+/// as if it was: pad with zeros when shorter, ignore the extra when longer.
+fn dry_run_dst_len(buffer_dst: &mut [u8], src_len: usize, dst_len: usize) -> usize {
+    if src_len > dst_len{
+        buffer_dst[dst_len .. src_len].fill(0);
+    }
+    src_len
+}
+
+/// How the progress of the verify pass is shown, it is not shown when quiet, without progress or in JSON
+#[derive(Clone, Copy)]
+struct VerifyProgress {
+    progress_bar: bool,
+    color: bool,
+    interval: Duration,
+}
+
+/// Re-read each written region from source and destination and compare them byte for byte, the source swapped
+/// like during the sync, showing the progress if any. Return the regions that do not match.
+fn verify_written(src_path: &Path, dst_path: &Path, written_ranges: &[(u64, usize)], source_offset: u64, swap_endian: Option<usize>, progress: Option<VerifyProgress>) -> Result<Vec<(u64, usize)>, SyncError> {
+    let src_file = match File::open(src_path){
+        Ok(src_file) => src_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    let dst_file = match File::open(dst_path){
+        Ok(dst_file) => dst_file,
+        Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+    };
+    let max_len = written_ranges.iter().map(|(_, len)| *len).max().unwrap_or(0);
+    let mut buffer_src = vec![0u8; max_len];
+    let mut buffer_dst = vec![0u8; max_len];
+    let mut mismatches = Vec::new();
+    let total: u64 = written_ranges.iter().map(|&(_, len)| len as u64).sum();
+    let progress_bar = progress.filter(|progress| progress.progress_bar).map(|progress| {
+        let progress_bar = new_progress_bar(total, progress.interval, progress.color);
+        progress_bar.set_prefix("[VERIFY] ");
+        progress_bar
+    });
+    let start_time = Instant::now();
+    let mut time2display = Instant::now();
+    let mut verified = 0;
+    for &(offset, len) in written_ranges{
+        if let Err(err) = src_file.read_exact_at(&mut buffer_src[0 .. len], source_offset + offset){
+            return Err(SyncError::SourceReadFailed { offset: source_offset + offset, source: err });
+        }
+        if let Some(word_size) = swap_endian{
+            swap_endian_inplace(&mut buffer_src[0 .. len], word_size);
+        }
+        if let Err(err) = dst_file.read_exact_at(&mut buffer_dst[0 .. len], offset){
+            return Err(SyncError::DestReadFailed { offset, source: err });
+        }
+        if buffer_src[0 .. len] != buffer_dst[0 .. len]{
+            mismatches.push((offset, len));
+        }
+        verified += len as u64;
+        match (&progress_bar, progress){
+            (Some(progress_bar), _) => progress_bar.set_position(verified),
+            (None, Some(progress)) if time2display.elapsed() >= progress.interval => {
+                display_progress_prefixed("[VERIFY] ", verified as f64, total as f64, start_time, None, 0, progress.color);
+                time2display = Instant::now();
+            },
+            _ => (),
+        }
+    }
+    match (progress_bar, progress){
+        (Some(progress_bar), _) => progress_bar.finish(),
+        (None, Some(progress)) => {
+            display_progress_prefixed("[VERIFY] ", verified as f64, total as f64, start_time, None, 0, progress.color);
+            println!();
+        },
+        (None, None) => (),
+    }
+    Ok(mismatches)
+}
+
+/// Read the offset saved in a checkpoint file, 0 if there is none.
+/// Fail if the checkpoint was made for other paths.
+fn read_checkpoint(checkpoint_path: &Path, src_abs: &str, dst_abs: &str) -> Result<u64, SyncError> {
+    let content = match fs::read_to_string(checkpoint_path){
+        Ok(content) => content,
+        Err(_err) => return Ok(0), // No checkpoint yet
+    };
+    let checkpoint: serde_json::Value = match serde_json::from_str(&content){
+        Ok(checkpoint) => checkpoint,
+        Err(_err) => return Err(SyncError::InvalidCheckpoint(checkpoint_path.to_path_buf())),
+    };
+    if checkpoint["src_path"] != src_abs || checkpoint["dst_path"] != dst_abs{
+        return Err(SyncError::CheckpointMismatch {
+            checkpoint_path: checkpoint_path.to_path_buf(),
+            src_path: checkpoint["src_path"].to_string(),
+            dst_path: checkpoint["dst_path"].to_string(),
+        });
+    }
+    Ok(checkpoint["offset"].as_u64().unwrap_or(0))
+}
+
+/// Save offset in checkpoint file, destinations must be flushed before.
+/// Written in a temporary file then renamed, so a crash never leaves a partial checkpoint.
+fn write_checkpoint(checkpoint_path: &Path, src_abs: &str, dst_abs: &str, offset: u64) -> Result<(), SyncError> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let checkpoint = serde_json::json!({
+        "src_path": src_abs,
+        "dst_path": dst_abs,
+        "offset": offset,
+        "timestamp": timestamp,
+    });
+    let mut tmp_path = checkpoint_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, checkpoint.to_string())
+        .and_then(|_| fs::rename(&tmp_path, checkpoint_path))
+        .map_err(|err| SyncError::CheckpointWriteFailed(checkpoint_path.to_path_buf(), err))
+}
+
+/// Write progress of the synced range [start, end) to a JSON file for external monitoring, through a temporary file
+/// renamed over it so readers never see a partial file
+pub(crate) fn write_progress_file(progress_path: &Path, fp: u64, start: u64, end: u64, bytes_written: u64, start_time: Instant) -> io::Result<()> {
+    let done = fp.saturating_sub(start) as f64;
+    let elapsed = start_time.elapsed().as_secs_f64().max(0.001);
+    let eta_secs = if done > 0. {Some((elapsed * ((end - start) as f64 - done) / done) as u64)} else {None};
+    let progress = serde_json::json!({
+        "offset": fp,
+        "total": end,
+        "bytes_written": bytes_written,
+        "pct": done * 100. / (end - start).max(1) as f64,
+        "eta_secs": eta_secs,
+        "ts": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    });
+    let mut tmp_path = progress_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, progress.to_string())?;
+    fs::rename(&tmp_path, progress_path)
+}
+
+/// How buffers are compared, same for every destination
+#[derive(Clone, Copy)]
+struct Compare<'a> {
+    hash_mode: HashMode,
+    chunk_size: usize,
+    /// Destination is not truncated, its reads are padded to the source length
+    read_only: bool,
+    /// Compare the chunks of a buffer in parallel in this thread pool, byte mode only
+    parallel: Option<&'a rayon::ThreadPool>,
+    /// Destination is not read, it is assumed to be all zeros
+    seed_zeros: bool,
+    /// Retries of a failed destination read
+    retries: u32,
+    /// Count the bytes that differ in the differing chunks
+    count_differing: bool,
+    /// Position in the source of the destination start
+    source_offset: u64,
+}
+
+impl Compare<'_> {
+    /// Differing regions of two buffers of the same length, compared byte for byte
+    fn dirty_ranges(self, buffer_src: &[u8], buffer_dst: &[u8]) -> Vec<(usize, usize)> {
+        match self.parallel{
+            Some(pool) => pool.install(|| compare_buffer_parallel(buffer_src, buffer_dst, self.chunk_size)),
+            None => find_dirty_ranges(buffer_src, buffer_dst, self.chunk_size),
+        }
+    }
+}
+
+/// A destination being synced
+struct Destination<'a> {
+    path: &'a Path,
+    writer: RegionWriter<'a>,
+    /// First error of this destination, it is skipped after that
+    error: Option<SyncError>,
+}
+
+impl Destination<'_> {
+    /// Compare the source buffer at file offset fp with this destination and write the differing regions.
+    /// In byte mode, dst_read is the result of reading buffer_dst from the destination.
+    fn sync_buffer(&mut self, buffer_src: &[u8], buffer_dst: &mut [u8], dst_read: io::Result<usize>, fp: usize, compare: Compare) -> Result<(), SyncError> {
+        let src_len = buffer_src.len();
+        let dirty_ranges = match compare.hash_mode{
+            _ if compare.seed_zeros => {
+                let dirty_ranges = find_nonzero_ranges(buffer_src, compare.chunk_size);
+                if compare.count_differing{
+                    self.writer.bytes_differing += dirty_ranges.iter().map(|&(start, len)| buffer_src[start .. start + len].iter().filter(|&&byte| byte != 0).count() as u64).sum::<u64>();
+                }
+                dirty_ranges
+            },
+            HashMode::Bytes => {
+                let mut dst_len = match dst_read{
+                    Ok(dst_len) => dst_len,
+                    Err(err) => return Err(SyncError::DestReadFailed { offset: fp as u64, source: err }),
+                };
+                if compare.read_only{
+                    dst_len = dry_run_dst_len(buffer_dst, src_len, dst_len);
+                }
+                if src_len != dst_len{
+                    return Err(SyncError::ReadMismatch { offset: fp as u64, src_len, dst_len });
+                }
+                let dirty_ranges = compare.dirty_ranges(buffer_src, &buffer_dst[0 .. src_len]);
+                if compare.count_differing{
+                    self.writer.bytes_differing += differing_bytes(buffer_src, buffer_dst, &dirty_ranges);
+                }
+                dirty_ranges
+            },
+            HashMode::Sha256 => {
+                let bytes_differing = compare.count_differing.then_some(&mut self.writer.bytes_differing);
+                find_dirty_ranges_sha256(buffer_src, self.writer.dst_file, fp as u64, buffer_dst, compare.read_only, compare.retries, bytes_differing)?
+            },
+        };
+        self.writer.write_ranges(buffer_src, &dirty_ranges, fp, compare.chunk_size)
+    }
+
+    /// Same as sync_buffer in byte mode, with the destination mapped in memory
+    fn sync_mapped(&mut self, data_src: &[u8], dst_map: &Mmap, fp: usize, compare: Compare) -> Result<(), SyncError> {
+        let src_len = data_src.len();
+        let data_dst = &dst_map[fp.min(dst_map.len()) .. (fp + src_len).min(dst_map.len())];
+        let padded;
+        let data_dst = if data_dst.len() == src_len{
+            data_dst
+        }else if compare.read_only{
+            // Destination is not truncated, compare as if padded with zeros
+            padded = [data_dst, &vec![0; src_len - data_dst.len()]].concat();
+            &padded
+        }else{
+            return Err(SyncError::ReadMismatch { offset: fp as u64, src_len, dst_len: data_dst.len() });
+        };
+        let dirty_ranges = compare.dirty_ranges(data_src, data_dst);
+        if compare.count_differing{
+            self.writer.bytes_differing += differing_bytes(data_src, data_dst, &dirty_ranges);
+        }
+        self.writer.write_ranges(data_src, &dirty_ranges, fp, compare.chunk_size)
+    }
+}
+
+/// Map len bytes of file read only, len is given as block devices have no file length
+fn map_file(file: &File, len: u64) -> io::Result<Mmap> {
+    unsafe { MmapOptions::new().len(len as usize).map(file) }
+}
+
+/// Create a new file of size bytes by writing a single zero byte at its end, the rest is a hole on filesystems
+/// with sparse files, so no space is allocated until data is written.
+pub fn create_sparse_file(path: &Path, size: u64) -> io::Result<File> {
+    let file = OpenOptions::new().create_new(true).read(true).write(true).open(path)?;
+    if size > 0{
+        file.write_all_at(&[0], size - 1)?;
+    }
+    Ok(file)
+}
+
+/// Open destination and truncate it to the source size if it is a regular file.
+/// Fail instead of resizing an existing regular file if truncate is false.
+/// A new destination is created as a sparse file with create_sparse.
+pub(crate) fn open_destination(dst_path: &Path, src_size: u64, dry_run: bool, read_only: bool, direct: bool, truncate: bool, create_sparse: bool) -> Result<File, SyncError> {
+    if create_sparse && !read_only && !dst_path.exists(){
+        log::info!("Create sparse {:?} of {} bytes", dst_path, src_size);
+        if let Err(err) = create_sparse_file(dst_path, src_size){
+            return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err));
+        }
+    }
+    let dst_size = filesize(dst_path)?;
+    let existed = dst_path.exists();
+    let resize = dst_size != src_size && !is_block_device(dst_path);
+    if resize && existed && !truncate && (dry_run || !read_only){
+        return Err(SyncError::TruncateRefused { path: dst_path.to_path_buf(), dst_size, src_size });
+    }
+    let dst_file = match open_direct(OpenOptions::new().create(!read_only).truncate(false).read(true).write(!read_only), dst_path, direct){
+        Ok(dst_file) => dst_file,
+        Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+    };
+
+    if resize && read_only{
+        if dry_run{
+            log::info!("DRY RUN: would truncate {:?} from {} to {} bytes", dst_path, dst_size, src_size);
+        }
+    } else if resize{
+        if existed{
+            log::warn!("Warning: truncating {:?} from {} to {} bytes, the source size", dst_path, dst_size, src_size);
+        }else{
+            log::info!("Truncate {:?} from {} to {} bytes", dst_path, dst_size, src_size);
+        }
+        if let Err(err) = platform::truncate_file(dst_file.as_raw_fd(), src_size){
+            return Err(SyncError::TruncateFailed(dst_path.to_path_buf(), err));
+        }
+    } else if is_block_device(dst_path) && dst_size < src_size{
+        return Err(SyncError::DestTooSmall { src_size, dst_size });
+    }
+    Ok(dst_file)
+}
+
+/// Flush a destination and check its written regions if verify is enabled, return the written regions.
+/// Return the error the destination got during the sync if any.
+fn finish_destination(src_path: &Path, dest: Destination, dst_file: &File, verify: bool, source_offset: u64, swap_endian: Option<usize>, progress: Option<VerifyProgress>) -> Result<Vec<(u64, usize)>, SyncError> {
+    if let Some(err) = dest.error{
+        return Err(err);
+    }
+    let written_ranges = dest.writer.finish()?;
+    if verify{
+        dst_file.sync_data()?;
+        log::info!("Verifying {} written regions of {}...", written_ranges.len(), dest.path.display());
+        let mismatches = verify_written(src_path, dest.path, &written_ranges, source_offset, swap_endian, progress)?;
+        if !mismatches.is_empty(){
+            return Err(SyncError::VerifyFailed { mismatches, regions: written_ranges.len() });
+        }
+    }
+    Ok(written_ranges)
+}
+
+/// Sort regions (offset, len) and merge the adjacent ones, regions are split at buffer boundaries during the sync
+/// and written out of order with priority regions
+fn merge_ranges(mut ranges: Vec<(u64, usize)>) -> Vec<(u64, usize)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, usize)> = Vec::with_capacity(ranges.len());
+    for (offset, len) in ranges{
+        match merged.last_mut(){
+            Some((last_offset, last_len)) if *last_offset + *last_len as u64 == offset => *last_len += len,
+            _ => merged.push((offset, len)),
+        }
+    }
+    merged
+}
+
+/// Sync the region of len bytes at fp to the given destinations chunk by chunk with positioned reads,
+/// recording I/O errors in error_log instead of failing. Used with ignore_errors when a buffer could not be synced at once.
+/// Return the bytes of source processed, less than len at end of source.
+fn salvage_region(src_file: &File, destinations: &mut [Destination], indexes: &[usize], fp: u64, len: usize, compare: Compare, error_log: &mut ErrorLog) -> usize {
+    let mut chunk_src = vec![0u8; compare.chunk_size];
+    let mut chunk_dst = vec![0u8; compare.chunk_size];
+    let mut done = 0;
+    while done < len{
+        let offset = fp + done as u64;
+        let chunk_len = compare.chunk_size.min(len - done);
+        let src_len = match read_full_at(src_file, &mut chunk_src[0 .. chunk_len], compare.source_offset + offset){
+            Ok(0) => break,
+            Ok(src_len) => src_len,
+            Err(err) => {
+                error_log.record(offset, format!("failed to read source: {}", err));
+                done += chunk_len;
+                continue;
+            },
+        };
+        let data_src = &chunk_src[0 .. src_len];
+        for &i in indexes{
+            let dest = &mut destinations[i];
+            let differ = match read_full_at(dest.writer.dst_file, &mut chunk_dst[0 .. src_len], offset){
+                Ok(dst_len) => dst_len != src_len || chunk_dst[0 .. src_len] != *data_src,
+                Err(err) => {
+                    // Written anyway, the drive may remap the bad sector
+                    error_log.record(offset, format!("failed to read {}: {}", dest.path.display(), err));
+                    true
+                },
+            };
+            if !differ{
+                dest.writer.chunks_identical += 1;
+                continue;
+            }
+            dest.writer.chunks_different += 1;
+            if let Err(err) = dest.writer.write(data_src, offset){
+                error_log.record(offset, format!("{}: {}", dest.path.display(), err));
+            }
+        }
+        done += src_len;
+        if src_len < chunk_len{
+            break; // End of source
+        }
+    }
+    done
+}
+
+/// Sync src_path to dst_path, writing only the chunks that differ
+pub fn sync_paths(src_path: &Path, dst_path: &Path, opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    sync_paths_multi(src_path, &[dst_path.to_path_buf()], opts)
+}
+
+/// Compare src_path to dst_path without writing anything, return the differing regions (offset, len) in order.
+/// It is a dry run: a destination shorter than the source is compared as if padded with zeros,
+/// the part of a longer destination beyond the source size is ignored.
+pub fn diff_paths(src_path: &Path, dst_path: &Path, opts: SyncOptions) -> Result<Vec<(u64, usize)>, SyncError> {
+    let stats = sync_paths(src_path, dst_path, opts.dry_run(true).output_delta(None))?;
+    Ok(stats.destinations.into_iter().next().map(|dest| dest.written_ranges).unwrap_or_default())
+}
+
+/// Compare src_path to dst_path without writing anything, stopping at the first difference.
+/// Return the offset of the first differing byte, or of the end of the shorter one for regular files of different
+/// sizes, None if they are identical. A partial comparison stopped by the stop flag returns None too, check it.
+pub fn compare_paths(src_path: &Path, dst_path: &Path, opts: SyncOptions) -> Result<Option<u64>, SyncError> {
+    let whole = opts.offset == 0 && opts.length.is_none();
+    let swap_endian = opts.swap_endian;
+    let source_offset = opts.source_offset;
+    let opts = SyncOptions { stop_at_difference: true, ..opts.dry_run(true).output_delta(None) };
+    let stats = sync_paths(src_path, dst_path, opts)?;
+    if let Some(&(offset, len)) = stats.destinations.first().and_then(|dest| dest.written_ranges.first()){
+        // Regions are whole chunks, find the first differing byte in the first one
+        let src_file = File::open(src_path).map_err(|err| SyncError::SourceOpenFailed(src_path.to_path_buf(), err))?;
+        let dst_file = File::open(dst_path).map_err(|err| SyncError::DestOpenFailed(dst_path.to_path_buf(), err))?;
+        let mut buffer_src = vec![0u8; len];
+        let mut buffer_dst = vec![0u8; len];
+        let src_len = read_full_at(&src_file, &mut buffer_src, source_offset + offset).map_err(|err| SyncError::SourceReadFailed { offset: source_offset + offset, source: err })?;
+        let dst_len = read_full_at(&dst_file, &mut buffer_dst, offset).map_err(|err| SyncError::DestReadFailed { offset, source: err })?;
+        if let Some(word_size) = swap_endian{
+            swap_endian_inplace(&mut buffer_src[0 .. src_len], word_size);
+        }
+        let len = src_len.min(dst_len);
+        let first = (0 .. len).find(|&i| buffer_src[i] != buffer_dst[i]).unwrap_or(len);
+        return Ok(Some(offset + first as u64));
+    }
+    if stats.interrupted || !whole{
+        return Ok(None);
+    }
+    // Dry run compares a shorter regular file destination as if padded with zeros
+    match (fs::metadata(src_path), fs::metadata(dst_path)){
+        (Ok(src), Ok(dst)) if src.is_file() && dst.is_file() && src.len() - source_offset != dst.len() => Ok(Some((src.len() - source_offset).min(dst.len()))),
+        _ => Ok(None),
+    }
+}
+
+/// Sync src_path to several destinations at once, the source is read only once.
+/// With more than one destination, a destination failing during the sync is skipped and its error
+/// is reported in its `DestinationStats`, the others are still synced.
+pub fn sync_paths_multi(src_path: &Path, dst_paths: &[PathBuf], opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    if opts.atomic{
+        return atomic::sync_atomic(src_path, dst_paths, opts);
+    }
+    if opts.rollback_on_error{
+        return journal::sync_with_rollback(src_path, dst_paths, opts);
+    }
+    if let Some(segment_size) = opts.segment_size{
+        return segment::sync_segmented(src_path, dst_paths, opts, segment_size);
+    }
+    // Options to copy the seed base image, without the sync range and outputs
+    let seed_opts = SyncOptions {
+        verify: false,
+        checkpoint_path: None,
+        checksum_file: None,
+        block_map: None,
+        progress_file: None,
+        output_delta: None,
+        write_journal: None,
+        offset: 0,
+        length: None,
+        source_offset: 0,
+        exclude_ranges: Vec::new(),
+        priority_regions: Vec::new(),
+        seed_from: None,
+        swap_endian: None,
+        simulate_corruption: None,
+        metadata: false,
+        quiet: true,
+        ..opts.clone()
+    };
+    let SyncOptions { threaded, parallel_compare, compare_threads, buffer_size, buffer_count, chunk_size, align, quiet, no_progress, progress_bar, color, progress_file, progress_interval, eta_algorithm, io_engine, json, hash_mode, flush_mode, block_size_auto, sparse, trim, direct, read_ahead, mmap, compression, encryption, atomic: _, dry_run, output_delta, delta_compression, write_journal, rollback_on_error: _, checksum_file, checksum_algorithm, block_map, rate_limit, max_bytes_written, max_diff_ratio, diff_filter, iops_limit, verify, write_verify_each, write_barrier, use_sendfile, count_differing_bytes, ignore_errors, retries, seed_zeros, truncate_dest, create_sparse_dest, metadata, swap_endian, simulate_corruption, zero_unwritten, checkpoint_path, checkpoint_interval, segment_size: _, stop_flag, journal_created, buffer_pool, stop_at_difference, events, offset, length, source_offset, exclude_ranges, priority_regions, seed_from } = opts;
+    let checkpoint_path = checkpoint_path.as_deref();
+    let multi = dst_paths.len() > 1;
+    if dst_paths.is_empty(){
+        return Err(SyncError::InvalidOptions("at least one destination is needed".to_string()));
+    }
+    if offset % chunk_size as u64 != 0{
+        return Err(SyncError::InvalidOptions(format!("offset {} is not a multiple of the chunk size {}", offset, chunk_size)));
+    }
+    if let Some(word_size) = swap_endian{
+        if ![2, 4, 8].contains(&word_size){
+            return Err(SyncError::InvalidOptions(format!("cannot swap the endianness of {} byte words, only 2, 4 or 8", word_size)));
+        }
+        if mmap || ignore_errors{
+            return Err(SyncError::InvalidOptions("the endianness cannot be swapped with mmap or ignore_errors".to_string()));
+        }
+        let unaligned = exclude_ranges.iter().any(|range| range.start % word_size as u64 != 0 || range.end % word_size as u64 != 0);
+        if offset % word_size as u64 != 0 || unaligned{
+            return Err(SyncError::InvalidOptions(format!("offset and excluded ranges must be multiples of the {} byte words to swap", word_size)));
+        }
+    }
+    if let Some((rate, _)) = simulate_corruption{
+        if !(0. ..= 1.).contains(&rate){
+            return Err(SyncError::InvalidOptions(format!("the corruption rate {} is not between 0 and 1", rate)));
+        }
+        if mmap{
+            return Err(SyncError::InvalidOptions("corruption cannot be simulated with mmap".to_string()));
+        }
+    }
+    if use_sendfile && (swap_endian.is_some() || simulate_corruption.is_some() || write_verify_each){
+        return Err(SyncError::InvalidOptions("sendfile cannot be used to swap the endianness, simulate corruption or verify each write".to_string()));
+    }
+    let mut corrupter = simulate_corruption.map(|(rate, seed)| Corrupter::new(rate, seed));
+    if !priority_regions.is_empty() && (checkpoint_path.is_some() || buffer_count > 1){
+        return Err(SyncError::InvalidOptions("priority regions cannot be used with a checkpoint or a read pipeline".to_string()));
+    }
+    if max_diff_ratio.is_some_and(|ratio| !(0. ..= 1.).contains(&ratio)){
+        return Err(SyncError::InvalidOptions(format!("the difference ratio {} is not between 0 and 1", max_diff_ratio.unwrap_or_default())));
+    }
+    if io_engine != IoEngine::Sync && (threaded || buffer_count > 1 || mmap){
+        return Err(SyncError::InvalidOptions(format!("the {} I/O engine cannot be used with threaded, buffer count or mmap", io_engine)));
+    }
+    if align == 0{
+        return Err(SyncError::InvalidOptions("the write alignment cannot be 0".to_string()));
+    }
+    if multi && output_delta.is_some(){
+        return Err(SyncError::InvalidOptions("a delta file can only be written for one destination".to_string()));
+    }
+    if write_journal.is_some(){
+        if multi{
+            return Err(SyncError::InvalidOptions("a journal can only be written for one destination".to_string()));
+        }
+        if dry_run || output_delta.is_some(){
+            return Err(SyncError::InvalidOptions("a journal cannot be written in dry run or with a delta file".to_string()));
+        }
+        if direct || zero_unwritten || seed_from.is_some() || checkpoint_path.is_some(){
+            return Err(SyncError::InvalidOptions("a journal cannot be written with direct I/O, zero unwritten, seed from or a checkpoint".to_string()));
+        }
+    }
+    if buffer_count > 1 && ignore_errors{
+        return Err(SyncError::InvalidOptions("a read pipeline cannot be used to ignore errors".to_string()));
+    }
+    if compression.is_some(){
+        return Err(SyncError::InvalidOptions("compression needs a remote destination".to_string()));
+    }
+    if encryption.is_some(){
+        return Err(SyncError::InvalidOptions("encryption needs a remote destination".to_string()));
+    }
+    let mut stats = SyncStats { dry_run, ..Default::default() };
+    // JSON mode prints only JSON objects to stdout, no interactive progress
+    let quiet = quiet || json;
+    let dst_names = dst_paths.iter().map(|dst_path| format!("{:?}", dst_path)).collect::<Vec<String>>().join(", ");
+    log::info!("Synching {:?} to {}", src_path, dst_names);
+    let mut src_file = match open_direct(OpenOptions::new().read(true), src_path, direct){
+        Ok(src_file) => src_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    if mmap && !src_file.metadata()?.file_type().is_file() && !is_block_device(src_path){
+        return Err(SyncError::InvalidOptions(format!("{} cannot be mapped, mmap needs a regular file or a block device", src_path.display())));
+    }
+    let src_file_size = filesize(src_path)?;
+    // The source seen by the sync starts at source_offset, offsets and sizes are destination ones from here
+    let src_size = if source_offset > 0{
+        if source_offset >= src_file_size{
+            return Err(SyncError::InvalidOptions(format!("source offset {} is beyond source size {}", source_offset, src_file_size)));
+        }
+        let src_size = src_file_size - source_offset;
+        match length{
+            Some(length) if offset.saturating_add(length) > src_size => return Err(SyncError::InvalidOptions(format!("source offset {} + offset {} + length {} is beyond source size {}", source_offset, offset, length, src_file_size))),
+            Some(length) => offset + length,
+            None => src_size,
+        }
+    }else{
+        src_file_size
+    };
+    log::info!("Sizes:");
+    if source_offset > 0{
+        log::info!("{}: {} [{:.1} MB] from offset {}", src_path.display(), src_size, src_size as f64 / 1024. / 1024., source_offset);
+    }else{
+        log::info!("{}: {} [{:.1} MB]", src_path.display(), src_size, src_size as f64 / 1024. / 1024.);
+    }
+    for dst_path in dst_paths{
+        let dst_size = filesize(dst_path)?;
+        log::info!("{}: {} [{:.1} MB]", dst_path.display(), dst_size, dst_size as f64 / 1024. / 1024.);
+    }
+
+    if src_size == 0{
+        log::warn!("Source file is empty ! Nothing to do !");
+        return Ok(stats);
+    }
+    // End of the synced range
+    let mut end = match length{
+        Some(length) => offset.saturating_add(length),
+        None => src_size,
+    };
+    if end > src_size{
+        log::warn!("Warning: offset + length ({}) is beyond source size ({}), stopping at end of source.", end, src_size);
+        end = src_size;
+    }
+    if offset >= end{
+        return Err(SyncError::InvalidOptions(format!("offset {} is beyond source size {}", offset, src_size)));
+    }
+
+    // Destination is only read in dry run or when writing a delta file
+    let read_only = dry_run || output_delta.is_some();
+    if let Some(seed_path) = &seed_from{
+        let seed_size = filesize(seed_path)?;
+        for dst_path in dst_paths{
+            if filesize(dst_path)? == seed_size{
+                continue; // Already seeded or synced
+            }
+            if read_only{
+                log::info!("DRY RUN: would seed {:?} from {:?}", dst_path, seed_path);
+                continue;
+            }
+            log::info!("Seeding {:?} from {:?}", dst_path, seed_path);
+            if is_block_device(dst_path){
+                stats.bytes_seeded += sync_paths(seed_path, dst_path, seed_opts.clone())?.bytes_written;
+            }else{
+                // Can be a reflink or an in kernel copy, faster than read and write
+                match fs::copy(seed_path, dst_path){
+                    Ok(len) => stats.bytes_seeded += len,
+                    Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+                }
+            }
+        }
+    }
+    // Size to restore on undo, before the destination is truncated or extended
+    let mut journal = match &write_journal{
+        Some(journal_path) => Some((journal_path.as_path(), journal::create_journal(journal_path, filesize(&dst_paths[0])?)?)),
+        None => None,
+    };
+    if let (Some(_), Some(journal_created)) = (&journal, &journal_created){
+        journal_created.store(true, Ordering::Relaxed);
+    }
+    // Sizes before the destinations are extended, the regions beyond are new
+    let dst_sizes = dst_paths.iter().map(|dst_path| filesize(dst_path)).collect::<Result<Vec<_>, _>>()?;
+    let mut dst_files = Vec::new();
+    for dst_path in dst_paths{
+        dst_files.push(open_destination(dst_path, src_size, dry_run, read_only, direct, truncate_dest, create_sparse_dest)?);
+    }
+    let mut chunk_size = chunk_size;
+    if block_size_auto{
+        let physical_block_size = dst_files.iter().filter_map(|dst_file| get_physical_block_size(dst_file.as_raw_fd())).max();
+        if let Some(physical_block_size) = physical_block_size{
+            chunk_size = physical_block_size as usize;
+        }
+    }
+    let mut buffer_size = buffer_size;
+    if direct{
+        // O_DIRECT needs sizes and offsets aligned to the logical block size
+        let align = std::iter::once(&src_file).chain(&dst_files)
+            .filter_map(|file| get_logical_block_size(file.as_raw_fd()))
+            .fold(DIRECT_ALIGN, |align, lbs| align.max(lbs as usize));
+        buffer_size = buffer_size.div_ceil(align) * align;
+        chunk_size = chunk_size.div_ceil(align) * align;
+    }
+    if offset % chunk_size as u64 != 0{
+        return Err(SyncError::InvalidOptions(format!("offset {} is not a multiple of the chunk size {}", offset, chunk_size)));
+    }
+
+    if hash_mode == HashMode::Sha256{
+        log::info!("Buffer size: 1x {} [{:.1} MB] - SHA-256 mode", buffer_size, buffer_size as f64 / 1024. / 1024.);
+    }else{
+        log::info!("Buffer size: 2x {} [{:.1} MB]", buffer_size, buffer_size as f64 / 1024. / 1024.);
+    }
+    log::info!("Chunk size: {} [{:.1} KB]", chunk_size, chunk_size as f64 / 1024.);
+    if seed_zeros{
+        log::warn!("Warning: destinations are not read and assumed to be zeroed, any data left in them where the source is zero stays as is.");
+    }
+
+    let mut buffer_src = acquire_buffer(buffer_pool.as_ref(), buffer_size);
+    // In SHA-256 mode, only one destination chunk is in RAM at a time
+    let mut buffer_dst = match hash_mode{
+        HashMode::Bytes => acquire_buffer(buffer_pool.as_ref(), buffer_size),
+        HashMode::Sha256 => acquire_buffer(buffer_pool.as_ref(), chunk_size),
+    };
+    let mut direct = direct;
+    if !source_offset.is_multiple_of(DIRECT_ALIGN as u64){
+        log::warn!("Warning: source offset {} is not a multiple of the {} byte block size, reads are slower.", source_offset, DIRECT_ALIGN);
+        if direct{
+            // Direct I/O needs aligned source positions
+            for file in std::iter::once(&src_file).chain(&dst_files){
+                clear_direct(file)?;
+            }
+            direct = false;
+        }
+    }
+    let src_map = if mmap{
+        match map_file(&src_file, src_file_size){
+            Ok(src_map) => Some(src_map),
+            Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+        }
+    }else{
+        None
+    };
+    let mut dst_maps = Vec::new();
+    for (dst_path, dst_file) in dst_paths.iter().zip(&dst_files){
+        if mmap && hash_mode == HashMode::Bytes && !seed_zeros{
+            match map_file(dst_file, filesize(dst_path)?){
+                Ok(dst_map) => dst_maps.push(Some(dst_map)),
+                Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+            }
+        }else{
+            dst_maps.push(None);
+        }
+    }
+    // Synced regions in order, the whole range without priority regions
+    let mut schedule = if priority_regions.is_empty(){
+        vec![(offset, end)]
+    }else{
+        let aligned: Vec<(u64, u64)> = priority_regions.iter().map(|&(start, end)| {
+            let (start, end) = align_range(start as usize, end as usize, chunk_size);
+            (start as u64, end as u64)
+        }).collect();
+        build_offset_schedule(end, &aligned).into_iter()
+            .filter_map(|(start, end)| (start.max(offset) < end).then_some((start.max(offset), end)))
+            .collect()
+    }.into_iter();
+    let mut region = schedule.next().unwrap_or((offset, end));
+    // Bytes of the regions before the current one, for the progress
+    let mut regions_done = 0;
+    let mut fp = region.0 as usize;
+    let mut time2display = Instant::now();
+    let mut time2progress_file = Instant::now();
+    let verify_progress = (!quiet && !no_progress && !json).then_some(VerifyProgress { progress_bar, color, interval: progress_interval });
+    let progress_bar = (progress_bar && !quiet && !no_progress).then(|| new_progress_bar(end - offset, progress_interval, color));
+    let mut time2checkpoint = Instant::now();
+    let src_abs = fs::canonicalize(src_path)?.to_string_lossy().into_owned();
+    let mut dst_abs = Vec::new();
+    for dst_path in dst_paths{
+        dst_abs.push(fs::canonicalize(dst_path)?.to_string_lossy().into_owned());
+    }
+    let dst_abs = dst_abs.join(", ");
+
+    if let Some(checkpoint_path) = checkpoint_path{
+        let checkpoint_offset = read_checkpoint(checkpoint_path, &src_abs, &dst_abs)? as usize;
+        if checkpoint_offset > fp{
+            fp = checkpoint_offset;
+            log::info!("Resuming from offset {} [{:.1} MB]", fp, fp as f64 / 1024. / 1024.);
+        }
+    }
+    if fp > 0 || source_offset > 0{
+        src_file.seek(SeekFrom::Start(source_offset + fp as u64))?;
+        for mut dst_file in &dst_files{
+            dst_file.seek(SeekFrom::Start(fp as u64))?;
+        }
+    }
+    let mut delta = match &output_delta{
+        Some(delta_path) => Some((delta_path.as_path(), delta::create_delta(delta_path, src_path, src_size, delta_compression)?)),
+        None => None,
+    };
+    let checksum_error = |path: &Path, err| SyncError::ChecksumFailed(path.to_path_buf(), err);
+    let mut checksums = match &checksum_file{
+        Some(checksum_path) => Some((checksum_path.as_path(), ChecksumWriter::create(checksum_path, checksum_algorithm).map_err(|err| checksum_error(checksum_path, err))?)),
+        None => None,
+    };
+    // Backends of the source and of each destination, none with the sync engine
+    let (src_backend, dst_backends) = if io_engine != IoEngine::Sync{
+        log::info!("I/O engine: {}", io_engine);
+        let io_error = |err| SyncError::InvalidOptions(format!("cannot start the {} I/O engine: {}", io_engine, err));
+        let dst_backends = dst_files.iter().map(|dst_file| io_engine.open(dst_file).map(Some)).collect::<io::Result<Vec<_>>>().map_err(io_error)?;
+        (Some(io_engine.open(&src_file).map_err(io_error)?), dst_backends)
+    }else{
+        (None, dst_files.iter().map(|_| None).collect())
+    };
+    // Another handle, the source is read with its file position meanwhile and sendfile does not move it
+    let sendfile_src = if use_sendfile {Some(src_file.try_clone()?)} else {None};
+    let mut destinations: Vec<Destination> = dst_paths.iter().zip(&dst_files).zip(dst_backends).zip(dst_sizes).map(|(((dst_path, dst_file), backend), dst_size)| Destination {
+        path: dst_path,
+        writer: RegionWriter {
+            dst_file,
+            backend,
+            dry_run,
+            retries,
+            align,
+            diff_filter,
+            verify_each: write_verify_each,
+            barrier: write_barrier && is_block_device(dst_path),
+            sendfile: sendfile_src.as_ref().filter(|_| dst_file.metadata().is_ok_and(|metadata| metadata.is_file())).map(|src_file| SendfileSource { src_file, source_offset, dst_size }),
+            delta: delta.take(),
+            journal: journal.take(),
+            rate_limiter: rate_limit.map(RateLimiter::new),
+            iops_limiter: iops_limit.map(IopsLimiter::new),
+            max_bytes_written,
+            sparse: sparse || (trim && is_block_device(dst_path)),
+            trim: trim && is_block_device(dst_path),
+            bytes_written: 0,
+            bytes_differing: 0,
+            write_elapsed: Duration::ZERO,
+            chunks_identical: 0,
+            chunks_different: 0,
+            write_sizes: BTreeMap::new(),
+            written_ranges: Vec::new(),
+        },
+        error: None,
+    }).collect();
+    // Work stealing pool of the parallel compare, separate from the global one used by the other parallel tasks
+    let compare_pool = if parallel_compare && hash_mode == HashMode::Bytes{
+        match rayon::ThreadPoolBuilder::new().num_threads(compare_threads).build(){
+            Ok(pool) => {
+                log::info!("Parallel compare: {} threads", pool.current_num_threads());
+                Some(pool)
+            },
+            Err(err) => return Err(SyncError::InvalidOptions(format!("cannot start the compare threads: {}", err))),
+        }
+    }else{
+        None
+    };
+    let compare = Compare { hash_mode, chunk_size, read_only, parallel: compare_pool.as_ref(), seed_zeros, retries, count_differing: count_differing_bytes, source_offset };
+    let start_time = Instant::now();
+    let mut speed_estimator = (eta_algorithm == EtaAlgorithm::Exponential).then(SpeedEstimator::new);
+    // Entries of the error log already sent as events
+    let mut errors_sent = 0;
+
+    if threaded{
+        log::info!("Threaded - Reading source and destination at the same time.");
+    }
+    // Destinations are not read with seed zeros
+    let read_dst_files = if seed_zeros {&dst_files[0 .. 0]} else {&dst_files[..]};
+    if let Some(read_ahead) = read_ahead{
+        log::info!("Read-ahead: {} [{:.1} MB]", read_ahead, read_ahead as f64 / 1024. / 1024.);
+        for file in std::iter::once(&src_file).chain(read_dst_files){
+            if let Err(err) = advise_sequential(file.as_raw_fd()){
+                log::warn!("Warning: failed to advise sequential reads: {}", err);
+            }
+        }
+    }
+    // The first destination is read ahead with the source, the others after like without a pipeline
+    let mut pipeline = if buffer_count > 1 && src_map.is_none(){
+        log::info!("Pipeline - Reading {} buffers ahead while comparing and writing.", buffer_count - 1);
+        let plan = ReadPlan {
+            fp,
+            end,
+            buffer_size,
+            read_dst: hash_mode == HashMode::Bytes && !seed_zeros,
+            exclude_ranges: exclude_ranges.clone(),
+            direct,
+            threaded,
+            retries,
+            source_offset,
+        };
+        Some(ReadPipeline::start(&src_file, &dst_files[0], &buffer_src, &buffer_dst, buffer_count, buffer_pool.as_ref(), plan)?)
+    }else{
+        None
+    };
+    loop{
+        let active: Vec<usize> = (0 .. destinations.len()).filter(|&i| destinations[i].error.is_none()).collect();
+        if active.is_empty(){
+            break; // Every destination failed
+        }
+        if fp as u64 >= region.1{
+            // Next region of the priority schedule
+            let Some(next) = schedule.next() else {
+                break;
+            };
+            regions_done += region.1 - region.0;
+            region = next;
+            fp = region.0 as usize;
+            src_file.seek(SeekFrom::Start(source_offset + fp as u64))?;
+            for mut dst_file in &dst_files{
+                dst_file.seek(SeekFrom::Start(fp as u64))?;
+            }
+            continue;
+        }
+        if let Some(range) = exclude::range_at(fp as u64, &exclude_ranges){
+            // Jump over the excluded range
+            let skip_to = range.end.min(region.1);
+            stats.bytes_skipped += skip_to - fp as u64;
+            fp = skip_to as usize;
+            src_file.seek(SeekFrom::Start(source_offset + fp as u64))?;
+            for mut dst_file in &dst_files{
+                dst_file.seek(SeekFrom::Start(fp as u64))?;
+            }
+            continue;
+        }
+        // Do not read beyond the synced range nor into the next excluded range
+        let read_end = exclude::next_start(fp as u64, &exclude_ranges).map_or(region.1, |start| start.min(region.1));
+        let read_size = buffer_size.min((read_end - fp as u64) as usize);
+        if read_size == 0{
+            break;
+        }
+        if let Some(read_ahead) = read_ahead{
+            // The kernel reads the next region while this buffer is compared and written
+            advise_read_ahead(std::iter::once(&src_file), source_offset + (fp + read_size) as u64, read_ahead);
+            advise_read_ahead(read_dst_files.iter(), (fp + read_size) as u64, read_ahead);
+        }
+        if direct && (!fp.is_multiple_of(DIRECT_ALIGN) || !read_size.is_multiple_of(DIRECT_ALIGN)){
+            // Unaligned tail or excluded range, finish with the page cache
+            for file in std::iter::once(&src_file).chain(&dst_files){
+                clear_direct(file)?;
+            }
+            direct = false;
+        }
+        // In SHA-256 mode, destination is read chunk by chunk later
+        let dst_read_size = if hash_mode == HashMode::Bytes && !mmap && !seed_zeros {read_size} else {0};
+        let (src_len, mut first_dst_read) = match &src_map{
+            Some(src_map) => (read_size.min(src_map.len().saturating_sub(source_offset as usize + fp)), None),
+            None => {
+                // Threaded mode reads the first destination at the same time as the source, the others after
+                let (src_read, first_dst_read) = match &mut pipeline{
+                    Some(pipeline) => {
+                        let (src_read, dst_read) = pipeline.next(fp, &mut buffer_src, &mut buffer_dst);
+                        (src_read, (0, dst_read))
+                    },
+                    None => {
+                        let (src_read, dst_read) = match (&src_backend, &destinations[active[0]].writer.backend){
+                            (Some(src_backend), Some(dst_backend)) => (
+                                retry_io(|| src_backend.read_at(&mut buffer_src[0 .. read_size], source_offset + fp as u64), retries),
+                                retry_io(|| dst_backend.read_at(&mut buffer_dst[0 .. dst_read_size], fp as u64), retries),
+                            ),
+                            _ => read_buffers(&src_file, destinations[active[0]].writer.dst_file, &mut buffer_src[0 .. read_size], &mut buffer_dst[0 .. dst_read_size], threaded, retries),
+                        };
+                        (src_read, (active[0], dst_read))
+                    },
+                };
+                match src_read{
+                    Ok(src_len) => (src_len, Some(first_dst_read)),
+                    Err(err) if ignore_errors => {
+                        log::warn!("Warning: failed to read source at offset {}: {}, syncing chunk by chunk.", fp, err);
+                        let len = salvage_region(&src_file, &mut destinations, &active, fp as u64, read_size, compare, &mut stats.error_log);
+                        if len == 0{
+                            break;
+                        }
+                        fp += len;
+                        stats.bytes_compared += len as u64;
+                        src_file.seek(SeekFrom::Start(source_offset + fp as u64))?;
+                        for mut dst_file in &dst_files{
+                            dst_file.seek(SeekFrom::Start(fp as u64))?;
+                        }
+                        continue;
+                    },
+                    Err(err) => return Err(SyncError::SourceReadFailed { offset: fp as u64, source: err }),
+                }
+            },
+        };
+        if src_len == 0{
+            break;
+        }
+        if let Some(corrupter) = &mut corrupter{
+            corrupter.corrupt(&mut buffer_src[0 .. src_len]);
+        }
+        if let Some(word_size) = swap_endian{
+            swap_endian_inplace(&mut buffer_src[0 .. src_len], word_size);
+        }
+        let data_src: &[u8] = match &src_map{
+            Some(src_map) => &src_map[source_offset as usize + fp .. source_offset as usize + fp + src_len],
+            None => &buffer_src[0 .. src_len],
+        };
+        if let Some((checksum_path, checksums)) = &mut checksums{
+            checksums.write_chunks(data_src, fp as u64, chunk_size).map_err(|err| checksum_error(checksum_path, err))?;
+        }
+        for &i in &active{
+            let dest = &mut destinations[i];
+            let result = match &dst_maps[i]{
+                Some(dst_map) => dest.sync_mapped(data_src, dst_map, fp, compare),
+                None => {
+                    let dst_read = match first_dst_read.take_if(|(first, _)| *first == i){
+                        Some((_, dst_read)) => dst_read,
+                        None => {
+                            let mut dst_file = dest.writer.dst_file;
+                            retry_io(|| dst_file.read(&mut buffer_dst[0 .. dst_read_size]), retries)
+                        },
+                    };
+                    dest.sync_buffer(data_src, &mut buffer_dst, dst_read, fp, compare)
+                },
+            };
+            if let Err(err) = result{
+                let io_error = matches!(err, SyncError::DestReadFailed { .. } | SyncError::WriteFailed { .. } | SyncError::ReadMismatch { .. });
+                if ignore_errors && io_error{
+                    log::warn!("Warning: {}: {}, syncing chunk by chunk.", dest.path.display(), err);
+                    salvage_region(&src_file, &mut destinations, &[i], fp as u64, src_len, compare, &mut stats.error_log);
+                    // Back to sequential reads after the buffer
+                    let mut dst_file = destinations[i].writer.dst_file;
+                    dst_file.seek(SeekFrom::Start((fp + src_len) as u64))?;
+                    continue;
+                }
+                if !multi{
+                    return Err(err);
+                }
+                if json{
+                    println!("{}", serde_json::json!({"type": "error", "destination": dest.path, "message": err.to_string()}));
+                }else if !quiet && !no_progress && progress_bar.is_none(){
+                    println!(); // To skip line after display_progress
+                }
+                // The bar is hidden while the message is printed so they do not overlap
+                if let Some(events) = &events{
+                    let _ = events.send(SyncEvent::Error { offset: fp as u64, message: format!("{}: {}", dest.path.display(), err) });
+                }
+                let log_error = || log::error!("{}: {}, skipping this destination.", dest.path.display(), err);
+                match &progress_bar{
+                    Some(progress_bar) => progress_bar.suspend(log_error),
+                    None => log_error(),
+                }
+                dest.error = Some(err);
+            }
+        }
+        fp += src_len;
+        stats.bytes_compared += src_len as u64;
+        if let Some(limit) = max_diff_ratio{
+            // Ratio of the destination with the most writes
+            let bytes_written = destinations.iter().map(|dest| dest.writer.bytes_written).max().unwrap_or_default();
+            let ratio = bytes_written as f64 / stats.bytes_compared.max(1) as f64;
+            if ratio > limit{
+                if !quiet && !no_progress && progress_bar.is_none(){
+                    println!(); // To skip line after display_progress
+                }
+                return Err(SyncError::DiffRatioExceeded { ratio, limit });
+            }
+        }
+        // Offset as if the regions were synced in order
+        let progress_fp = offset + regions_done + fp as u64 - region.0;
+        if let Some(events) = &events{
+            for (offset, message) in &stats.error_log.entries[errors_sent ..]{
+                let _ = events.send(SyncEvent::Error { offset: *offset, message: message.clone() });
+            }
+            errors_sent = stats.error_log.entries.len();
+            let _ = events.send(SyncEvent::Progress {
+                offset: progress_fp,
+                start: offset,
+                end,
+                bytes_compared: stats.bytes_compared,
+                bytes_written: destinations.iter().map(|dest| dest.writer.bytes_written).sum(),
+                chunks_different: destinations.iter().map(|dest| dest.writer.chunks_different).sum(),
+            });
+        }
+        if let Some(checkpoint_path) = checkpoint_path{
+            if time2checkpoint.elapsed().as_secs() >= checkpoint_interval{
+                for dst_file in &dst_files{
+                    dst_file.sync_data()?;
+                }
+                write_checkpoint(checkpoint_path, &src_abs, &dst_abs, fp as u64)?;
+                time2checkpoint = Instant::now();
+            }
+        }
+        if let Some(progress_path) = &progress_file{
+            if time2progress_file.elapsed() >= progress_interval{
+                let bytes_written = destinations.iter().map(|dest| dest.writer.bytes_written).sum();
+                if let Err(err) = write_progress_file(progress_path, progress_fp, offset, end, bytes_written, start_time){
+                    log::warn!("Warning: failed to write progress file {}: {}", progress_path.display(), err);
+                }
+                time2progress_file = Instant::now();
+            }
+        }
+        if let Some(progress_bar) = &progress_bar{
+            progress_bar.set_position(progress_fp - offset);
+        }else if !no_progress && time2display.elapsed() >= progress_interval{
+            let bytes_written = destinations.iter().map(|dest| dest.writer.bytes_written).sum();
+            if json{
+                json_progress(progress_fp, end, bytes_written, start_time);
+            }else if !quiet{
+                // Progress of the synced range
+                display_progress((progress_fp - offset) as f64, (end - offset) as f64, start_time, speed_estimator.as_mut(), stats.error_log.entries.len(), color);
+            }
+            time2display = Instant::now();
+        }
+        if stop_flag.as_ref().is_some_and(|stop_flag| stop_flag.load(Ordering::Relaxed)){
+            stats.interrupted = true;
+            break;
+        }
+        if stop_at_difference && destinations.iter().any(|dest| dest.writer.chunks_different > 0){
+            break;
+        }
+    }
+    if let Some(progress_bar) = progress_bar{
+        progress_bar.finish();
+    }else if !quiet && !no_progress{
+        println!(); // To skip line after display_progress
+    }
+    stats.elapsed = start_time.elapsed();
+    stats.end_offset = fp as u64;
+    if zero_unwritten && !stats.interrupted && end == src_size{
+        for dest in destinations.iter_mut().filter(|dest| dest.error.is_none() && is_block_device(dest.path)){
+            let dst_size = filesize(dest.path)?;
+            if dst_size <= src_size{
+                continue;
+            }
+            let len = dst_size - src_size;
+            if read_only{
+                log::info!("DRY RUN: would zero {} [{:.1} MB] of {:?} beyond the source size", len, len as f64 / 1024. / 1024., dest.path);
+                continue;
+            }
+            log::info!("Zeroing {} [{:.1} MB] of {:?} beyond the source size", len, len as f64 / 1024. / 1024., dest.path);
+            // The end of the source may not be aligned for O_DIRECT
+            let result = if direct {clear_direct(dest.writer.dst_file)} else {Ok(())};
+            if let Err(err) = result.and_then(|_| zero_range(dest.writer.dst_file, src_size, len, chunk_size)){
+                let err = SyncError::WriteFailed { offset: src_size, source: err };
+                if !multi{
+                    return Err(err);
+                }
+                log::error!("{}: {}, skipping this destination.", dest.path.display(), err);
+                dest.error = Some(err);
+            }
+        }
+    }
+    if flush_mode != FlushMode::None && !read_only{
+        let flush_start = Instant::now();
+        for dest in destinations.iter_mut().filter(|dest| dest.error.is_none()){
+            let fd = dest.writer.dst_file.as_raw_fd();
+            let result = match flush_mode{
+                FlushMode::None => Ok(()),
+                FlushMode::Fsync => fsync(fd),
+                FlushMode::Fdatasync => fdatasync(fd),
+            };
+            if let Err(err) = result{
+                let err = SyncError::FlushFailed(dest.path.to_path_buf(), err.into());
+                if !multi{
+                    return Err(err);
+                }
+                log::error!("{}, skipping this destination.", err);
+                dest.error = Some(err);
+            }
+        }
+        stats.flush_elapsed = flush_start.elapsed();
+    }
+    if let Some((checksum_path, checksums)) = checksums{
+        checksums.finish().map_err(|err| checksum_error(checksum_path, err))?;
+    }
+    if let Some(checkpoint_path) = checkpoint_path{
+        if stats.interrupted{
+            // Save where it stopped to resume from there
+            for dst_file in &dst_files{
+                dst_file.sync_data()?;
+            }
+            write_checkpoint(checkpoint_path, &src_abs, &dst_abs, fp as u64)?;
+        }else{
+            // Sync is complete, next run starts from the beginning
+            let _ = fs::remove_file(checkpoint_path);
+        }
+    }
+    for (dest, dst_file) in destinations.into_iter().zip(&dst_files){
+        let path = dest.path.to_path_buf();
+        let bytes_written = dest.writer.bytes_written;
+        let regions = dest.writer.written_ranges.len();
+        stats.write_elapsed += dest.writer.write_elapsed;
+        stats.chunks_identical += dest.writer.chunks_identical;
+        stats.chunks_different += dest.writer.chunks_different;
+        stats.bytes_would_write_minimal += dest.writer.bytes_differing;
+        for (&size, &count) in &dest.writer.write_sizes{
+            stats.write_calls += count;
+            *stats.write_sizes.entry(size).or_default() += count;
+        }
+        let verify_start = Instant::now();
+        let mut result = finish_destination(src_path, dest, dst_file, verify, source_offset, swap_endian, verify_progress);
+        if verify && result.is_ok(){
+            stats.verified_regions += regions;
+            stats.verify_elapsed += verify_start.elapsed();
+        }
+        if metadata && result.is_ok() && !stats.interrupted && dst_file.metadata()?.is_file(){
+            match sync_metadata(src_path, &path, dry_run){
+                Ok(changes) => {
+                    stats.metadata_differences += changes.differences;
+                    stats.metadata_applied += changes.applied;
+                },
+                Err(err) => result = Err(SyncError::MetadataFailed(path.clone(), err)),
+            }
+        }
+        let (written_ranges, error) = match result{
+            Ok(written_ranges) => (merge_ranges(written_ranges), None),
+            Err(err) if !multi => return Err(err),
+            Err(err) => (Vec::new(), Some(err.to_string())),
+        };
+        stats.bytes_written += bytes_written;
+        stats.destinations.push(DestinationStats { path, bytes_written, written_ranges, error });
+    }
+    if let Some((block_map_path, format)) = &block_map{
+        save_block_map(&stats.destinations, block_map_path, *format)?;
+    }
+    if let (Some(progress_path), false) = (&progress_file, stats.interrupted){
+        let _ = fs::remove_file(progress_path);
+    }
+    Ok(stats)
+}
+
+/// Save the sectors written to the destinations in a block map file
+pub(crate) fn save_block_map(destinations: &[DestinationStats], path: &Path, format: BlockMapFormat) -> Result<(), SyncError> {
+    let mut block_map = BlockMap::new();
+    for &(offset, len) in destinations.iter().flat_map(|dest| &dest.written_ranges){
+        block_map.insert_range(offset, len as u64);
+    }
+    match block_map.save(path, format){
+        Ok(()) => Ok(()),
+        Err(err) => Err(SyncError::BlockMapFailed(path.to_path_buf(), err)),
+    }
+}
@@ -0,0 +1,65 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Log target copying every log line to a log file, with a timestamp, for unattended syncs.
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Log target writing to inner, usually stderr, and appending each line to a log file prefixed with an RFC 3339 timestamp
+pub struct LogTee {
+    inner: Box<dyn Write + Send>,
+    file: File,
+    /// Next byte written to the file starts a line
+    line_start: bool,
+}
+
+impl LogTee {
+    pub fn new(inner: Box<dyn Write + Send>, file: File) -> LogTee {
+        LogTee { inner, file, line_start: true }
+    }
+
+    /// Open a log file in append mode, created if needed
+    pub fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Append buf to the log file, timestamping each line
+    fn write_file(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut file_buf = Vec::with_capacity(buf.len() + 32);
+        for line in buf.split_inclusive(|&byte| byte == b'\n'){
+            if self.line_start{
+                write!(file_buf, "{} ", humantime::format_rfc3339_seconds(SystemTime::now()))?;
+            }
+            file_buf.extend_from_slice(line);
+            self.line_start = line.ends_with(b"\n");
+        }
+        self.file.write_all(&file_buf)
+    }
+}
+
+impl Write for LogTee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full disk must not stop the sync nor hide its log
+        let _ = self.write_file(buf);
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _ = self.file.flush();
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! LVM snapshot of the source volume, so a live volume is synced in a consistent state.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Temporary LVM snapshot created with lvcreate, removed with lvremove when dropped
+#[derive(Debug)]
+pub struct LvmSnapshot {
+    vg: String,
+    name: String,
+    path: PathBuf,
+}
+
+/// Run an LVM command, return its stderr as error if it fails
+fn run_lvm(program: &str, args: &[&str]) -> io::Result<()> {
+    let output = Command::new(program).args(args).output().map_err(|err| io::Error::new(err.kind(), format!("failed to run {}: {}", program, err)))?;
+    if !output.status.success(){
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("{} failed: {}: {}", program, output.status, stderr.trim())));
+    }
+    Ok(())
+}
+
+impl LvmSnapshot {
+    /// Snapshot logical volume `lv` of volume group `vg` as `<lv>_localblocksync_<timestamp>`.
+    /// `size` is the copy-on-write space given to lvcreate --size, e.g. "1G". The snapshot becomes invalid if it fills up.
+    pub fn create(vg: &str, lv: &str, size: &str) -> io::Result<LvmSnapshot> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let name = format!("{}_localblocksync_{}", lv, timestamp);
+        let origin = format!("{}/{}", vg, lv);
+        run_lvm("lvcreate", &["--snapshot", "--size", size, "--name", &name, &origin])?;
+        log::info!("Created LVM snapshot {}/{} of {} [{}]", vg, name, origin, size);
+        Ok(LvmSnapshot {
+            vg: vg.to_string(),
+            path: Path::new("/dev").join(vg).join(&name),
+            name,
+        })
+    }
+
+    /// Device path of the snapshot, /dev/<vg>/<name>
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LvmSnapshot {
+    fn drop(&mut self){
+        let snapshot = format!("{}/{}", self.vg, self.name);
+        match run_lvm("lvremove", &["--force", &snapshot]){
+            Ok(()) => log::info!("Removed LVM snapshot {}", snapshot),
+            Err(err) => log::warn!("Warning: failed to remove LVM snapshot {}: {}", snapshot, err),
+        }
+    }
+}
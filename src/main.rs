@@ -12,39 +12,596 @@
  You should have received a copy of the GNU General Public License along with
  this program. If not, see <https://www.gnu.org/licenses/>.
 */
-use std::sync::{Arc, Mutex};
-use std::{fs,thread,process};
-use std::path::Path;
-use nix::ioctl_read;
-use block_utils;
-use std::os::unix::io::AsRawFd;
-use std::io::{prelude::*, stdout};
-use std::fs::{File, OpenOptions};
-use std::os::unix::fs::FileExt;
-use std::time::Instant;
-use nix::libc::ftruncate64;
-use clap::Parser;
+use std::fs;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use serde::{Deserialize, Serialize};
+use log::LevelFilter;
+use ratatui::crossterm::terminal;
+use localblocksync::tui::{self, LogPipe};
+use localblocksync::{apply_delta, benchmark_paths, chunk_states, chunks_per_char, compare_paths, copy_to_stream, diff_paths, is_block_device, is_mounted, read_exclude_ranges, read_priority_regions, serve, sync_paths_multi, sync_remote, sync_compressed, sync_stream, set_io_priority, undo_journal, set_nice, render_chunk_states, to_hex, verify_checksums, BlockMapFormat, ChecksumAlgorithm, ChecksumEntry, Compression, DmSnapshot, EncryptionKey, EtaAlgorithm, FileCompression, FlushMode, HashMode, IoEngine, IoPriority, LogTee, LvmSnapshot, MetricsServer, SourceWatcher, SyncError, SyncOptions, SyncStats};
 
 /// Sync file and block device that write only difference
+/// Every option can also be set in a --config TOML file, keys are the option long names with underscores
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
+#[clap(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    /// Options of the sync, given without a subcommand name to keep the command line of older versions working
+    #[clap(flatten)]
+    sync: SyncArgs,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Options of the sync subcommand, also accepted without the subcommand name
+#[derive(clap::Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SyncArgs {
     /// Use 2 threads to read source and destination at the same time. Do not use if they are on the same physical disk.
     #[clap(short, long)]
     thread: bool,
 
+    /// Compare the chunks of each buffer in parallel on all CPU cores, for fast disks where comparing is the bottleneck
+    #[clap(long)]
+    parallel_compare: bool,
+
+    /// Compare the chunks of each buffer in parallel on this many threads, like --parallel-compare with a set number of threads
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1 ..))]
+    threads: Option<u16>,
+
     /// Read buffer size in MB, default 100MB (Need 2x this in RAM)
     #[clap(short, long, default_value_t = 100)]
     buffer_size: usize,
 
+    /// Read the next buffers in a thread while the current one is compared and written, to hide the read latency.
+    /// Number of buffers from 2 (double buffering) to 8, each needs 2x --buffer-size in RAM.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u8).range(2 ..= 8), conflicts_with_all = ["mmap", "ignore_errors", "remote"])]
+    buffer_count: Option<u8>,
+
     /// Chunk size in KB, default 1024KB (Need 1x this in RAM)
     #[clap(short, long, default_value_t = 1024)]
     chunk_size: usize,
 
-    /// Quiet mode, do not print interactive user detail like: show progress, Threaded mode active, Buffer Size, Block size, filesize
+    /// Expand each write to this alignment in bytes on both ends, e.g. 4096 for storage that performs best with aligned writes
+    #[clap(long, value_name = "BYTES", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1 ..), conflicts_with = "remote")]
+    align: u64,
+
+    /// Use the physical block size of the destination block device as chunk size (logical sector size if unknown).
+    /// The chunk size option is silently overridden by the physical block size for block devices, regular files keep it.
+    #[clap(long)]
+    block_size_auto: bool,
+
+    /// Quiet mode, do not show progress and set the log level to error: no Threaded mode active, Buffer Size, Chunk size, filesize
     #[clap(short, long)]
     quiet: bool,
 
+    /// Do not show progress, but still print other details and the final summary, unlike quiet
+    #[clap(long)]
+    no_progress: bool,
+
+    /// Show progress as a full progress bar with bytes done, speed and remaining time instead of the plain progress line
+    #[clap(long)]
+    progress_bar: bool,
+
+    /// Color the progress output, default when stdout is a terminal and NO_COLOR is not set
+    #[clap(long, conflicts_with = "no_color")]
+    color: bool,
+
+    /// Do not color the progress output
+    #[clap(long)]
+    no_color: bool,
+
+    /// Follow the sync in a full screen terminal interface: progress, current and peak speed, remaining time, counters
+    /// and a scrollable log. q stops the sync after the current buffer.
+    #[clap(long, conflicts_with_all = ["progress_bar", "json", "quiet", "watch", "diff_only_report"])]
+    tui: bool,
+
+    /// Send log output to the system syslog daemon (facility local0) instead of stderr, for cron jobs
+    #[clap(long, conflicts_with = "tui")]
+    syslog: bool,
+
+    /// Also append the log to this file, each line prefixed with an RFC 3339 timestamp. If it cannot be opened,
+    /// the sync goes on with a warning.
+    #[clap(long, value_name = "PATH", conflicts_with = "syslog")]
+    log_file: Option<String>,
+
+    /// Write progress as a JSON object to this file every progress interval, for monitoring from scripts or services.
+    /// Removed when the sync completes, kept if it fails or is interrupted.
+    #[clap(long, value_name = "FILE")]
+    progress_file: Option<String>,
+
+    /// At the end, print the number of identical and differing chunks, write calls and a histogram of write sizes
+    #[clap(long)]
+    stats_detail: bool,
+
+    /// At the end, print a map of the chunks as characters, `.` identical, `#` written, `E` error, as wide as the
+    /// terminal. A character stands for several chunks on a large device.
+    #[clap(long)]
+    print_block_map_ascii: bool,
+
+    /// At the end, print the bytes that actually differed (the minimum to write), the bytes written and their ratio,
+    /// to tune --chunk-size and --align
+    #[clap(long, conflicts_with = "remote")]
+    write_amplification_report: bool,
+
+    /// Log level of the messages printed to stderr, progress and JSON objects go to stdout
+    #[clap(long, value_name = "LEVEL", default_value = "info", value_parser = ["trace", "debug", "info", "warn", "error"])]
+    log_level: String,
+
+    /// Interval in seconds between progress updates, e.g. 0.5
+    #[clap(long, value_name = "SECONDS", default_value_t = 2.)]
+    progress_interval: f64,
+
+    /// How the remaining time is estimated: linear (average speed since the start) or exponential
+    /// (moving average of the recent speed, follows speed changes)
+    #[clap(long, value_name = "ALGORITHM", default_value = "linear", value_parser = ["linear", "exponential"])]
+    progress_eta_algorithm: String,
+
+    /// Backend of the reads and writes: sync (blocking calls), tokio (blocking calls in tokio's thread pool) or io-uring
+    /// (needs the io-uring feature). Not with --thread, --buffer-count nor --mmap.
+    #[clap(long, value_name = "ENGINE", default_value = "sync", value_parser = ["sync", "tokio", "io-uring"])]
+    io_engine: String,
+
+    /// Print newline-delimited JSON objects to stdout instead of human readable text: progress every progress interval, then a done object with final stats
+    #[clap(short, long)]
+    json: bool,
+
+    /// Format of the final stats: human readable lines, one csv line `elapsed_secs,bytes_written,bytes_compared`
+    /// or a JSON object. csv and json are printed to stdout even with --quiet.
+    #[clap(long, value_name = "FORMAT", default_value = "human", value_parser = ["human", "csv", "json"], conflicts_with = "json")]
+    output_format: String,
+
+    /// Compare chunks by SHA-256 digest instead of byte for byte. Only 1x buffer size in RAM, destination is read one chunk at a time.
+    /// Slower (CPU bound), use it only when RAM is limited. Byte comparison is the fastest when 2x buffer size fits in RAM.
+    #[clap(short = 'H', long)]
+    hash: bool,
+
+    /// Punch holes in a regular file destination where the source is all zeros instead of writing zeros, to keep it sparse
+    #[clap(long, conflicts_with = "output_delta")]
+    sparse: bool,
+
+    /// Discard (TRIM) the ranges of a block device destination where the source is all zeros instead of writing zeros, for SSDs.
+    /// Only for devices that read discarded blocks as zeros, or they are found different at every sync. Regular files get zeros written.
+    #[clap(long, conflicts_with = "output_delta")]
+    trim: bool,
+
+    /// Open source and destination with O_DIRECT to bypass the page cache, buffer and chunk sizes are rounded up to the logical block size.
+    /// Falls back to the page cache with a warning if the filesystem does not support it.
+    #[clap(long)]
+    direct: bool,
+
+    /// Tell the kernel the source and destinations are read sequentially, and to read this many MB ahead of each buffer
+    /// while it is compared. Helps spinning disks with a small default read-ahead.
+    #[clap(long, value_name = "MB", conflicts_with = "remote")]
+    read_ahead: Option<usize>,
+
+    /// Linux I/O scheduling priority: idle, best-effort:<0-7> or realtime:<0-7> (0 is the highest), e.g. idle for background syncs
+    #[clap(long, value_name = "CLASS:LEVEL", value_parser = |s: &str| s.parse::<IoPriority>().map(|_| s.to_string()))]
+    io_priority: Option<String>,
+
+    /// CPU scheduling niceness from -20 to 19, added to the current one like the nice command. It does not affect I/O,
+    /// use it with --io-priority idle for a full background mode. Negative values need root, only a warning is printed otherwise.
+    #[clap(long, value_name = "LEVEL", allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20 ..= 19))]
+    nice: Option<i32>,
+
+    /// Map source and destination in memory and compare them without copying into buffers, can be faster on NVMe.
+    /// Source must be a regular file or a block device.
+    #[clap(long)]
+    mmap: bool,
+
+    /// Write to a copy of each file destination (cloned if the filesystem supports it) renamed over it only once
+    /// fully synced and flushed, so an interrupted sync leaves the destination intact. Needs free space for the copy if not cloned.
+    #[clap(long, conflicts_with_all = ["output_delta", "resume", "remote"])]
+    atomic: bool,
+
+    /// Call fsync on destinations after all writes, so nothing is left in kernel buffers. Its time is reported separately.
+    #[clap(long, group = "flush")]
+    fsync: bool,
+
+    /// Same as --fsync but with fdatasync, which skips the metadata not needed to read the data back
+    #[clap(long, group = "flush")]
+    fdatasync: bool,
+
+    /// Dry run, read and compare everything but do not write nor truncate the destination. Report bytes that would be written.
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Compare only, like --dry-run, and print one `start-end` line per differing region to stdout (end excluded).
+    /// Exit code 0 if source and destination are identical, 1 if they differ, 2 on error, for scripts and CI.
+    #[clap(long, conflicts_with_all = ["output_delta", "resume", "dest", "remote", "atomic", "watch", "json"])]
+    diff_only_report: bool,
+
+    /// Refuse to sync, with exit code 2, if a destination file was modified more recently than the source file,
+    /// as it may hold newer data. Block devices are not checked.
+    #[clap(long, conflicts_with = "remote")]
+    timestamp_check: bool,
+
+    /// Refuse to sync, with exit code 2, if a destination is mounted, e.g. a mount point given instead of its block
+    /// device or a disk with a mounted partition, from /proc/mounts
+    #[clap(long, conflicts_with = "remote")]
+    destination_device_check: bool,
+
+    /// Exit with code 4 if the source is not a block device, e.g. a regular file, for scripts that must only copy disks
+    #[clap(long)]
+    source_block_device_only: bool,
+
+    /// Exit with code 5 if a destination is not a block device, so a regular file, e.g. a configuration file given by
+    /// mistake, is never overwritten
+    #[clap(long)]
+    dest_block_device_only: bool,
+
+    /// Sync even if --timestamp-check finds a destination newer than the source or --destination-device-check a
+    /// mounted destination
+    #[clap(long)]
+    force: bool,
+
+    /// Write differing regions to this delta file instead of the destination, which is only read. Apply it later with the apply-delta subcommand.
+    #[clap(short, long, value_name = "DELTA_FILE", conflicts_with_all = ["dry_run", "resume"])]
+    output_delta: Option<String>,
+
+    /// Write differing regions to this gzip compressed delta archive instead of the destination, with the source path
+    /// and size in its header. Apply it later with the apply-delta subcommand.
+    #[clap(long, value_name = "ARCHIVE", conflicts_with_all = ["output_delta", "destination_zstd", "dry_run", "resume", "sparse", "trim", "atomic", "diff_only_report", "write_journal", "verify"])]
+    destination_gzip: Option<String>,
+
+    /// Same as --destination-gzip, compressed with zstd
+    #[clap(long, value_name = "ARCHIVE", conflicts_with_all = ["output_delta", "dry_run", "resume", "sparse", "trim", "atomic", "diff_only_report", "write_journal", "verify"])]
+    destination_zstd: Option<String>,
+
+    /// Save the destination data to this journal before each write, to restore it later with the undo subcommand.
+    #[clap(long, value_name = "JOURNAL_FILE", conflicts_with_all = ["dry_run", "output_delta", "resume", "remote", "atomic", "dest"])]
+    write_journal: Option<String>,
+
+    /// If the sync fails, restore the destination from the --write-journal journal, as it was before the sync
+    #[clap(long, requires = "write_journal")]
+    rollback_on_error: bool,
+
+    /// Write the digest of every source chunk to this file, one `offset<TAB>digest<TAB>length` line per chunk.
+    /// Check a copy against it later with the verify subcommand, without the source.
+    #[clap(long, value_name = "CHECKSUM_FILE")]
+    checksum_file: Option<String>,
+
+    /// Hash algorithm of the --checksum-file: sha256, blake3 (much faster) or xxhash (fastest, not cryptographic).
+    /// It is recorded in the file, verify detects it.
+    #[clap(long, value_name = "ALGORITHM", value_parser = ["sha256", "blake3", "xxhash"], default_value = "sha256")]
+    checksum_algo: String,
+
+    /// Write the offsets of the 512-byte sectors written to this file, for audit trails. Format set by --block-map-format.
+    #[clap(long, value_name = "FILE")]
+    block_map: Option<String>,
+
+    /// Format of the --block-map file: binary (sorted little endian u64 offsets), text (one offset per line)
+    /// or ranges (`start_byte,end_byte` lines of adjacent sectors, usable with --exclude-ranges)
+    #[clap(long, value_name = "FORMAT", default_value = "binary", value_parser = ["binary", "text", "ranges"])]
+    block_map_format: String,
+
+    /// Limit write bandwidth in MB/s, to avoid starving other I/O on the destination
+    #[clap(long, value_name = "MB/s")]
+    rate_limit: Option<f64>,
+
+    /// Abort with an error before more than N bytes are written, against a near full rewrite e.g. when source and
+    /// destination were swapped by mistake. What is already written is kept.
+    #[clap(long, value_name = "N", conflicts_with = "remote")]
+    max_bytes_written: Option<u64>,
+
+    /// Stop after the current buffer once this many seconds have passed, print the stats of the synced part and exit
+    /// with code 6. Continue later from the offset it stopped at with --offset.
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "benchmark")]
+    time_limit: Option<u64>,
+
+    /// Abort with exit code 3 when more than this fraction (0.0 to 1.0) of the data compared had to be written,
+    /// e.g. 0.5 if half of the source differing means a wrong source or destination
+    #[clap(long, value_name = "RATIO", conflicts_with = "remote")]
+    max_diff_ratio: Option<f64>,
+
+    /// Do not write differing regions shorter than this many bytes, e.g. 4096 to leave alone metadata that changes on
+    /// every read. Regions are made of whole chunks, a value up to the chunk size filters nothing.
+    #[clap(long, value_name = "BYTES", default_value_t = 0, conflicts_with = "remote")]
+    diff_filter: usize,
+
+    /// Limit the number of write calls per second (IOPS), for storage limited by operations more than bandwidth.
+    /// Can be combined with --rate-limit.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1 ..))]
+    limit_writes_per_second: Option<u32>,
+
+    /// Start the sync at this offset in bytes of source and destination, must be a multiple of the chunk size
+    #[clap(long, default_value_t = 0)]
+    offset: u64,
+
+    /// Sync only this many bytes from the offset, default up to the end of the source
+    #[clap(long)]
+    length: Option<u64>,
+
+    /// Read the source from this offset in bytes, synced to the start of the destination. With --length, extract
+    /// a partition of a whole disk image to its own file.
+    #[clap(long, value_name = "BYTES", default_value_t = 0, conflicts_with = "remote")]
+    source_offset: u64,
+
+    /// CSV file of `start_byte,end_byte` lines (end excluded) of ranges known to be identical, neither read nor written
+    #[clap(long, value_name = "CSV_FILE")]
+    exclude_ranges: Option<String>,
+
+    /// CSV file of `start_byte,end_byte` lines (end excluded) of regions to sync first, in the order given, e.g. the boot
+    /// sector and kernel of a VM image to make it bootable sooner. The rest is synced after, from the start.
+    #[clap(long, value_name = "CSV_FILE", conflicts_with_all = ["resume", "buffer_count", "remote"])]
+    priority_regions: Option<String>,
+
+    /// Streaming mode: a source `-` is read from stdin and compared to the destination, a destination `-` gets a copy
+    /// of the source on stdout. Most options cannot be used, a pipe is read or written only once in order.
+    #[clap(long, conflicts_with_all = ["thread", "remote", "watch", "lvm_snapshot", "diff_only_report", "tui", "timestamp_check", "benchmark", "dest"])]
+    stream: bool,
+
+    /// Compression of the source image: none, gz or zstd. A compressed source is decompressed on the fly and synced
+    /// without writing it to disk first. It is read once in order, like a --stream source, so --offset and the other
+    /// seeking options cannot be used.
+    #[clap(long, value_name = "ALGORITHM", default_value = "none", value_parser = ["none", "gz", "zstd"])]
+    source_compression: String,
+
+    /// Deprecated, use --source-compression gz
+    #[clap(long, hide = true, conflicts_with = "source_compression")]
+    source_gzip: bool,
+
+    /// Serve Prometheus metrics over HTTP on this address during the sync, e.g. 127.0.0.1:9101: bytes compared and
+    /// written, progress ratio and speed. Stopped when the sync is done, kept between syncs with --watch.
+    #[clap(long, value_name = "BIND_ADDR", conflicts_with_all = ["tui", "stream"])]
+    stats_prometheus: Option<String>,
+
+    /// Only measure the read speed of one buffer of source and destinations (at the same time with --thread), write nothing and exit
+    #[clap(long)]
+    benchmark: bool,
+
+    /// Do not read the destination, assume it is zeroed (e.g. a new or discarded device) and write only the non-zero chunks
+    /// of the source. UNSAFE if the destination is not zeroed: its data where the source is zero is kept.
+    #[clap(long, conflicts_with_all = ["remote", "seed_from"])]
+    seed_zeros: bool,
+
+    /// Resize a regular file destination of another size to the source size, the default, with a warning
+    #[clap(long, conflicts_with = "no_truncate_dest")]
+    truncate_dest: bool,
+
+    /// Exit with an error instead of resizing a regular file destination of another size than the source,
+    /// e.g. if the wrong destination was given. A new destination file is still created.
+    #[clap(long, conflicts_with = "remote")]
+    no_truncate_dest: bool,
+
+    /// After the data, also sync the permissions, the ownership (as root) and the extended attributes of regular file
+    /// destinations. Block device destinations are skipped.
+    #[clap(long, conflicts_with = "remote")]
+    metadata: bool,
+
+    /// Create a destination file which does not exist as a sparse file of the source size, only the source data which
+    /// is not zero is then written
+    #[clap(long, conflicts_with = "remote")]
+    create_sparse_dest: bool,
+
+    /// Byte-swap every 2, 4 or 8 byte word of the source before comparing, to sync an image of the other endianness.
+    /// The destination receives the swapped data, not a copy of the source.
+    #[clap(long, value_name = "WORD_SIZE", value_parser = ["2", "4", "8"], conflicts_with_all = ["mmap", "ignore_errors", "remote"])]
+    swap_endian: Option<String>,
+
+    /// After the sync, write zeros to the part of a block device destination beyond the source size, which is otherwise
+    /// left as is. Regular file destinations are truncated to the source size anyway.
+    #[clap(long, conflicts_with = "remote")]
+    zero_unwritten: bool,
+
+    /// Base image, e.g. the previous backup. A destination which size differs from it, like a new file, is first
+    /// initialized with a copy of it, then only what changed in the source since the base is written.
+    #[clap(long, value_name = "BASE_IMAGE")]
+    seed_from: Option<String>,
+
+    /// After sync, re-read every written region from source and destination and check they match. Exit with error if not.
+    #[clap(short, long, conflicts_with_all = ["dry_run", "output_delta"])]
+    verify: bool,
+
+    /// Read back every region right after writing it and check it matches the source, a mismatch is a write error:
+    /// retried with --retries, skipped and logged with --ignore-errors. Read from the page cache without --direct.
+    #[clap(long, conflicts_with_all = ["dry_run", "output_delta", "destination_gzip", "destination_zstd", "remote", "stream"])]
+    write_verify_each: bool,
+
+    /// Flush the cache of block device destinations after each write, so that a power loss cannot leave later writes
+    /// on the device without earlier ones. Can reduce write speed by 10-100x, only use it for critical data.
+    #[clap(long, conflicts_with_all = ["dry_run", "output_delta", "destination_gzip", "destination_zstd", "remote", "stream"])]
+    write_barrier: bool,
+
+    /// Copy the regions beyond the previous end of a regular file destination, e.g. all of a new file, with sendfile(2)
+    /// in the kernel instead of writing them from user space. Falls back to writes if it is not supported. Linux only.
+    #[clap(long, conflicts_with_all = ["dry_run", "output_delta", "destination_gzip", "destination_zstd", "remote", "stream", "swap_endian", "simulate_corruption", "write_verify_each"])]
+    use_sendfile: bool,
+
+    /// Sync to the destination of a `localblocksync serve` server at this address instead of a local destination.
+    /// Only differing chunks are sent. In plain text without a key, use it on a trusted network or an SSH tunnel, or
+    /// encrypted and authenticated with --encrypt.
+    #[clap(long, value_name = "HOST:PORT", conflicts_with_all = ["dst_path", "dest", "benchmark"])]
+    remote: Option<String>,
+
+    /// Compress the data sent to the --remote server chunk by chunk, zstd or lz4 (faster, compresses less)
+    #[clap(long, value_name = "ALGORITHM", value_parser = ["zstd", "lz4"], requires = "remote")]
+    compress: Option<String>,
+
+    /// Encrypt the connection to the --remote server with ChaCha20-Poly1305 and this 32 byte key file, written with the
+    /// keygen subcommand. The server needs the same key file. Lighter than TLS, but only as secret as the key file.
+    #[clap(long, value_name = "KEY_FILE", requires = "remote")]
+    encrypt: Option<String>,
+
+    /// zstd compression level from 1 (fastest) to 22 (smallest), default 3
+    #[clap(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1 ..= 22), requires = "compress")]
+    compress_level: Option<i32>,
+
+    /// Shell command run before each sync, before any file is opened, e.g. to pause a VM or snapshot a volume.
+    /// The sync is aborted if it fails.
+    #[clap(long, value_name = "SHELL_COMMAND")]
+    pre_sync_hook: Option<String>,
+
+    /// Shell command run after each sync and its flush, even if the sync failed. It gets BYTES_WRITTEN, ELAPSED_SECS
+    /// and SYNC_RESULT (ok, interrupted or failed) environment variables. Exit with error if it fails.
+    #[clap(long, value_name = "SHELL_COMMAND")]
+    post_sync_hook: Option<String>,
+
+    /// Sync from a temporary snapshot of this LVM logical volume, removed after the sync, so a mounted volume is
+    /// copied in a consistent state. SRC_PATH is then omitted: `localblocksync --lvm-snapshot vg/lv DST_PATH`. Needs root.
+    #[clap(long, value_name = "VG/LV", conflicts_with = "watch")]
+    lvm_snapshot: Option<String>,
+
+    /// Copy-on-write space of the --lvm-snapshot, the sync fails if more than this is written to the volume meanwhile
+    #[clap(long, value_name = "SIZE", default_value = "1G")]
+    lvm_snapshot_size: String,
+
+    /// Write to a device-mapper snapshot of the destination block device, stored on this block device which content
+    /// is lost, instead of the destination itself. The destination keeps its previous state until the snapshot is
+    /// merged with --snapshot-merge, or later with dmsetup. The snapshot is removed if the sync fails. Needs root.
+    #[clap(long, value_name = "SNAPSHOT_DEVICE", conflicts_with_all = ["dest", "remote", "stream", "watch", "dry_run", "output_delta", "destination_gzip", "destination_zstd"])]
+    snapshot_before: Option<String>,
+
+    /// Merge the --snapshot-before snapshot into the destination after a successful sync, waiting for the merge
+    #[clap(long, requires = "snapshot_before")]
+    snapshot_merge: bool,
+
+    /// After the sync, keep running and sync again each time the source file is modified, until Ctrl-C. Linux only (inotify).
+    /// Every sync compares the whole source, only the changed regions are written.
+    #[clap(long, conflicts_with_all = ["benchmark", "resume"])]
+    watch: bool,
+
+    /// In watch mode, wait until the source has not changed for this many milliseconds before syncing again
+    #[clap(long, value_name = "MS", default_value_t = 1000)]
+    watch_debounce: u64,
+
+    /// Log read and write errors and go on with the next chunk instead of failing, for degraded drives.
+    /// Source chunks that cannot be read are left as is in the destination. Exit with error if any error happened.
+    #[clap(long, conflicts_with = "remote")]
+    ignore_errors: bool,
+
+    /// Retry a failed read or write N times, waiting 100ms then twice longer each time, before failing or going on with --ignore-errors
+    #[clap(long, value_name = "N", default_value_t = 0, conflicts_with = "remote")]
+    retry: u32,
+
+    /// Debug builds only: flip a random bit of a source read with this probability, between 0 and 1, to test --verify
+    #[cfg(debug_assertions)]
+    #[clap(long, value_name = "RATE", conflicts_with_all = ["mmap", "remote"])]
+    simulate_corruption: Option<f64>,
+
+    /// Debug builds only: seed of the --simulate-corruption generator, the same seed corrupts the same bits
+    #[cfg(debug_assertions)]
+    #[clap(long, value_name = "SEED", default_value_t = 0, requires = "simulate_corruption")]
+    simulate_seed: u64,
+
+    /// Checkpoint file, save synced offset in it regularly and resume from it if it exists. Removed when sync is done.
+    #[clap(short, long, value_name = "CHECKPOINT_FILE", conflicts_with = "dry_run")]
+    resume: Option<String>,
+
+    /// Interval in seconds between checkpoint saves, default 60s
+    #[clap(long, default_value_t = 60)]
+    checkpoint_interval: u64,
+
+    /// Sync in segments of this size in MB, each flushed and recorded in the --resume checkpoint when done, so a resume
+    /// skips the completed segments and restarts the interrupted one from its start
+    #[clap(long, value_name = "SEGMENT_SIZE_MB", value_parser = clap::value_parser!(u64).range(1 ..), requires = "resume", conflicts_with_all = ["remote", "atomic", "priority_regions", "checksum_file", "block_map", "write_journal"])]
+    checkpointed_write: Option<u64>,
+
+    /// Read options from this TOML file, e.g. `thread = true` or `src_path = "/dev/sda1"`. Command line options override it.
+    #[clap(long, value_name = "TOML_FILE")]
+    #[serde(skip)]
+    config: Option<String>,
+
+    /// Print the effective configuration as TOML and exit, to bootstrap a --config file
+    #[clap(long)]
+    #[serde(skip)]
+    dump_config: bool,
+
+    /// Path of data source, a file or a block device. Required, on the command line or in the config file.
+    src_path: Option<String>,
+
+    /// Path of data destination, a file or a block device. Required unless --dest is given.
+    dst_path: Option<String>,
+
+    /// Additional destination, can be repeated to sync several destinations in one pass reading the source once
+    #[clap(short, long, value_name = "DST_PATH")]
+    dest: Vec<String>,
+}
+
+impl SyncArgs {
+    /// Delta file or compressed delta archive written instead of the destination, with its compression
+    fn delta_file(&self) -> Option<(&String, FileCompression)> {
+        match (&self.output_delta, &self.destination_gzip, &self.destination_zstd){
+            (Some(delta_file), _, _) => Some((delta_file, FileCompression::None)),
+            (_, Some(delta_file), _) => Some((delta_file, FileCompression::Gzip)),
+            (_, _, Some(delta_file)) => Some((delta_file, FileCompression::Zstd)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync a source to a destination, the default when no subcommand is given
+    Sync(Box<SyncArgs>),
+    /// Apply a delta file written with --output-delta, or a delta archive, to a target file or block device
+    ApplyDelta(ApplyDeltaArgs),
+    /// Undo a sync, writing back the destination data saved with --write-journal, the last write first
+    Undo(UndoArgs),
+    /// Compare source and destination without writing anything, stop at the first difference.
+    /// Exit 0 if they are identical, 1 if they differ, 2 on error, like cmp.
+    Compare(CompareArgs),
+    /// Print the regions of the destination that differ from the source, without writing anything
+    Diff(DiffArgs),
+    /// Listen for a client started with --remote and sync it to a destination, one client at a time until killed
+    Serve(ServeArgs),
+    /// Write a new random key file for --encrypt, to copy to both the client and the server
+    Keygen(KeygenArgs),
+    /// Check every chunk of a file or block device against a checksum file written with --checksum-file.
+    /// Exit 0 if every chunk matches, 1 if any does not, 2 if the checksum file is malformed or on error.
+    Verify(VerifyArgs),
+}
+
+/// Arguments of the apply-delta subcommand
+#[derive(clap::Args, Debug)]
+struct ApplyDeltaArgs {
+    /// Delta file written with --output-delta, or archive written with --destination-gzip or --destination-zstd
+    delta_file: String,
+
+    /// Path of the target, a file or a block device
+    target: String,
+}
+
+/// Arguments of the undo subcommand
+#[derive(clap::Args, Debug)]
+struct UndoArgs {
+    /// Journal file written with --write-journal
+    journal_file: String,
+
+    /// Path of the destination of the sync, a file or a block device
+    target: String,
+}
+
+/// Arguments of the compare subcommand
+#[derive(clap::Args, Debug)]
+struct CompareArgs {
+    /// Chunk size in KB
+    #[clap(short, long, default_value_t = 1024)]
+    chunk_size: usize,
+
+    /// Read buffer size in MB (Need 2x this in RAM)
+    #[clap(short, long, default_value_t = 100)]
+    buffer_size: usize,
+
+    /// Do not display the progress
+    #[clap(long)]
+    no_progress: bool,
+
+    /// Print the offset of the first differing byte
+    #[clap(short, long)]
+    verbose: bool,
+
     /// Path of data source, a file or a block device
     src_path: String,
 
@@ -52,224 +609,978 @@ struct Args {
     dst_path: String,
 }
 
-// Generate ioctl function
-const BLKGETSIZE64_CODE: u8 = 0x12; // Defined in linux/fs.h
-const BLKGETSIZE64_SEQ: u8 = 114;
-ioctl_read!(ioctl_blkgetsize64, BLKGETSIZE64_CODE, BLKGETSIZE64_SEQ, u64); // Define function ioctl_blkgetsize64
+/// Arguments of the diff subcommand
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Chunk size in KB, granularity of the reported regions
+    #[clap(short, long, default_value_t = 1024)]
+    chunk_size: usize,
 
-fn main(){
-    let arg = Args::parse();
-    let src_path = Path::new(&arg.src_path);
-    let dst_path = Path::new(&arg.dst_path);
-    copy(src_path, dst_path, arg.thread, arg.buffer_size, arg.chunk_size, arg.quiet);
+    /// Read buffer size in MB (Need 2x this in RAM)
+    #[clap(short, long, default_value_t = 100)]
+    buffer_size: usize,
+
+    /// Output format: tsv prints `offset<TAB>length` lines, json one object per line
+    #[clap(long, value_enum, default_value_t = DiffFormat::Tsv)]
+    diff_format: DiffFormat,
+
+    /// Path of data source, a file or a block device
+    src_path: String,
+
+    /// Path of data destination, a file or a block device
+    dst_path: String,
+}
+
+/// Arguments of the serve subcommand
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Address to listen on
+    #[clap(long, value_name = "HOST:PORT", default_value = "0.0.0.0:7227")]
+    listen: String,
+
+    /// Only accept clients encrypting with this 32 byte key file, written with the keygen subcommand
+    #[clap(long, value_name = "KEY_FILE")]
+    encrypt: Option<String>,
+
+    /// Path of data destination, a file or a block device
+    dst_path: String,
 }
 
-/// Determine block device size
-fn get_device_size(path: &str) -> u64 {
-   let file = OpenOptions::new()
-             .read(true)
-             .open(path).unwrap();
+/// Arguments of the keygen subcommand
+#[derive(clap::Args, Debug)]
+struct KeygenArgs {
+    /// Key file to create, an existing file is not overwritten
+    output_file: String,
+}
 
-   let fd = file.as_raw_fd();
+/// Arguments of the verify subcommand
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Only print the mismatches, not a line for each matching chunk
+    #[clap(short, long)]
+    quiet: bool,
 
-   let mut cap = 0u64;
-   let cap_ptr = &mut cap as *mut u64;
+    /// Checksum file written with --checksum-file
+    checksum_file: String,
 
-   unsafe {
-      ioctl_blkgetsize64(fd, cap_ptr).unwrap();
-   }
-   return cap;
+    /// Path of the copy to check, a file or a block device
+    target: String,
 }
 
-fn is_block_device(path: &std::path::Path) -> bool {
-    let path_abs = fs::canonicalize(path).unwrap();
+/// Format of the final stats of a sync
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Human,
+    Csv,
+    Json,
+}
 
-    return match block_utils::is_block_device(path_abs){
-        Ok(is_block_device) => is_block_device,
-        Err(_e) => false,
-    };
+/// Output format of the diff subcommand
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DiffFormat {
+    Human,
+    Tsv,
+    Json,
 }
 
-fn filesize(path: &std::path::Path) -> Result<u64, std::io::Error> {
-    match fs::canonicalize(path){
-        Ok(path_abs) => {
-            if is_block_device(&path_abs) {
-                //println!("{:?} is a block device", path_abs);
-                return Ok(get_device_size(path_abs.to_str().unwrap()));
+/// Parse the command line, options not given on it are taken from the --config file if any
+fn parse_args() -> (SyncArgs, Option<Command>) {
+    let mut cmd = Cli::command();
+    let matches = cmd.get_matches_mut();
+    let cli = match Cli::from_arg_matches(&matches){
+        Ok(cli) => cli,
+        Err(err) => err.exit(),
+    };
+    // The sync options are the same with or without the sync subcommand name
+    let (mut arg, command, sync_matches) = match cli.command{
+        Some(Command::Sync(arg)) => (*arg, None, matches.subcommand_matches("sync").unwrap()),
+        command => (cli.sync, command, &matches),
+    };
+    if let Some(config_path) = arg.config.take(){
+        let content = match fs::read_to_string(&config_path){
+            Ok(content) => content,
+            Err(err) => cmd.error(ErrorKind::Io, format!("Failed to read config file {}: {}", config_path, err)).exit(),
+        };
+        let config: toml::Table = match toml::from_str(&content){
+            Ok(config) => config,
+            Err(err) => cmd.error(ErrorKind::InvalidValue, format!("Invalid config file {}: {}", config_path, err)).exit(),
+        };
+        let mut merged = toml::Table::try_from(&arg).unwrap();
+        for (key, value) in config{
+            if !cmd.get_arguments().any(|option| option.get_id() == key.as_str()){
+                cmd.error(ErrorKind::UnknownArgument, format!("Unknown key {:?} in config file {}", key, config_path)).exit();
             }
-            match path_abs.metadata(){
-                Ok(m) => Ok(m.len()),
-                Err(_e) => Ok(0)
+            // Command line wins over the config file
+            if sync_matches.value_source(&key) != Some(ValueSource::CommandLine){
+                merged.insert(key, value);
             }
-        },
-        Err(_e) => Ok(0)
+        }
+        let dump_config = arg.dump_config;
+        arg = match merged.try_into(){
+            Ok(arg) => arg,
+            Err(err) => cmd.error(ErrorKind::InvalidValue, format!("Invalid config file {}: {}", config_path, err)).exit(),
+        };
+        arg.config = Some(config_path);
+        arg.dump_config = dump_config;
+    }
+    if arg.dump_config{
+        print!("{}", toml::to_string(&arg).unwrap());
+        process::exit(0);
+    }
+    if let Some(lvm_snapshot) = &arg.lvm_snapshot{
+        let Some((vg, lv)) = lvm_snapshot.split_once('/') else {
+            cmd.error(ErrorKind::InvalidValue, format!("Invalid --lvm-snapshot {:?}, expected VG/LV", lvm_snapshot)).exit();
+        };
+        if arg.src_path.is_some() && arg.dst_path.is_some(){
+            cmd.error(ErrorKind::ArgumentConflict, "SRC_PATH cannot be given with --lvm-snapshot").exit();
+        }
+        // The only path given is the destination, the origin volume stands for the source until the snapshot is taken
+        if arg.dst_path.is_none() && arg.dest.is_empty() && arg.remote.is_none(){
+            arg.dst_path = arg.src_path.take();
+        }
+        arg.src_path = Some(format!("/dev/{}/{}", vg, lv));
+    }
+    if command.is_none() && (arg.src_path.is_none() || (arg.dst_path.is_none() && arg.dest.is_empty() && arg.remote.is_none())){
+        cmd.error(ErrorKind::MissingRequiredArgument, "SRC_PATH and DST_PATH (or --dest or --remote) are required, on the command line or in the --config file").exit();
     }
+    (arg, command)
 }
 
-fn display_progress(file_cursor_pos: f64, src_size: f64, start_time: Instant){
-    let mut stdout = stdout();
-    let progress = file_cursor_pos / src_size;
-    let progress_round = (progress*10.).ceil();
-    let progress_txt = format!("{:#<1$}","", progress_round as usize);
-    let progress_pc = (progress * 100.).ceil();
-    let speed_mb = file_cursor_pos / start_time.elapsed().as_secs() as f64 / 1024. / 1024.;
-    let remaining_time = start_time.elapsed().as_secs() as f64 * (src_size - file_cursor_pos) / file_cursor_pos;
-    print!("\r[{:-<10}] {}% - {:.3} MB/s - Remaining {:.0}s          ", progress_txt, progress_pc, speed_mb, remaining_time);
-    stdout.flush().unwrap();
+/// Color the progress output if forced, or by default on a terminal unless NO_COLOR is set (https://no-color.org)
+fn use_color(arg: &SyncArgs) -> bool {
+    if arg.color || arg.no_color{
+        return arg.color;
+    }
+    env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && io::stdout().is_terminal()
 }
 
-fn copy(src_path: &Path, dst_path: &Path, threaded: bool, buffer_size: usize, chunk_size: usize, quiet: bool){
-    println!("Synching {:?} to {:?}", src_path, dst_path);
-    let src_size = filesize(src_path).unwrap();
-    let dst_size = filesize(dst_path).unwrap();
-    if !quiet{
-        println!("Sizes:");
-        println!("{}: {} [{:.1} MB]", src_path.to_str().unwrap(), src_size, src_size as f64 / 1024. / 1024.);
-        println!("{}: {} [{:.1} MB]", dst_path.to_str().unwrap(), dst_size, dst_size as f64 / 1024. / 1024.);
+fn main(){
+    let (arg, command) = parse_args();
+    let log_level = if arg.quiet {LevelFilter::Error} else {arg.log_level.parse().unwrap()};
+    // Logs would mess up the terminal interface, they are shown in it
+    let mut tui_logs = None;
+    if arg.syslog{
+        let formatter = syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_LOCAL0,
+            hostname: None,
+            process: "localblocksync".into(),
+            pid: process::id(),
+        };
+        match syslog::unix(formatter){
+            Ok(logger) => {
+                log::set_boxed_logger(Box::new(syslog::BasicLogger::new(logger))).unwrap();
+                log::set_max_level(log_level);
+            },
+            Err(err) => {
+                eprintln!("Cannot connect to syslog: {}", err);
+                process::exit(1);
+            }
+        }
+    }else{
+        let mut logger = env_logger::Builder::new();
+        logger.filter_level(log_level).format(|buf, record| writeln!(buf, "{}", record.args()));
+        let mut target: Box<dyn Write + Send> = Box::new(io::stderr());
+        if arg.tui && command.is_none(){
+            let (log_pipe, logs) = LogPipe::new();
+            target = Box::new(log_pipe);
+            tui_logs = Some(logs);
+        }
+        if let Some(log_path) = &arg.log_file{
+            match LogTee::open_file(Path::new(log_path)){
+                Ok(file) => target = Box::new(LogTee::new(target, file)),
+                Err(err) => eprintln!("Warning: cannot open log file {}: {}", log_path, err),
+            }
+        }
+        logger.target(env_logger::Target::Pipe(target));
+        logger.init();
     }
-
-    if src_size == 0{
-        println!("Source file is empty ! Nothing to do !");
+    if let Some(Command::ApplyDelta(ApplyDeltaArgs { delta_file, target })) = &command{
+        log::info!("Applying {:?} to {:?}", delta_file, target);
+        match apply_delta(Path::new(delta_file), Path::new(target)){
+            Ok(stats) => {
+                log::info!("Elapsed time: {:.2}s", stats.elapsed.as_secs_f64());
+                log::info!("Total bytes written: {} [{:.1} MB]", stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        }
         return;
     }
-
-    let mut src_file = match File::open(src_path){
-        Ok(src_file) => src_file,
-        Err(_err) => {
-            println!("Failed to open {}.", src_path.to_str().unwrap());
+    if let Some(Command::Undo(UndoArgs { journal_file, target })) = &command{
+        log::info!("Undoing {:?} on {:?}", journal_file, target);
+        match undo_journal(Path::new(journal_file), Path::new(target)){
+            Ok(stats) => {
+                log::info!("Elapsed time: {:.2}s", stats.elapsed.as_secs_f64());
+                log::info!("Total bytes restored: {} [{:.1} MB]", stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(Command::Compare(CompareArgs { chunk_size, buffer_size, no_progress, verbose, src_path, dst_path })) = &command{
+        let opts = SyncOptions::new()
+            .buffer_size(1024*1024*buffer_size)
+            .chunk_size(1024*chunk_size)
+            .threaded(true)
+            .no_progress(*no_progress);
+        match compare_paths(Path::new(src_path), Path::new(dst_path), opts){
+            Ok(None) => (),
+            Ok(Some(offset)) => {
+                if *verbose{
+                    println!("{} {} differ: first difference at offset {}", src_path, dst_path, offset);
+                }
+                process::exit(1);
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(2);
+            }
+        }
+        return;
+    }
+    if let Some(Command::Diff(DiffArgs { chunk_size, buffer_size, diff_format, src_path, dst_path })) = &command{
+        let opts = SyncOptions::new()
+            .buffer_size(1024*1024*buffer_size)
+            .chunk_size(1024*chunk_size)
+            .quiet(true);
+        let ranges = match diff_paths(Path::new(src_path), Path::new(dst_path), opts){
+            Ok(ranges) => ranges,
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        };
+        for &(offset, len) in &ranges{
+            match diff_format{
+                DiffFormat::Human => println!("{} bytes [{:.1} MB] differ at offset {} [{:.1} MB]", len, len as f64 / 1024. / 1024., offset, offset as f64 / 1024. / 1024.),
+                DiffFormat::Tsv => println!("{}\t{}", offset, len),
+                DiffFormat::Json => println!("{}", serde_json::json!({"offset": offset, "length": len})),
+            }
+        }
+        if let DiffFormat::Human = diff_format{
+            let total: u64 = ranges.iter().map(|&(_, len)| len as u64).sum();
+            println!("{} differing regions, {} bytes [{:.1} MB]", ranges.len(), total, total as f64 / 1024. / 1024.);
+        }
+        return;
+    }
+    if let Some(Command::Verify(VerifyArgs { quiet, checksum_file, target })) = &command{
+        log::info!("Verifying {:?} against {:?}", target, checksum_file);
+        let print_chunk = |entry: &ChecksumEntry, digest: &[u8]| {
+            if digest != entry.digest{
+                println!("MISMATCH at offset {}: expected {} got {}", entry.offset, to_hex(&entry.digest), to_hex(digest));
+            }else if !quiet{
+                println!("OK at offset {}: {}", entry.offset, to_hex(digest));
+            }
+        };
+        match verify_checksums(Path::new(checksum_file), Path::new(target), print_chunk){
+            Ok((checked, mismatches)) => {
+                if !mismatches.is_empty(){
+                    log::error!("Verify failed: {} of {} chunks do not match !", mismatches.len(), checked);
+                    process::exit(1);
+                }
+                log::info!("Verify OK: {} chunks match", checked);
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(2);
+            }
+        }
+        return;
+    }
+    if let Some(Command::Keygen(KeygenArgs { output_file })) = &command{
+        if let Err(err) = EncryptionKey::generate(Path::new(output_file)){
+            log::error!("Failed to write key file {}: {}", output_file, err);
             process::exit(1);
         }
+        log::info!("Key written to {}", output_file);
+        return;
+    }
+    if let Some(Command::Serve(ServeArgs { listen, encrypt, dst_path })) = &command{
+        let key = encrypt.as_deref().map(read_key);
+        if let Err(err) = serve(listen, Path::new(dst_path), key.as_ref()){
+            log::error!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    let encryption = arg.encrypt.as_deref().map(read_key);
+    // Values from the config file are not checked by clap
+    let compression = match arg.compress.as_deref(){
+        Some("zstd") => Some(Compression::Zstd(arg.compress_level.unwrap_or(3).clamp(1, 22))),
+        Some("lz4") => Some(Compression::Lz4),
+        Some(other) => {
+            log::error!("Invalid compression {:?}, expected zstd or lz4", other);
+            process::exit(1);
+        },
+        None => None,
     };
-    let mut dst_file = match OpenOptions::new().create(true).read(true).write(true).open(dst_path){
-        Ok(dst_file) => dst_file,
-        Err(_err) => {
-            println!("Failed to open {} in write mode.", dst_path.to_str().unwrap());
+    let checksum_algorithm: ChecksumAlgorithm = match arg.checksum_algo.parse(){
+        Ok(checksum_algorithm) => checksum_algorithm,
+        Err(err) => {
+            log::error!("{}", err);
             process::exit(1);
+        },
+    };
+    let swap_endian = match arg.swap_endian.as_deref().map(str::parse::<usize>){
+        Some(Ok(word_size)) => Some(word_size),
+        Some(Err(_)) => {
+            log::error!("Invalid --swap-endian {:?}, expected 2, 4 or 8", arg.swap_endian.as_deref().unwrap_or_default());
+            process::exit(1);
+        },
+        None => None,
+    };
+    let io_engine: IoEngine = match arg.io_engine.parse(){
+        Ok(io_engine) => io_engine,
+        Err(err) => {
+            log::error!("{}", err);
+            process::exit(1);
+        },
+    };
+    let source_compression: FileCompression = match arg.source_compression.parse(){
+        Ok(FileCompression::None) if arg.source_gzip => {
+            log::warn!("Warning: --source-gzip is deprecated, use --source-compression gz");
+            FileCompression::Gzip
+        },
+        Ok(source_compression) => source_compression,
+        Err(err) => {
+            log::error!("{}", err);
+            process::exit(1);
+        },
+    };
+    let eta_algorithm: EtaAlgorithm = match arg.progress_eta_algorithm.parse(){
+        Ok(eta_algorithm) => eta_algorithm,
+        Err(err) => {
+            log::error!("{}", err);
+            process::exit(1);
+        },
+    };
+    let output_format = match arg.output_format.as_str(){
+        "human" => OutputFormat::Human,
+        "csv" => OutputFormat::Csv,
+        "json" => OutputFormat::Json,
+        other => {
+            log::error!("Invalid output format {:?}, expected human, csv or json", other);
+            process::exit(1);
+        },
+    };
+    let block_map_format = match arg.block_map_format.as_str(){
+        "binary" => BlockMapFormat::Binary,
+        "text" => BlockMapFormat::Text,
+        "ranges" => BlockMapFormat::Ranges,
+        other => {
+            log::error!("Invalid block map format {:?}, expected binary, text or ranges", other);
+            process::exit(1);
+        },
+    };
+    if let Some(nice) = arg.nice{
+        match set_nice(nice){
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => log::warn!("Warning: no permission to set nice level {}: {}", nice, err),
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
         }
+    }
+    if let Some(io_priority) = &arg.io_priority{
+        // Values from the config file are not checked by clap
+        let io_priority: IoPriority = match io_priority.parse(){
+            Ok(io_priority) => io_priority,
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        };
+        if let Err(err) = set_io_priority(io_priority){
+            log::warn!("Warning: failed to set I/O priority {}: {}", io_priority, err);
+        }
+    }
+    let src_path = Path::new(arg.src_path.as_deref().unwrap());
+    let dst_paths: Vec<PathBuf> = arg.dst_path.iter().chain(&arg.dest).map(PathBuf::from).collect();
+    if arg.source_block_device_only && !is_block_device(src_path){
+        log::error!("Source {} is not a block device, refusing to sync (--source-block-device-only)", src_path.display());
+        process::exit(4);
+    }
+    if arg.dest_block_device_only{
+        if let Some(dst_path) = dst_paths.iter().find(|dst_path| !is_block_device(dst_path)){
+            log::error!("Destination {} is not a block device, refusing to sync (--dest-block-device-only)", dst_path.display());
+            process::exit(5);
+        }
+    }
+    let exclude_ranges = match &arg.exclude_ranges{
+        Some(exclude_path) => match read_exclude_ranges(Path::new(exclude_path)){
+            Ok(exclude_ranges) => exclude_ranges,
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        },
+        None => Vec::new(),
     };
-
-    if dst_size != src_size && !is_block_device(dst_path){
-        println!("Truncate {:?} from {} to {} bytes", dst_path, dst_size, src_size);
-        unsafe{
-            ftruncate64(dst_file.as_raw_fd(), src_size as i64);
+    let priority_regions = match &arg.priority_regions{
+        Some(priority_path) => match read_priority_regions(Path::new(priority_path)){
+            Ok(priority_regions) => priority_regions,
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+    if arg.benchmark{
+        let opts = SyncOptions::new()
+            .threaded(arg.thread)
+            .buffer_size(1024*1024*arg.buffer_size)
+            .offset(arg.offset);
+        match benchmark_paths(src_path, &dst_paths, opts){
+            Ok(bench) => {
+                for read in std::iter::once(&bench.source).chain(&bench.destinations){
+                    log::info!("Read {}: {} [{:.1} MB] in {:.2}s - {:.1} MB/s", read.path.display(), read.bytes, read.bytes as f64 / 1024. / 1024., read.elapsed.as_secs_f64(), read.rate_mb());
+                }
+                if bench.destination_bottleneck(){
+                    log::info!("Destination read is the likely bottleneck.");
+                }else{
+                    log::info!("Source read is the likely bottleneck.");
+                }
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
         }
-    } else if is_block_device(dst_path) && dst_size < src_size{
-        println!("Destination is a block device and is too small.");
         return;
     }
-
-    let buffer_size: usize = 1024*1024*buffer_size;
-    let block_size: usize = 1024*chunk_size; // Window for writing
-    if !quiet{
-        println!("Buffer size: 2x {} [{:.1} MB]", buffer_size, buffer_size as f64 / 1024. / 1024.);
-        println!("Block size (chunk): 2x {} [{:.1} MB]", block_size, block_size as f64 / 1024. / 1024.);
-    }
-
-    let mut buffer_src = vec![0u8; buffer_size];
-    let mut buffer_dst = vec![0u8; buffer_size];
-    let mut fp: usize = 0;
-    let mut bytes_written: usize = 0;
-    let mut time2display = Instant::now();
-    let start_time = Instant::now();
-
-    if threaded{
-        if !quiet {
-            println!("Threaded - Reading source and destination at the same time.");
-        }
-        let src_file = Arc::new(Mutex::new(src_file));
-        let buffer_src = Arc::new(Mutex::new(buffer_src));
-        let src_len = Arc::new(Mutex::new(0));
-
-        loop{
-            let src_file = Arc::clone(&src_file);
-            let buffer_src1 = Arc::clone(&buffer_src);
-            let buffer_src2 = Arc::clone(&buffer_src);
-            let src_len1 = Arc::clone(&src_len);
-            let src_len2 = Arc::clone(&src_len);
-            let thandle = thread::spawn(move || {
-                let mut src_len = src_len1.lock().unwrap();
-                let mut src_file = src_file.lock().unwrap();
-                let mut buffer_src = buffer_src1.lock().unwrap();
-                *src_len = (*src_file).read(&mut *buffer_src).unwrap();
-            });
-
-            let dst_len = dst_file.read(&mut buffer_dst).unwrap();
-            // Wait thread to finish
-            thandle.join().unwrap();
-
-            let src_len = src_len2.lock().unwrap();
-            let buffer_src = buffer_src2.lock().unwrap();
-            
-            if *src_len == 0 || dst_len == 0{
-                break;
+    if arg.write_barrier{
+        log::warn!("Warning: --write-barrier can reduce write speed by 10-100x, only use it for critical data");
+    }
+    // Ctrl-C or kill stops after the current buffer, final stats are still printed
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM]{
+        signal_hook::flag::register(signal, Arc::clone(&stop_flag)).unwrap();
+    }
+    // The time limit stops the sync the same way
+    let time_limit_reached = Arc::new(AtomicBool::new(false));
+    if let Some(time_limit) = arg.time_limit{
+        let (stop_flag, time_limit_reached) = (Arc::clone(&stop_flag), Arc::clone(&time_limit_reached));
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(time_limit));
+            time_limit_reached.store(true, Ordering::Relaxed);
+            stop_flag.store(true, Ordering::Relaxed);
+        });
+    }
+    let opts = SyncOptions::new()
+        .threaded(arg.thread)
+        .parallel_compare(arg.parallel_compare || arg.threads.is_some())
+        .compare_threads(arg.threads.unwrap_or(0) as usize)
+        .buffer_size(1024*1024*arg.buffer_size)
+        .buffer_count(arg.buffer_count.unwrap_or(1).clamp(1, 8) as usize)
+        .chunk_size(1024*arg.chunk_size)
+        .align(arg.align as usize)
+        .quiet(arg.quiet)
+        .no_progress(arg.no_progress || arg.diff_only_report)
+        .progress_bar(arg.progress_bar)
+        .color(use_color(&arg))
+        .progress_file(arg.progress_file.as_ref().map(PathBuf::from))
+        .progress_interval(Duration::from_secs_f64(arg.progress_interval))
+        .eta_algorithm(eta_algorithm)
+        .io_engine(io_engine)
+        .json(arg.json)
+        .block_size_auto(arg.block_size_auto)
+        .hash_mode(if arg.hash {HashMode::Sha256} else {HashMode::Bytes})
+        .flush_mode(if arg.fsync {FlushMode::Fsync} else if arg.fdatasync {FlushMode::Fdatasync} else {FlushMode::None})
+        .sparse(arg.sparse)
+        .trim(arg.trim)
+        .direct(arg.direct)
+        .read_ahead(arg.read_ahead.map(|read_ahead| 1024*1024*read_ahead))
+        .mmap(arg.mmap)
+        .atomic(arg.atomic)
+        .compression(compression)
+        .encryption(encryption)
+        .dry_run(arg.dry_run)
+        .output_delta(arg.delta_file().map(|(delta_file, _)| PathBuf::from(delta_file)))
+        .delta_compression(arg.delta_file().map_or(FileCompression::None, |(_, compression)| compression))
+        .write_journal(arg.write_journal.as_ref().map(PathBuf::from))
+        .rollback_on_error(arg.rollback_on_error)
+        .checksum_file(arg.checksum_file.as_ref().map(PathBuf::from))
+        .checksum_algorithm(checksum_algorithm)
+        .block_map(arg.block_map.as_ref().map(|block_map| (PathBuf::from(block_map), block_map_format)))
+        .rate_limit(arg.rate_limit.map(|rate| (rate * 1024. * 1024.) as u64))
+        .max_bytes_written(arg.max_bytes_written)
+        .max_diff_ratio(arg.max_diff_ratio)
+        .diff_filter(arg.diff_filter)
+        .iops_limit(arg.limit_writes_per_second)
+        .verify(arg.verify)
+        .write_verify_each(arg.write_verify_each)
+        .write_barrier(arg.write_barrier)
+        .use_sendfile(arg.use_sendfile)
+        .count_differing_bytes(arg.write_amplification_report)
+        .ignore_errors(arg.ignore_errors)
+        .retries(arg.retry)
+        .seed_zeros(arg.seed_zeros)
+        .swap_endian(swap_endian)
+        .truncate_dest(!arg.no_truncate_dest)
+        .metadata(arg.metadata)
+        .create_sparse_dest(arg.create_sparse_dest)
+        .zero_unwritten(arg.zero_unwritten)
+        .checkpoint(arg.resume.as_ref().map(PathBuf::from))
+        .checkpoint_interval(arg.checkpoint_interval)
+        .checkpointed_write(arg.checkpointed_write.map(|segment_size| 1024*1024*segment_size))
+        .stop_flag(Some(Arc::clone(&stop_flag)))
+        .offset(arg.offset)
+        .length(arg.length)
+        .source_offset(arg.source_offset)
+        .exclude_ranges(exclude_ranges)
+        .priority_regions(priority_regions)
+        .seed_from(arg.seed_from.as_ref().map(PathBuf::from));
+    #[cfg(debug_assertions)]
+    let opts = opts.simulate_corruption(arg.simulate_corruption.map(|rate| (rate, arg.simulate_seed)));
+    if arg.stream{
+        let stats = match (src_path == Path::new("-"), dst_paths[0] == Path::new("-")){
+            (true, true) => {
+                log::error!("--stream needs a file or a block device as source or destination, not - for both");
+                process::exit(1);
+            },
+            (false, false) => {
+                log::error!("--stream needs - as source, for stdin, or as destination, for stdout");
+                process::exit(1);
+            },
+            (true, false) => sync_stream(io::stdin().lock(), &dst_paths[0], opts),
+            (false, true) => {
+                // Stdout is the copy, only the log on stderr can be printed
+                if output_format != OutputFormat::Human{
+                    log::error!("--output-format {} prints to stdout, which is the destination with --stream -", arg.output_format);
+                    process::exit(1);
+                }
+                copy_to_stream(src_path, io::stdout().lock(), opts)
+            },
+        };
+        match stats{
+            Ok(stats) => {
+                print_stats(&arg, &stats, output_format);
+                if stats.interrupted{
+                    exit_interrupted(&time_limit_reached, stats.end_offset);
+                }
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if source_compression != FileCompression::None{
+        if dst_paths.len() > 1 || arg.stream || arg.remote.is_some() || arg.watch || arg.lvm_snapshot.is_some() || arg.diff_only_report || arg.tui || arg.benchmark{
+            log::error!("--source-compression {} syncs to one local destination, not with --dest, --stream, --remote, --watch, --lvm-snapshot, --diff-only-report, --tui nor --benchmark", source_compression);
+            process::exit(1);
+        }
+        match sync_compressed(src_path, &dst_paths[0], opts, source_compression){
+            Ok(stats) => {
+                print_stats(&arg, &stats, output_format);
+                if stats.interrupted{
+                    exit_interrupted(&time_limit_reached, stats.end_offset);
+                }
+            },
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if arg.diff_only_report{
+        let stats = match sync_paths_multi(src_path, &dst_paths, opts.dry_run(true)){
+            Ok(stats) => stats,
+            Err(err) => {
+                log::error!("{}", err);
+                process::exit(2);
             }
-            if *src_len != dst_len{
-                println!("Read len are not equal !");
-                break;
+        };
+        if stats.interrupted{
+            log::warn!("Interrupted at offset {}, report incomplete", stats.end_offset);
+            exit_interrupted(&time_limit_reached, stats.end_offset);
+        }
+        let ranges = &stats.destinations[0].written_ranges;
+        for &(offset, len) in ranges{
+            println!("{}-{}", offset, offset + len as u64);
+        }
+        // Dry run only logs that a regular file destination would be truncated or extended
+        let sizes = (fs::metadata(src_path), fs::metadata(&dst_paths[0]));
+        let size_differs = match sizes{
+            (Ok(src), Ok(dst)) if src.is_file() && dst.is_file() && src.len() != dst.len() && arg.offset == 0 && arg.length.is_none() => {
+                log::info!("Sizes differ: {} is {} bytes, {} is {} bytes", src_path.display(), src.len(), dst_paths[0].display(), dst.len());
+                true
+            },
+            _ => false,
+        };
+        if ranges.is_empty() && !size_differs{
+            log::info!("{} and {} are identical", src_path.display(), dst_paths[0].display());
+            return;
+        }
+        log::info!("{} differing regions, {} bytes [{:.1} MB]", ranges.len(), stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+        process::exit(1);
+    }
+    if arg.snapshot_before.is_some() && !is_block_device(&dst_paths[0]){
+        log::error!("--snapshot-before needs a block device destination, {} is not", dst_paths[0].display());
+        process::exit(1);
+    }
+    if arg.destination_device_check && !arg.dry_run{
+        for dst_path in &dst_paths{
+            let message = match is_mounted(dst_path){
+                Ok(true) => format!("Destination {} is mounted, writing to it would corrupt the mounted filesystem", dst_path.display()),
+                Ok(false) => continue,
+                // A destination to create does not exist yet
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => format!("Cannot check if destination {} is mounted: {}", dst_path.display(), err),
+            };
+            if arg.force{
+                log::warn!("Warning: {}, syncing anyway (--force)", message);
+            }else{
+                log::error!("{}, use --force to sync anyway", message);
+                process::exit(2);
             }
-            if *buffer_src != buffer_dst{
-                let mut block_start_pos = 0;
-                let mut block_pos = 0;
-                let mut current_block_differ = false;
-                loop{
-                    let mut block_size = block_size;
-                    if block_size + block_pos > *src_len{
-                        block_size = *src_len - block_pos;
-                        if block_size <= 0{
-                            if current_block_differ{
-                                dst_file.write_at(&buffer_src[block_start_pos .. block_pos], fp as u64 + block_start_pos as u64).unwrap();
-                                bytes_written += block_pos - block_start_pos;
-                            }
-                            break;
-                        }
-                    }
-                    let next_block_pos = block_pos + block_size;
-                    if buffer_src[block_pos .. next_block_pos] != buffer_dst[block_pos .. next_block_pos]{
-                        if !current_block_differ{
-                            block_start_pos = block_pos;
-                            current_block_differ = true;
-                        }
-                    }else{
-                        if current_block_differ{
-                            dst_file.write_at(&buffer_src[block_start_pos .. block_pos], fp as u64 + block_start_pos as u64).unwrap();
-                            bytes_written += block_pos - block_start_pos;
-                            current_block_differ = false;
-                        }
-                    }
-                    block_pos = next_block_pos;
+        }
+    }
+    if arg.timestamp_check{
+        for dst_path in &dst_paths{
+            if let Some(message) = newer_destination(src_path, dst_path){
+                if arg.force{
+                    log::warn!("Warning: {}, syncing anyway (--force)", message);
+                }else{
+                    log::error!("{}, use --force to sync anyway", message);
+                    process::exit(2);
                 }
             }
-            fp += *src_len;
-            if !quiet && time2display.elapsed().as_secs() > 2{
-                display_progress(fp as f64, src_size as f64, start_time);
-                time2display = Instant::now();
+        }
+    }
+    let mut metrics_server = match &arg.stats_prometheus{
+        Some(addr) => match MetricsServer::start(addr){
+            Ok(metrics_server) => Some(metrics_server),
+            Err(err) => {
+                log::error!("Failed to serve Prometheus metrics on {}: {}", addr, err);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let opts = match &metrics_server{
+        Some(metrics_server) => opts.events(Some(metrics_server.events())),
+        None => opts,
+    };
+    // Watch before the first sync, so changes made during a sync trigger the next one
+    let mut watcher = if arg.watch{
+        match SourceWatcher::new(src_path){
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                log::error!("Failed to watch {}: {}", src_path.display(), err);
+                process::exit(1);
             }
         }
     }else{
-        loop{
-            let src_len = src_file.read(&mut buffer_src).unwrap();
-            let dst_len = dst_file.read(&mut buffer_dst).unwrap();
-            if src_len == 0 || dst_len == 0{
-                break;
+        None
+    };
+    loop{
+        if let Some(pre_sync_hook) = &arg.pre_sync_hook{
+            if let Err(err) = run_hook("pre-sync hook", pre_sync_hook, &[]){
+                log::error!("{}, sync aborted", err);
+                process::exit(1);
+            }
+        }
+        let sync_start = Instant::now();
+        let snapshot = match arg.lvm_snapshot.as_ref().and_then(|lvm_snapshot| lvm_snapshot.split_once('/')){
+            Some((vg, lv)) => match LvmSnapshot::create(vg, lv, &arg.lvm_snapshot_size){
+                Ok(snapshot) => Some(snapshot),
+                Err(err) => {
+                    log::error!("Failed to snapshot {}/{}: {}", vg, lv, err);
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let source = snapshot.as_ref().map_or(src_path, |snapshot| snapshot.path());
+        let dst_snapshot = match &arg.snapshot_before{
+            Some(cow) => match DmSnapshot::create(&dst_paths[0], Path::new(cow)){
+                Ok(dst_snapshot) => Some(dst_snapshot),
+                Err(err) => {
+                    log::error!("Failed to snapshot {} on {}: {}", dst_paths[0].display(), cow, err);
+                    // process::exit skips destructors, the LVM snapshot of the source is removed on drop
+                    drop(snapshot);
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let dst_paths = match &dst_snapshot{
+            Some(dst_snapshot) => vec![dst_snapshot.path().to_path_buf()],
+            None => dst_paths.clone(),
+        };
+        let result = match tui_logs.take(){
+            Some(logs) => {
+                let title = format!("localblocksync {} to {}", source.display(), arg.remote.clone().unwrap_or_else(|| dst_paths.iter().map(|dst_path| dst_path.display().to_string()).collect::<Vec<_>>().join(", ")));
+                let (source, dst_paths, remote) = (source.to_path_buf(), dst_paths.clone(), arg.remote.clone());
+                tui::run(title, opts.clone(), logs, move |opts| match &remote{
+                    Some(addr) => sync_remote(&source, addr, opts),
+                    None => sync_paths_multi(&source, &dst_paths, opts),
+                })
+            },
+            None => match &arg.remote{
+                Some(addr) => sync_remote(source, addr, opts.clone()),
+                None => sync_paths_multi(source, &dst_paths, opts.clone()),
+            },
+        };
+        // Removed before the post-sync hook, and explicitly as process::exit below skips destructors
+        drop(snapshot);
+        let mut result = result;
+        if let Some(dst_snapshot) = dst_snapshot{
+            let snapshot_path = dst_snapshot.path().display().to_string();
+            let snapshot_result = match &result{
+                Ok(stats) if !stats.interrupted && arg.snapshot_merge => dst_snapshot.merge(),
+                Ok(stats) if !stats.interrupted => {
+                    log::info!("Synced to snapshot {}, the destination is unchanged until it is merged", snapshot_path);
+                    Ok(())
+                },
+                _ => dst_snapshot.remove(),
+            };
+            match (snapshot_result, &result){
+                (Err(err), Ok(_)) => result = Err(SyncError::Io(io::Error::new(err.kind(), format!("snapshot {}: {}", snapshot_path, err)))),
+                // The sync error is reported below
+                (Err(err), Err(_)) => log::error!("Snapshot {}: {}", snapshot_path, err),
+                (Ok(()), _) => (),
+            }
+        }
+        // Run even if the sync failed, e.g. to resume what the pre-sync hook paused
+        let mut hook_failed = false;
+        if let Some(post_sync_hook) = &arg.post_sync_hook{
+            let (bytes_written, sync_result) = match &result{
+                Ok(stats) if stats.interrupted => (stats.bytes_written, "interrupted"),
+                Ok(stats) => (stats.bytes_written, "ok"),
+                Err(_) => (0, "failed"),
+            };
+            let envs = [
+                ("BYTES_WRITTEN", bytes_written.to_string()),
+                ("ELAPSED_SECS", format!("{:.3}", sync_start.elapsed().as_secs_f64())),
+                ("SYNC_RESULT", sync_result.to_string()),
+            ];
+            if let Err(err) = run_hook("post-sync hook", post_sync_hook, &envs){
+                log::error!("{}", err);
+                hook_failed = true;
+            }
+        }
+        let stats = match result{
+            Ok(stats) => stats,
+            Err(err) => {
+                if arg.json{
+                    println!("{}", serde_json::json!({"type": "error", "message": err.to_string()}));
+                }else{
+                    log::error!("{}", err);
+                }
+                process::exit(if matches!(err, SyncError::DiffRatioExceeded { .. }) {3} else {1});
+            }
+        };
+        print_stats(&arg, &stats, output_format);
+        if stats.interrupted{
+            exit_interrupted(&time_limit_reached, stats.end_offset);
+        }
+        let failed = stats.destinations.iter().any(|dest| dest.error.is_some()) || !stats.error_log.entries.is_empty() || hook_failed;
+        let Some(watcher) = &mut watcher else {
+            if let Some(metrics_server) = metrics_server.take(){
+                metrics_server.shutdown();
             }
-            if src_len != dst_len{
-                println!("Read len are not equal !");
-                break;
+            if failed{
+                process::exit(1);
             }
-            if buffer_src != buffer_dst{
-                dst_file.write_at(&buffer_src[0 .. src_len], fp as u64).unwrap();
-                bytes_written += src_len;
+            return;
+        };
+        log::info!("Watching {} for changes, Ctrl-C to stop", src_path.display());
+        match watcher.wait_for_change(Duration::from_millis(arg.watch_debounce), &stop_flag){
+            Ok(true) => log::info!("{} changed, syncing again", src_path.display()),
+            Ok(false) => process::exit(if time_limit_reached.load(Ordering::Relaxed) {6} else {130}),
+            Err(err) => {
+                log::error!("Failed to watch {}: {}", src_path.display(), err);
+                process::exit(1);
             }
-            fp += src_len;
-            if !quiet && time2display.elapsed().as_secs() > 2{
-                    display_progress(fp as f64, src_size as f64, start_time);
-                time2display = Instant::now();
+        }
+    }
+}
+
+/// Tell why if dst_path is a regular file modified after src_path, block devices have no meaningful modification time
+fn newer_destination(src_path: &Path, dst_path: &Path) -> Option<String> {
+    let (src, dst) = (fs::metadata(src_path).ok()?, fs::metadata(dst_path).ok()?);
+    if !src.is_file() || !dst.is_file(){
+        return None;
+    }
+    let (src_modified, dst_modified) = (src.modified().ok()?, dst.modified().ok()?);
+    let newer = dst_modified.duration_since(src_modified).ok().filter(|newer| !newer.is_zero())?;
+    Some(format!("Destination {} was modified {:.0}s after source {}", dst_path.display(), newer.as_secs_f64(), src_path.display()))
+}
+
+/// Read an --encrypt key file, exit if it is not valid
+fn read_key(path: &str) -> EncryptionKey {
+    match EncryptionKey::read(Path::new(path)){
+        Ok(key) => key,
+        Err(err) => {
+            log::error!("Failed to read key file {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Exit after an interrupted sync: with code 6 and where to continue from if the time limit was reached, else 130
+fn exit_interrupted(time_limit_reached: &AtomicBool, end_offset: u64) -> ! {
+    if time_limit_reached.load(Ordering::Relaxed){
+        log::warn!("Time limit reached, continue with --offset {}", end_offset);
+        process::exit(6);
+    }
+    process::exit(130);
+}
+
+/// Run a hook shell command with sh -c, its stdin, stdout and stderr are ours. Return why if it does not succeed.
+fn run_hook(name: &str, command: &str, envs: &[(&str, String)]) -> Result<(), String> {
+    log::info!("Running {}: {}", name, command);
+    match process::Command::new("sh").arg("-c").arg(command).envs(envs.iter().map(|(key, value)| (key, value))).status(){
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} {:?} failed: {}", name, command, status)),
+        Err(err) => Err(format!("Failed to run {} {:?}: {}", name, command, err)),
+    }
+}
+
+/// Final stats of a sync as a JSON object
+fn stats_json(stats: &SyncStats) -> serde_json::Value {
+    serde_json::json!({
+        "bytes_written": stats.bytes_written,
+        "bytes_compared": stats.bytes_compared,
+        "bytes_skipped": stats.bytes_skipped,
+        "bytes_seeded": stats.bytes_seeded,
+        "bytes_sent": stats.bytes_sent,
+        "bytes_would_write_minimal": stats.bytes_would_write_minimal,
+        "metadata_differences": stats.metadata_differences,
+        "metadata_applied": stats.metadata_applied,
+        "elapsed_secs": stats.elapsed.as_secs_f64(),
+        "write_elapsed_secs": stats.write_elapsed.as_secs_f64(),
+        "flush_elapsed_secs": stats.flush_elapsed.as_secs_f64(),
+        "dry_run": stats.dry_run,
+        "interrupted": stats.interrupted,
+        "end_offset": stats.end_offset,
+        "verified_regions": stats.verified_regions,
+        "verify_elapsed_secs": stats.verify_elapsed.as_secs_f64(),
+        "chunks_identical": stats.chunks_identical,
+        "chunks_different": stats.chunks_different,
+        "write_calls": stats.write_calls,
+        "write_sizes": stats.write_sizes.iter().map(|(size, count)| serde_json::json!({"size": size, "count": count})).collect::<Vec<_>>(),
+        "io_errors": stats.error_log.entries.iter().map(|(offset, message)| serde_json::json!({"offset": offset, "message": message})).collect::<Vec<_>>(),
+        "destinations": stats.destinations.iter().map(|dest| serde_json::json!({
+            "path": dest.path,
+            "bytes_written": dest.bytes_written,
+            "error": dest.error,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// Print the final stats of a sync, as a JSON done object with --json, else in output_format
+fn print_stats(arg: &SyncArgs, stats: &SyncStats, output_format: OutputFormat){
+    let failed = stats.destinations.iter().any(|dest| dest.error.is_some());
+    if arg.json{
+        let mut done = stats_json(stats);
+        done["type"] = "done".into();
+        println!("{}", done);
+        return;
+    }
+    match output_format{
+        OutputFormat::Human => (),
+        OutputFormat::Csv => {
+            println!("{:.3},{},{}", stats.elapsed.as_secs_f64(), stats.bytes_written, stats.bytes_compared);
+            return;
+        },
+        OutputFormat::Json => {
+            println!("{}", stats_json(stats));
+            return;
+        },
+    }
+    if stats.interrupted{
+        log::warn!("Interrupted at offset {} after {} bytes [{:.1} MB]", stats.end_offset, stats.bytes_compared, stats.bytes_compared as f64 / 1024. / 1024.);
+    }
+    log::info!("Elapsed time: {:.2}s", stats.elapsed.as_secs_f64());
+    if (arg.fsync || arg.fdatasync) && !stats.dry_run && arg.delta_file().is_none(){
+        log::info!("Flush ({}): {:.2}s", if arg.fsync {"fsync"} else {"fdatasync"}, stats.flush_elapsed.as_secs_f64());
+    }
+    if stats.dry_run{
+        log::info!("DRY RUN: would write {} bytes [{:.1} MB]", stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+    }else if let Some((delta_file, _)) = arg.delta_file(){
+        log::info!("Total bytes written to delta {}: {} [{:.1} MB]", delta_file, stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+    }else{
+        log::info!("Total bytes written: {} [{:.1} MB]", stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+    }
+    if stats.bytes_seeded > 0{
+        log::info!("Seeded from base image: {} [{:.1} MB]", stats.bytes_seeded, stats.bytes_seeded as f64 / 1024. / 1024.);
+    }
+    if arg.compress.is_some() && !stats.dry_run{
+        log::info!("Sent {} [{:.1} MB] compressed - ratio {:.2}", stats.bytes_sent, stats.bytes_sent as f64 / 1024. / 1024., stats.bytes_written as f64 / stats.bytes_sent.max(1) as f64);
+    }
+    if stats.bytes_skipped > 0{
+        log::info!("Skipped (excluded ranges): {} [{:.1} MB]", stats.bytes_skipped, stats.bytes_skipped as f64 / 1024. / 1024.);
+    }
+    if arg.verify && stats.verified_regions > 0{
+        // The written regions of the destinations that did not fail are read back
+        let bytes_verified: u64 = stats.destinations.iter().filter(|dest| dest.error.is_none()).map(|dest| dest.bytes_written).sum();
+        let verify_secs = stats.verify_elapsed.as_secs_f64();
+        log::info!("Verify: {:.2}s - {} [{:.1} MB] read - {:.1} MB/s", verify_secs, bytes_verified, bytes_verified as f64 / 1024. / 1024., bytes_verified as f64 / verify_secs.max(0.001) / 1024. / 1024.);
+    }
+    if arg.metadata{
+        log::info!("Metadata differences: {} found, {} applied", stats.metadata_differences, stats.metadata_applied);
+    }
+    if stats.destinations.len() > 1{
+        for dest in &stats.destinations{
+            match &dest.error{
+                Some(err) => log::error!("  {}: FAILED - {}", dest.path.display(), err),
+                None => log::info!("  {}: {} [{:.1} MB]", dest.path.display(), dest.bytes_written, dest.bytes_written as f64 / 1024. / 1024.),
             }
         }
     }
-    if !quiet{
-        println!(""); // To skip line after display_progress
+    if let Some(rate_limit) = arg.rate_limit{
+        log::info!("Rate limit: {:.1} MB/s - Average write rate: {:.1} MB/s", rate_limit, stats.bytes_written as f64 / stats.elapsed.as_secs_f64().max(0.001) / 1024. / 1024.);
+    }
+    if let Some(iops_limit) = arg.limit_writes_per_second{
+        log::info!("Write calls limit: {}/s - Average: {:.1}/s", iops_limit, stats.write_calls as f64 / stats.elapsed.as_secs_f64().max(0.001));
+    }
+    if arg.stats_detail{
+        let chunks = stats.chunks_identical + stats.chunks_different;
+        log::info!("Chunks identical: {:>12}", stats.chunks_identical);
+        log::info!("Chunks different: {:>12} ({:.2}% of compared chunks)", stats.chunks_different, stats.chunks_different as f64 * 100. / chunks.max(1) as f64);
+        log::info!("Write calls:      {:>12}", stats.write_calls);
+        let read_elapsed = stats.elapsed.saturating_sub(stats.write_elapsed).as_secs_f64();
+        log::info!("Read and compare: {:>11.2}s ({}) - {:.1} MB/s", read_elapsed, if arg.mmap {"mmap"} else {"read"}, stats.bytes_compared as f64 / read_elapsed.max(0.001) / 1024. / 1024.);
+        log::info!("Write:            {:>11.2}s", stats.write_elapsed.as_secs_f64());
+        log::info!("Write size histogram:");
+        log::info!("  {:>12} {:>10} {:>12}", "Size", "[KB]", "Writes");
+        for (size, count) in &stats.write_sizes{
+            log::info!("  {:>12} {:>10.1} {:>12}", size, *size as f64 / 1024., count);
+        }
+    }
+    if arg.write_amplification_report{
+        let minimal = stats.bytes_would_write_minimal;
+        log::info!("Write amplification:");
+        log::info!("  Minimal (differing bytes): {:>14} [{:.1} MB]", minimal, minimal as f64 / 1024. / 1024.);
+        log::info!("  Written:                   {:>14} [{:.1} MB]", stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024.);
+        if minimal > 0{
+            log::info!("  Ratio:                     {:>14.2}", stats.bytes_written as f64 / minimal as f64);
+        }
+    }
+    if arg.print_block_map_ascii{
+        let chunk_size = 1024 * arg.chunk_size as u64;
+        let states = chunk_states(stats, arg.offset, stats.end_offset, chunk_size);
+        let width = terminal::size().map_or(80, |(columns, _)| columns as usize);
+        let per_char = chunks_per_char(states.len(), width);
+        log::info!("Block map, {} chunks of {} [{:.1} KB] per character: . identical, # written, E error", per_char, chunk_size, chunk_size as f64 / 1024.);
+        println!("{}", render_chunk_states(&states, width));
+    }
+    if !stats.error_log.entries.is_empty(){
+        log::error!("{} I/O errors skipped, the first at offset {}", stats.error_log.entries.len(), stats.error_log.entries[0].0);
+    }
+    if arg.verify && !failed{
+        log::info!("Verify OK");
     }
-    println!("Elapsed time: {:.2}s", start_time.elapsed().as_secs());
-    println!("Total bytes written: {} [{:.1} MB]", bytes_written, bytes_written as f64 / 1024. / 1024.);
 }
@@ -0,0 +1,109 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Sync of file permissions, ownership and extended attributes of a regular file destination
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use nix::errno::Errno;
+use nix::sys::stat::{fchmod, fstat, Mode};
+use nix::unistd::{fchown, Gid, Uid};
+use xattr::FileExt;
+
+/// Metadata differences between source and destination, found and applied
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetadataChanges {
+    pub differences: u64,
+    pub applied: u64,
+}
+
+impl MetadataChanges {
+    /// Count a difference, apply it with op unless dry_run. Not permitted, like chown without root, only warns.
+    fn apply<F: FnOnce() -> io::Result<()>>(&mut self, what: &str, dst_path: &Path, dry_run: bool, op: F) -> io::Result<()> {
+        self.differences += 1;
+        if dry_run{
+            log::info!("Would sync {} of {}", what, dst_path.display());
+            return Ok(());
+        }
+        match op(){
+            Ok(()) => {
+                log::debug!("Synced {} of {}", what, dst_path.display());
+                self.applied += 1;
+                Ok(())
+            },
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                log::warn!("Warning: cannot sync {} of {}: {}", what, dst_path.display(), err);
+                Ok(())
+            },
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn xattrs(file: &File) -> io::Result<BTreeMap<OsString, Vec<u8>>> {
+    let mut attrs = BTreeMap::new();
+    for name in file.list_xattr()?{
+        if let Some(value) = file.get_xattr(&name)?{
+            attrs.insert(name, value);
+        }
+    }
+    Ok(attrs)
+}
+
+/// Copy the permissions, the ownership (needs root) and the extended attributes of src_path to dst_path,
+/// extended attributes not in the source are removed. With dry_run only count the differences.
+pub fn sync_metadata(src_path: &Path, dst_path: &Path, dry_run: bool) -> io::Result<MetadataChanges> {
+    let src_file = File::open(src_path)?;
+    let dst_file = File::open(dst_path)?;
+    let src_stat = fstat(src_file.as_raw_fd())?;
+    let dst_stat = fstat(dst_file.as_raw_fd())?;
+    let mut changes = MetadataChanges::default();
+    // Ownership first, chown clears the setuid and setgid bits
+    if (src_stat.st_uid, src_stat.st_gid) != (dst_stat.st_uid, dst_stat.st_gid){
+        changes.apply("ownership", dst_path, dry_run, || {
+            fchown(dst_file.as_raw_fd(), Some(Uid::from_raw(src_stat.st_uid)), Some(Gid::from_raw(src_stat.st_gid))).map_err(io::Error::from)
+        })?;
+    }
+    let src_mode = src_stat.st_mode & 0o7777;
+    if src_mode != fstat(dst_file.as_raw_fd())?.st_mode & 0o7777{
+        changes.apply("permissions", dst_path, dry_run, || {
+            fchmod(dst_file.as_raw_fd(), Mode::from_bits_truncate(src_mode)).map_err(io::Error::from)
+        })?;
+    }
+    let src_attrs = match xattrs(&src_file){
+        Ok(attrs) => attrs,
+        // Filesystem without extended attributes
+        Err(err) if err.raw_os_error() == Some(Errno::EOPNOTSUPP as i32) => return Ok(changes),
+        Err(err) => return Err(err),
+    };
+    let dst_attrs = match xattrs(&dst_file){
+        Ok(attrs) => attrs,
+        Err(err) if err.raw_os_error() == Some(Errno::EOPNOTSUPP as i32) && src_attrs.is_empty() => return Ok(changes),
+        Err(err) => return Err(err),
+    };
+    for (name, value) in &src_attrs{
+        if dst_attrs.get(name) != Some(value){
+            let what = format!("extended attribute {}", name.to_string_lossy());
+            changes.apply(&what, dst_path, dry_run, || dst_file.set_xattr(name, value))?;
+        }
+    }
+    for name in dst_attrs.keys().filter(|name| !src_attrs.contains_key(*name)){
+        let what = format!("extra extended attribute {}", name.to_string_lossy());
+        changes.apply(&what, dst_path, dry_run, || dst_file.remove_xattr(name))?;
+    }
+    Ok(changes)
+}
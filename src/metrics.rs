@@ -0,0 +1,145 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Prometheus metrics of the sync, served over HTTP in the text format by a background thread.
+use std::io::{self, prelude::*};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use prometheus::{Encoder, Gauge, IntCounter, Registry, TextEncoder};
+use crate::SyncEvent;
+
+/// Metrics updated from the progress events of the syncs
+#[derive(Clone)]
+struct Metrics {
+    registry: Registry,
+    bytes_compared: IntCounter,
+    bytes_written: IntCounter,
+    progress: Gauge,
+    speed: Gauge,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let bytes_compared = IntCounter::new("localblocksync_bytes_compared_total", "Bytes read and compared from the source")?;
+        let bytes_written = IntCounter::new("localblocksync_bytes_written_total", "Bytes written to the destinations")?;
+        let progress = Gauge::new("localblocksync_progress_ratio", "Part of the current sync range done, 0 to 1")?;
+        let speed = Gauge::new("localblocksync_speed_bytes_per_second", "Compare speed since the previous progress update")?;
+        registry.register(Box::new(bytes_compared.clone()))?;
+        registry.register(Box::new(bytes_written.clone()))?;
+        registry.register(Box::new(progress.clone()))?;
+        registry.register(Box::new(speed.clone()))?;
+        Ok(Metrics { registry, bytes_compared, bytes_written, progress, speed })
+    }
+
+    /// Metrics in the Prometheus text format
+    fn encode(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut text);
+        text
+    }
+}
+
+/// HTTP server of the metrics, stopped by shutdown
+pub struct MetricsServer {
+    metrics: Metrics,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Listen on addr, e.g. 127.0.0.1:9101, and serve the metrics to every request in a background thread
+    pub fn start(addr: &str) -> io::Result<Self> {
+        let metrics = Metrics::new().map_err(io::Error::other)?;
+        let listener = TcpListener::bind(addr)?;
+        // Not blocking, to check the stop flag between connections
+        listener.set_nonblocking(true)?;
+        log::info!("Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (metrics, stop) = (metrics.clone(), Arc::clone(&stop));
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed){
+                    match listener.accept(){
+                        Ok((stream, _)) => {
+                            if let Err(err) = respond(stream, &metrics){
+                                log::debug!("Metrics request failed: {}", err);
+                            }
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+                        Err(err) => log::debug!("Metrics connection failed: {}", err),
+                    }
+                }
+            })
+        };
+        Ok(MetricsServer { metrics, stop, thread: Some(thread) })
+    }
+
+    /// Channel to give to SyncOptions::events, the metrics are updated from the progress events sent to it
+    pub fn events(&self) -> Sender<SyncEvent> {
+        let (sender, receiver) = mpsc::channel();
+        let metrics = self.metrics.clone();
+        thread::spawn(move || {
+            // Totals of the current sync, they restart from 0 for the next one in watch mode
+            let (mut compared, mut written) = (0, 0);
+            let mut last_update = Instant::now();
+            for event in receiver{
+                if let SyncEvent::Progress { offset, start, end, bytes_compared, bytes_written, .. } = event{
+                    if bytes_compared < compared || bytes_written < written{
+                        (compared, written) = (0, 0);
+                    }
+                    let elapsed = last_update.elapsed().as_secs_f64().max(0.001);
+                    metrics.speed.set((bytes_compared - compared) as f64 / elapsed);
+                    metrics.bytes_compared.inc_by(bytes_compared - compared);
+                    metrics.bytes_written.inc_by(bytes_written - written);
+                    metrics.progress.set(if end > start {(offset - start) as f64 / (end - start) as f64} else {1.});
+                    (compared, written) = (bytes_compared, bytes_written);
+                    last_update = Instant::now();
+                }
+            }
+            metrics.speed.set(0.);
+        });
+        sender
+    }
+
+    /// Stop the server and wait for its thread
+    pub fn shutdown(mut self){
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take(){
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer one HTTP request with the metrics, whatever its path
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // Read the request headers, up to the empty line
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 64 * 1024{
+        match stream.read(&mut buf)?{
+            0 => break,
+            n => request.extend_from_slice(&buf[.. n]),
+        }
+    }
+    let body = metrics.encode();
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())?;
+    stream.write_all(&body)
+}
@@ -0,0 +1,145 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Read-ahead pipeline: a reader thread fills the next buffers of the source and first destination
+//! while the sync loop compares and writes the current ones.
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use crate::buffer_pool::{acquire_buffer, BufferGuard, BufferPool};
+use crate::exclude::{self, ExcludeRange};
+use crate::{clear_direct, read_full_at, retry_io, DIRECT_ALIGN};
+
+/// Source and destination buffers read at file offset fp, with their read results
+struct ReadBlock {
+    fp: usize,
+    src: BufferGuard,
+    dst: BufferGuard,
+    src_read: io::Result<usize>,
+    dst_read: io::Result<usize>,
+}
+
+/// What the reader thread reads, the same regions as the sync loop walks through
+pub(crate) struct ReadPlan {
+    pub fp: usize,
+    pub end: u64,
+    pub buffer_size: usize,
+    /// Read the destination with each source buffer, not when it is read later chunk by chunk
+    pub read_dst: bool,
+    pub exclude_ranges: Vec<ExcludeRange>,
+    pub direct: bool,
+    pub threaded: bool,
+    /// Retries of a failed read
+    pub retries: u32,
+    /// Position in the source of the destination start
+    pub source_offset: u64,
+}
+
+/// Reader thread and the channels to pass buffers back and forth, buffer_count buffers in total
+pub(crate) struct ReadPipeline {
+    filled: Option<Receiver<ReadBlock>>,
+    empty: Option<SyncSender<(BufferGuard, BufferGuard)>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl ReadPipeline {
+    /// Start reading src_file and dst_file ahead, into buffer_count - 1 buffers the size of buffer_src and buffer_dst.
+    /// The sync loop holds the last one. They are taken from pool if there is one.
+    pub fn start(src_file: &File, dst_file: &File, buffer_src: &[u8], buffer_dst: &[u8], buffer_count: usize, pool: Option<&Arc<BufferPool>>, plan: ReadPlan) -> io::Result<ReadPipeline> {
+        let src_file = src_file.try_clone()?;
+        let dst_file = dst_file.try_clone()?;
+        let (filled_tx, filled_rx) = sync_channel(buffer_count);
+        let (empty_tx, empty_rx) = sync_channel(buffer_count);
+        for _ in 1 .. buffer_count{
+            empty_tx.send((acquire_buffer(pool, buffer_src.len()), acquire_buffer(pool, buffer_dst.len()))).unwrap();
+        }
+        let reader = thread::spawn(move || read_ahead(&src_file, &dst_file, plan, empty_rx, filled_tx));
+        Ok(ReadPipeline { filled: Some(filled_rx), empty: Some(empty_tx), reader: Some(reader) })
+    }
+
+    /// Swap buffer_src and buffer_dst with the next filled buffers, which must be the ones at file offset fp,
+    /// and return their read results. The previous buffers are given back to the reader.
+    pub fn next(&mut self, fp: usize, buffer_src: &mut BufferGuard, buffer_dst: &mut BufferGuard) -> (io::Result<usize>, io::Result<usize>) {
+        let block = match self.filled.as_ref().unwrap().recv(){
+            Ok(block) => block,
+            // The reader only stops by itself after a block the sync loop stops at, so it panicked
+            Err(_) => return (Err(io::Error::other("read pipeline thread stopped")), Ok(0)),
+        };
+        if block.fp != fp{
+            let message = format!("read pipeline at offset {} out of step with the sync at offset {}", block.fp, fp);
+            return (Err(io::Error::other(message)), Ok(0));
+        }
+        let src = std::mem::replace(buffer_src, block.src);
+        let dst = std::mem::replace(buffer_dst, block.dst);
+        // The reader may be done, then it does not need them
+        let _ = self.empty.as_ref().unwrap().send((src, dst));
+        (block.src_read, block.dst_read)
+    }
+}
+
+impl Drop for ReadPipeline {
+    fn drop(&mut self){
+        // Closing the channels stops the reader if it is still reading ahead
+        self.filled.take();
+        self.empty.take();
+        if let Some(reader) = self.reader.take(){
+            let _ = reader.join();
+        }
+    }
+}
+
+/// Reader thread: read the planned regions in order into the empty buffers and send them filled, until the end,
+/// a read error or the pipeline is dropped
+fn read_ahead(src_file: &File, dst_file: &File, plan: ReadPlan, empty: Receiver<(BufferGuard, BufferGuard)>, filled: SyncSender<ReadBlock>) {
+    let ReadPlan { mut fp, end, buffer_size, read_dst, exclude_ranges, mut direct, threaded, retries, source_offset } = plan;
+    loop{
+        if let Some(range) = exclude::range_at(fp as u64, &exclude_ranges){
+            fp = range.end.min(end) as usize;
+            continue;
+        }
+        let read_end = exclude::next_start(fp as u64, &exclude_ranges).map_or(end, |start| start.min(end));
+        let read_size = buffer_size.min((read_end - fp as u64) as usize);
+        if read_size == 0{
+            break;
+        }
+        if direct && (!fp.is_multiple_of(DIRECT_ALIGN) || !read_size.is_multiple_of(DIRECT_ALIGN)){
+            // Same as the sync loop, which gets there later
+            let _ = clear_direct(src_file).and_then(|_| clear_direct(dst_file));
+            direct = false;
+        }
+        let Ok((mut src, mut dst)) = empty.recv() else {
+            break;
+        };
+        let dst_len = if read_dst {read_size} else {0};
+        let (src_read, dst_read) = if threaded{
+            thread::scope(|scope| {
+                let src_buf = &mut src[0 .. read_size];
+                let thandle = scope.spawn(|| retry_io(|| read_full_at(src_file, src_buf, source_offset + fp as u64), retries));
+                let dst_read = retry_io(|| read_full_at(dst_file, &mut dst[0 .. dst_len], fp as u64), retries);
+                let src_read = thandle.join().unwrap_or_else(|_| Err(io::Error::other("source read thread panicked")));
+                (src_read, dst_read)
+            })
+        }else{
+            (retry_io(|| read_full_at(src_file, &mut src[0 .. read_size], source_offset + fp as u64), retries), retry_io(|| read_full_at(dst_file, &mut dst[0 .. dst_len], fp as u64), retries))
+        };
+        // The sync loop stops at the end of the source or on an error
+        let src_len = *src_read.as_ref().unwrap_or(&0);
+        if filled.send(ReadBlock { fp, src, dst, src_read, dst_read }).is_err() || src_len == 0{
+            break;
+        }
+        fp += src_len;
+    }
+}
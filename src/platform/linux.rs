@@ -0,0 +1,46 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use nix::ioctl_read;
+use nix::libc::ftruncate64;
+
+// Generate ioctl function
+const BLKGETSIZE64_CODE: u8 = 0x12; // Defined in linux/fs.h
+const BLKGETSIZE64_SEQ: u8 = 114;
+ioctl_read!(ioctl_blkgetsize64, BLKGETSIZE64_CODE, BLKGETSIZE64_SEQ, u64); // Define function ioctl_blkgetsize64
+
+/// Determine block device size, path is a &str or a &Path
+pub fn get_device_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let file = OpenOptions::new().read(true).open(path)?;
+    let mut cap = 0u64;
+    unsafe {
+        ioctl_blkgetsize64(file.as_raw_fd(), &mut cap)?;
+    }
+    Ok(cap)
+}
+
+/// Set the size of the regular file open as fd, extended with zeros or cut
+pub fn truncate_file(fd: RawFd, size: u64) -> io::Result<()> {
+    let ret = unsafe{
+        ftruncate64(fd, size as i64)
+    };
+    if ret != 0{
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
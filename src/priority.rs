@@ -0,0 +1,105 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Scheduling priority of the sync process, so background syncs do not slow down other programs.
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use nix::errno::Errno;
+use nix::libc;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_CLASS_RT: u32 = 1;
+const IOPRIO_CLASS_BE: u32 = 2;
+const IOPRIO_CLASS_IDLE: u32 = 3;
+
+/// Linux I/O scheduling class and level, level 0 is the highest priority and 7 the lowest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Only gets disk time when no other program needs it
+    Idle,
+    /// Default class, shares disk time by level
+    BestEffort(u8),
+    /// Always served first, needs root
+    Realtime(u8),
+}
+
+impl IoPriority {
+    /// Value of ioprio_set: class in the high bits, level in the low bits
+    fn ioprio_value(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoPriority::Idle => (IOPRIO_CLASS_IDLE, 0),
+            IoPriority::BestEffort(level) => (IOPRIO_CLASS_BE, level),
+            IoPriority::Realtime(level) => (IOPRIO_CLASS_RT, level),
+        };
+        ((class << IOPRIO_CLASS_SHIFT) | level as u32) as libc::c_int
+    }
+}
+
+/// Parse `idle`, `best-effort:<0-7>` or `realtime:<0-7>`
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':'){
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        let level = match level.map(str::parse::<u8>){
+            None => 4, // Default level of the kernel
+            Some(Ok(level)) if level <= 7 => level,
+            Some(_) => return Err(format!("invalid I/O priority level in {:?}, expected 0 to 7", s)),
+        };
+        match class{
+            "idle" => Ok(IoPriority::Idle),
+            "best-effort" | "be" => Ok(IoPriority::BestEffort(level)),
+            "realtime" | "rt" => Ok(IoPriority::Realtime(level)),
+            _ => Err(format!("invalid I/O priority {:?}, expected idle, best-effort:<0-7> or realtime:<0-7>", s)),
+        }
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoPriority::Idle => write!(f, "idle"),
+            IoPriority::BestEffort(level) => write!(f, "best-effort:{}", level),
+            IoPriority::Realtime(level) => write!(f, "realtime:{}", level),
+        }
+    }
+}
+
+/// Set the I/O priority of the current process, inherited by its threads created after
+pub fn set_io_priority(prio: IoPriority) -> io::Result<()> {
+    let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio.ioprio_value()) };
+    if ret != 0{
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Add increment to the CPU niceness of the current process like the nice command, from -20 (highest priority) to 19.
+/// Only CPU scheduling is affected, not I/O, see set_io_priority. Lowering niceness needs root, which fails with EPERM.
+pub fn set_nice(increment: i32) -> io::Result<()> {
+    if !(-20 ..= 19).contains(&increment){
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("nice level {} is not between -20 and 19", increment)));
+    }
+    // nice can return -1 on success, only errno tells
+    Errno::clear();
+    if unsafe { libc::nice(increment) } == -1 && Errno::last() != Errno::UnknownErrno{
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,222 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Network protocol of the remote mode, between a client reading the source and a server writing the destination.
+//!
+//! Every frame starts with a tag byte, all integers little endian:
+//! - `H` hello, first client frame: `[magic: 8 bytes "LBSPROT1"][source size: u64][chunk size: u32][dry run: u8]`,
+//!   the server truncates a regular file destination to the source size unless dry run is 1
+//! - `R` digest request: `[offset: u64][length: u32]`, the server answers with the digests of the chunks of this region
+//! - `S` digests: `[count: u32]` then `[sha256: 32 bytes]` repeated count times
+//! - `D` data: `[offset: u64][length: u32][data: length bytes]`, written by the server
+//! - `Z` compressed data: `[offset: u64][algorithm: u8][uncompressed length: u32][length: u32][data: length bytes]`,
+//!   algorithm 1 is zstd and 2 is lz4 (block format), the server decompresses then writes
+//! - `E` end, last client frame, the server flushes the destination
+//! - `K` ok: `[value: u64]`, answer to hello (destination size) and to end (bytes written)
+//! - `X` error: `[length: u32][message: length bytes]`, the server gives up the connection
+use std::io::{self, prelude::*};
+
+pub const PROTOCOL_MAGIC: [u8; 8] = *b"LBSPROT1";
+/// Longest data frame, longer regions are split
+pub const MAX_DATA_LEN: usize = 16 * 1024 * 1024;
+/// Most digests in one digests frame, longer digest requests are refused
+pub const MAX_DIGESTS: usize = 65536;
+/// Longest error message
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Compression of the data frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd with its level, 1 to 22
+    Zstd(i32),
+    Lz4,
+}
+
+impl Compression {
+    /// Algorithm byte of the compressed data frame
+    fn algorithm(self) -> u8 {
+        match self{
+            Compression::Zstd(_) => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+}
+
+/// Decompress data of a compressed data frame, len is the uncompressed length
+pub fn decompress(algorithm: u8, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let decompressed = match algorithm{
+        1 => zstd::bulk::decompress(data, len)?,
+        2 => lz4_flex::block::decompress(data, len).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression algorithm {}", algorithm))),
+    };
+    if decompressed.len() != len{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} bytes decompressed instead of {}", decompressed.len(), len)));
+    }
+    Ok(decompressed)
+}
+
+/// One protocol message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Hello { size: u64, chunk_size: u32, dry_run: bool },
+    DigestRequest { offset: u64, length: u32 },
+    Digests(Vec<[u8; 32]>),
+    Data { offset: u64, data: Vec<u8> },
+    CompressedData { offset: u64, algorithm: u8, length: u32, data: Vec<u8> },
+    End,
+    Ok(u64),
+    Error(String),
+}
+
+/// Write a data frame without copying data into a Frame, data must not be longer than MAX_DATA_LEN
+pub fn write_data<W: Write>(writer: &mut W, offset: u64, data: &[u8]) -> io::Result<()> {
+    writer.write_all(b"D")?;
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Write data compressed if it gets smaller, as a plain data frame otherwise.
+/// Data must not be longer than MAX_DATA_LEN. Return the number of data bytes sent.
+pub fn write_compressed<W: Write>(writer: &mut W, offset: u64, data: &[u8], compression: Compression) -> io::Result<usize> {
+    let compressed = match compression{
+        Compression::Zstd(level) => zstd::bulk::compress(data, level)?,
+        Compression::Lz4 => lz4_flex::block::compress(data),
+    };
+    if compressed.len() >= data.len(){
+        write_data(writer, offset, data)?;
+        return Ok(data.len());
+    }
+    let len = compressed.len();
+    write_frame(writer, &Frame::CompressedData { offset, algorithm: compression.algorithm(), length: data.len() as u32, data: compressed })?;
+    Ok(len)
+}
+
+/// Write a frame, the writer is not flushed
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    match frame{
+        Frame::Hello { size, chunk_size, dry_run } => {
+            writer.write_all(b"H")?;
+            writer.write_all(&PROTOCOL_MAGIC)?;
+            writer.write_all(&size.to_le_bytes())?;
+            writer.write_all(&chunk_size.to_le_bytes())?;
+            writer.write_all(&[*dry_run as u8])
+        },
+        Frame::DigestRequest { offset, length } => {
+            writer.write_all(b"R")?;
+            writer.write_all(&offset.to_le_bytes())?;
+            writer.write_all(&length.to_le_bytes())
+        },
+        Frame::Digests(digests) => {
+            writer.write_all(b"S")?;
+            writer.write_all(&(digests.len() as u32).to_le_bytes())?;
+            for digest in digests{
+                writer.write_all(digest)?;
+            }
+            Ok(())
+        },
+        Frame::Data { offset, data } => write_data(writer, *offset, data),
+        Frame::CompressedData { offset, algorithm, length, data } => {
+            writer.write_all(b"Z")?;
+            writer.write_all(&offset.to_le_bytes())?;
+            writer.write_all(&[*algorithm])?;
+            writer.write_all(&length.to_le_bytes())?;
+            writer.write_all(&(data.len() as u32).to_le_bytes())?;
+            writer.write_all(data)
+        },
+        Frame::End => writer.write_all(b"E"),
+        Frame::Ok(value) => {
+            writer.write_all(b"K")?;
+            writer.write_all(&value.to_le_bytes())
+        },
+        Frame::Error(message) => {
+            let message = &message.as_bytes()[.. message.len().min(MAX_MESSAGE_LEN)];
+            writer.write_all(b"X")?;
+            writer.write_all(&(message.len() as u32).to_le_bytes())?;
+            writer.write_all(message)
+        },
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read a length field and check it is at most max, so a bad peer cannot make us allocate anything
+fn read_len<R: Read>(reader: &mut R, max: usize) -> io::Result<usize> {
+    let len = read_u32(reader)? as usize;
+    if len > max{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame length {} is above the maximum {}", len, max)));
+    }
+    Ok(len)
+}
+
+/// Read the next frame
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Frame> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    match &tag{
+        b"H" => {
+            let mut magic = [0u8; 8];
+            reader.read_exact(&mut magic)?;
+            if magic != PROTOCOL_MAGIC{
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "peer is not localblocksync or uses another protocol version"));
+            }
+            let size = read_u64(reader)?;
+            let chunk_size = read_u32(reader)?;
+            let mut dry_run = [0u8; 1];
+            reader.read_exact(&mut dry_run)?;
+            Ok(Frame::Hello { size, chunk_size, dry_run: dry_run[0] != 0 })
+        },
+        b"R" => Ok(Frame::DigestRequest { offset: read_u64(reader)?, length: read_u32(reader)? }),
+        b"S" => {
+            let mut digests = vec![[0u8; 32]; read_len(reader, MAX_DIGESTS)?];
+            for digest in &mut digests{
+                reader.read_exact(digest)?;
+            }
+            Ok(Frame::Digests(digests))
+        },
+        b"D" => {
+            let offset = read_u64(reader)?;
+            let mut data = vec![0u8; read_len(reader, MAX_DATA_LEN)?];
+            reader.read_exact(&mut data)?;
+            Ok(Frame::Data { offset, data })
+        },
+        b"Z" => {
+            let offset = read_u64(reader)?;
+            let mut algorithm = [0u8; 1];
+            reader.read_exact(&mut algorithm)?;
+            let length = read_len(reader, MAX_DATA_LEN)? as u32;
+            let mut data = vec![0u8; read_len(reader, MAX_DATA_LEN)?];
+            reader.read_exact(&mut data)?;
+            Ok(Frame::CompressedData { offset, algorithm: algorithm[0], length, data })
+        },
+        b"E" => Ok(Frame::End),
+        b"K" => Ok(Frame::Ok(read_u64(reader)?)),
+        b"X" => {
+            let mut message = vec![0u8; read_len(reader, MAX_MESSAGE_LEN)?];
+            reader.read_exact(&mut message)?;
+            Ok(Frame::Error(String::from_utf8_lossy(&message).into_owned()))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown frame tag {:?}", tag[0] as char))),
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Do not sleep before the debt is worth this duration of writing, so small writes are smoothed over several chunks
+const MIN_SLEEP: Duration = Duration::from_millis(100);
+
+/// Token bucket limiting the write bandwidth.
+/// Bucket starts empty and holds at most 1 second of writing, so bursts after idle time are allowed but the average stays under the rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: f64,
+    /// Available bytes, negative when in debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter { rate: bytes_per_sec as f64, tokens: 0., last_refill: Instant::now() }
+    }
+
+    /// Bytes per second
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    fn refill(&mut self){
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// Account bytes just written, sleep if they exceed the rate
+    pub fn consume(&mut self, bytes: usize){
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.{
+            let debt = Duration::from_secs_f64(-self.tokens / self.rate);
+            if debt >= MIN_SLEEP{
+                thread::sleep(debt);
+                self.refill();
+            }
+        }
+    }
+}
+
+/// Token bucket limiting the number of write calls per second, composable with RateLimiter.
+/// Same as RateLimiter with one token per write call.
+#[derive(Debug)]
+pub struct IopsLimiter {
+    /// Write calls per second
+    limit: u32,
+    /// Available write calls, negative when in debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl IopsLimiter {
+    /// Limit of at least 1 write call per second
+    pub fn new(limit: u32) -> Self {
+        IopsLimiter { limit: limit.max(1), tokens: 0., last_refill: Instant::now() }
+    }
+
+    /// Write calls per second
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    fn refill(&mut self){
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.limit as f64).min(self.limit as f64);
+        self.last_refill = now;
+    }
+
+    /// Account one write call just made, sleep if calls exceed the limit
+    pub fn consume(&mut self){
+        self.refill();
+        self.tokens -= 1.;
+        if self.tokens < 0.{
+            let debt = Duration::from_secs_f64(-self.tokens / self.limit as f64);
+            if debt >= MIN_SLEEP{
+                thread::sleep(debt);
+                self.refill();
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Remote mode: a client reads the source and sends only the differing chunks over TCP to a server which writes
+//! the destination. Chunks are compared by SHA-256 digest, computed by the server on its side. Frames are defined
+//! in the protocol module. Without a key, there is no authentication nor encryption, use it on a trusted network or an
+//! SSH tunnel. With a key, the connection is encrypted and authenticated as described in the crypto module.
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::fs::File;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use sha2::{Digest, Sha256};
+use crate::crypto::{client_handshake, server_handshake, EncryptionKey};
+use crate::protocol::{decompress, read_frame, write_compressed, write_data, write_frame, Frame, MAX_DATA_LEN, MAX_DIGESTS};
+use crate::{display_progress, filesize, json_progress, open_destination, push_dirty_range, read_full_at, save_block_map, write_progress_file};
+use crate::{DestinationStats, EtaAlgorithm, IoEngine, IopsLimiter, RateLimiter, SpeedEstimator, SyncError, SyncEvent, SyncOptions, SyncStats};
+
+/// Read the answer of the server, its value if it is ok
+fn read_ok<R: Read>(reader: &mut R) -> io::Result<u64> {
+    match read_frame(reader)?{
+        Frame::Ok(value) => Ok(value),
+        Frame::Error(message) => Err(io::Error::other(message)),
+        frame => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected frame {:?}", frame))),
+    }
+}
+
+/// Ask the server the digests of the chunks of the region (offset, len)
+fn request_digests<R: Read, W: Write>(reader: &mut R, writer: &mut W, offset: u64, len: usize, chunk_size: usize) -> io::Result<Vec<[u8; 32]>> {
+    write_frame(writer, &Frame::DigestRequest { offset, length: len as u32 })?;
+    writer.flush()?;
+    let digests = match read_frame(reader)?{
+        Frame::Digests(digests) => digests,
+        Frame::Error(message) => return Err(io::Error::other(message)),
+        frame => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected frame {:?}", frame))),
+    };
+    if digests.len() != len.div_ceil(chunk_size){
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} digests received for {} chunks", digests.len(), len.div_ceil(chunk_size))));
+    }
+    Ok(digests)
+}
+
+/// Sync src_path to the destination of a server started with serve at addr (host:port).
+/// Only the chunks which digest differs are sent, the server flushes the destination at the end.
+pub fn sync_remote(src_path: &Path, addr: &str, opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    let SyncOptions { buffer_size, chunk_size, quiet, no_progress, color, progress_interval, eta_algorithm, json, compression, dry_run, rate_limit, iops_limit, stop_flag, ref events, ref encryption, ref block_map, ref progress_file, .. } = opts;
+    let unsupported = [
+        (opts.output_delta.is_some(), "output delta"),
+        (opts.write_journal.is_some(), "write journal"),
+        (opts.checksum_file.is_some(), "checksum file"),
+        (opts.verify, "verify"),
+        (opts.write_verify_each, "write verify each"),
+        (opts.write_barrier, "write barrier"),
+        (opts.use_sendfile, "sendfile"),
+        (opts.ignore_errors, "ignore errors"),
+        (opts.retries > 0, "retry"),
+        (opts.align > 1, "write alignment"),
+        (opts.max_bytes_written.is_some(), "max bytes written"),
+        (opts.max_diff_ratio.is_some(), "max diff ratio"),
+        (opts.diff_filter > 0, "diff filter"),
+        (opts.seed_zeros, "seed zeros"),
+        (opts.swap_endian.is_some(), "endianness swap"),
+        (opts.simulate_corruption.is_some(), "simulated corruption"),
+        (!opts.priority_regions.is_empty(), "priority regions"),
+        (!opts.truncate_dest, "no truncate dest"),
+        (opts.metadata, "metadata"),
+        (opts.create_sparse_dest, "create sparse dest"),
+        (opts.count_differing_bytes, "write amplification report"),
+        (opts.zero_unwritten, "zero unwritten"),
+        (opts.checkpoint_path.is_some(), "resume"),
+        (opts.segment_size.is_some(), "checkpointed write"),
+        (opts.seed_from.is_some(), "seed from"),
+        (opts.sparse, "sparse"),
+        (opts.trim, "trim"),
+        (opts.direct, "direct"),
+        (opts.read_ahead.is_some(), "read ahead"),
+        (opts.mmap, "mmap"),
+        (opts.buffer_count > 1, "buffer count"),
+        (opts.io_engine != IoEngine::Sync, "io engine"),
+        (opts.atomic, "atomic"),
+        (opts.block_size_auto, "block size auto"),
+        (opts.offset != 0 || opts.length.is_some() || !opts.exclude_ranges.is_empty(), "offset, length and exclude ranges"),
+        (opts.source_offset != 0, "source offset"),
+    ];
+    if let Some((_, option)) = unsupported.iter().find(|(enabled, _)| *enabled){
+        return Err(SyncError::InvalidOptions(format!("{} is not supported with a remote destination", option)));
+    }
+    if chunk_size == 0 || chunk_size > MAX_DATA_LEN{
+        return Err(SyncError::InvalidOptions(format!("chunk size must be between 1 and {} bytes with a remote destination", MAX_DATA_LEN)));
+    }
+    let remote_error = |err| SyncError::RemoteFailed(addr.to_string(), err);
+    let mut stats = SyncStats { dry_run, ..Default::default() };
+    let quiet = quiet || json;
+    log::info!("Synching {:?} to remote {}", src_path, addr);
+    let src_file = match File::open(src_path){
+        Ok(src_file) => src_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    let src_size = filesize(src_path)?;
+    let stream = TcpStream::connect(addr).map_err(remote_error)?;
+    // Digest requests are small and wait for their answer
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer): (Box<dyn Read>, Box<dyn Write>) = match encryption{
+        Some(key) => {
+            let (reader, writer) = client_handshake(BufReader::new(&stream), BufWriter::new(&stream), key).map_err(remote_error)?;
+            (Box::new(reader), Box::new(writer))
+        },
+        None => (Box::new(BufReader::new(&stream)), Box::new(BufWriter::new(&stream))),
+    };
+    write_frame(&mut writer, &Frame::Hello { size: src_size, chunk_size: chunk_size as u32, dry_run })
+        .and_then(|_| writer.flush())
+        .map_err(remote_error)?;
+    let dst_size = read_ok(&mut reader).map_err(remote_error)?;
+    log::info!("Sizes:");
+    log::info!("{}: {} [{:.1} MB]", src_path.display(), src_size, src_size as f64 / 1024. / 1024.);
+    log::info!("{}: {} [{:.1} MB]", addr, dst_size, dst_size as f64 / 1024. / 1024.);
+    log::info!("Buffer size: 1x {} [{:.1} MB] - remote mode", buffer_size, buffer_size as f64 / 1024. / 1024.);
+    log::info!("Chunk size: {} [{:.1} KB]", chunk_size, chunk_size as f64 / 1024.);
+    if let Some(compression) = compression{
+        log::info!("Compression: {:?}", compression);
+    }
+    if encryption.is_some(){
+        log::info!("Encryption: ChaCha20-Poly1305");
+    }
+
+    // Longest region of one digest request
+    let request_len = chunk_size * MAX_DIGESTS.min(u32::MAX as usize / chunk_size);
+    // Compressed chunk by chunk, so the server can write each as soon as it is received
+    let frame_len = if compression.is_some() {chunk_size} else {MAX_DATA_LEN};
+    let mut rate_limiter = rate_limit.map(RateLimiter::new);
+    let mut iops_limiter = iops_limit.map(IopsLimiter::new);
+    let mut buffer_src = vec![0u8; buffer_size];
+    let mut written_ranges = Vec::new();
+    let mut fp = 0u64;
+    let start_time = Instant::now();
+    let mut speed_estimator = (eta_algorithm == EtaAlgorithm::Exponential).then(SpeedEstimator::new);
+    let mut time2display = Instant::now();
+    let mut time2progress_file = Instant::now();
+    loop{
+        let src_len = match read_full_at(&src_file, &mut buffer_src, fp){
+            Ok(src_len) => src_len,
+            Err(err) => return Err(SyncError::SourceReadFailed { offset: fp, source: err }),
+        };
+        if src_len == 0{
+            break;
+        }
+        let data_src = &buffer_src[0 .. src_len];
+        let mut dirty_ranges = Vec::new();
+        for (i, request) in data_src.chunks(request_len).enumerate(){
+            let request_start = i * request_len;
+            let digests = request_digests(&mut reader, &mut writer, fp + request_start as u64, request.len(), chunk_size).map_err(remote_error)?;
+            for (j, (chunk_src, digest)) in request.chunks(chunk_size).zip(&digests).enumerate(){
+                if Sha256::digest(chunk_src)[..] != digest[..]{
+                    push_dirty_range(&mut dirty_ranges, request_start + j * chunk_size, chunk_src.len());
+                    stats.chunks_different += 1;
+                }else{
+                    stats.chunks_identical += 1;
+                }
+            }
+        }
+        for &(start, len) in &dirty_ranges{
+            for (i, data) in data_src[start .. start + len].chunks(frame_len).enumerate(){
+                if !dry_run{
+                    let offset = fp + (start + i * frame_len) as u64;
+                    let sent = match compression{
+                        Some(compression) => write_compressed(&mut writer, offset, data, compression),
+                        None => write_data(&mut writer, offset, data).map(|_| data.len()),
+                    }.map_err(remote_error)?;
+                    // The limit is on the network
+                    if let Some(rate_limiter) = &mut rate_limiter{
+                        rate_limiter.consume(sent);
+                    }
+                    // The server writes each data frame in one call
+                    if let Some(iops_limiter) = &mut iops_limiter{
+                        iops_limiter.consume();
+                    }
+                    stats.bytes_sent += sent as u64;
+                }
+                stats.write_calls += 1;
+                *stats.write_sizes.entry(data.len()).or_default() += 1;
+            }
+            stats.bytes_written += len as u64;
+            written_ranges.push((fp + start as u64, len));
+        }
+        writer.flush().map_err(remote_error)?;
+        fp += src_len as u64;
+        stats.bytes_compared += src_len as u64;
+        if let Some(events) = events{
+            let _ = events.send(SyncEvent::Progress { offset: fp, start: 0, end: src_size, bytes_compared: stats.bytes_compared, bytes_written: stats.bytes_written, chunks_different: stats.chunks_different });
+        }
+        if let (Some(progress_path), true) = (progress_file, time2progress_file.elapsed() >= progress_interval){
+            if let Err(err) = write_progress_file(progress_path, fp, 0, src_size, stats.bytes_written, start_time){
+                log::warn!("Warning: failed to write progress file {}: {}", progress_path.display(), err);
+            }
+            time2progress_file = Instant::now();
+        }
+        if !no_progress && time2display.elapsed() >= progress_interval{
+            if json{
+                json_progress(fp, src_size, stats.bytes_written, start_time);
+            }else if !quiet{
+                display_progress(fp as f64, src_size as f64, start_time, speed_estimator.as_mut(), 0, color);
+            }
+            time2display = Instant::now();
+        }
+        if stop_flag.as_ref().is_some_and(|stop_flag| stop_flag.load(Ordering::Relaxed)){
+            stats.interrupted = true;
+            break;
+        }
+    }
+    if !quiet && !no_progress{
+        println!(); // To skip line after display_progress
+    }
+    stats.elapsed = start_time.elapsed();
+    stats.end_offset = fp;
+    // The server flushes the destination before answering
+    let flush_start = Instant::now();
+    write_frame(&mut writer, &Frame::End)
+        .and_then(|_| writer.flush())
+        .map_err(remote_error)?;
+    read_ok(&mut reader).map_err(remote_error)?;
+    stats.flush_elapsed = flush_start.elapsed();
+    stats.destinations.push(DestinationStats { path: PathBuf::from(addr), bytes_written: stats.bytes_written, written_ranges, error: None });
+    if let Some((block_map_path, format)) = block_map{
+        save_block_map(&stats.destinations, block_map_path, *format)?;
+    }
+    if let (Some(progress_path), false) = (progress_file, stats.interrupted){
+        let _ = std::fs::remove_file(progress_path);
+    }
+    Ok(stats)
+}
+
+/// Answer the frames of one client until its end frame
+fn serve_frames<R: Read, W: Write>(reader: &mut R, writer: &mut W, dst_path: &Path) -> Result<SyncStats, SyncError> {
+    let start_time = Instant::now();
+    let (src_size, chunk_size, dry_run) = match read_frame(reader)?{
+        Frame::Hello { size, chunk_size, dry_run } => (size, chunk_size as usize, dry_run),
+        frame => return Err(SyncError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("expected hello, got {:?}", frame)))),
+    };
+    if chunk_size == 0 || chunk_size > MAX_DATA_LEN{
+        return Err(SyncError::InvalidOptions(format!("chunk size {} is not between 1 and {} bytes", chunk_size, MAX_DATA_LEN)));
+    }
+    let dst_file = open_destination(dst_path, src_size, dry_run, dry_run, false, true, false)?;
+    write_frame(writer, &Frame::Ok(filesize(dst_path)?))?;
+    writer.flush()?;
+    let mut stats = SyncStats { dry_run, ..Default::default() };
+    let mut buffer_chunk = vec![0u8; chunk_size];
+    loop{
+        match read_frame(reader)?{
+            Frame::DigestRequest { offset, length } => {
+                let length = length as usize;
+                if length > MAX_DIGESTS * chunk_size{
+                    return Err(SyncError::InvalidOptions(format!("digest request of {} bytes is too long", length)));
+                }
+                let mut digests = Vec::with_capacity(length.div_ceil(chunk_size));
+                for chunk_start in (0 .. length).step_by(chunk_size){
+                    let chunk_offset = offset + chunk_start as u64;
+                    let chunk_dst = &mut buffer_chunk[0 .. chunk_size.min(length - chunk_start)];
+                    let dst_len = match read_full_at(&dst_file, chunk_dst, chunk_offset){
+                        Ok(dst_len) => dst_len,
+                        Err(err) => return Err(SyncError::DestReadFailed { offset: chunk_offset, source: err }),
+                    };
+                    // Destination is not truncated in dry run, pad like it would be
+                    chunk_dst[dst_len ..].fill(0);
+                    digests.push(Sha256::digest(&*chunk_dst).into());
+                }
+                write_frame(writer, &Frame::Digests(digests))?;
+                writer.flush()?;
+                stats.bytes_compared += length as u64;
+            },
+            Frame::Data { offset, data } => {
+                if dry_run{
+                    return Err(SyncError::InvalidOptions("data received in dry run".to_string()));
+                }
+                if let Err(err) = dst_file.write_all_at(&data, offset){
+                    return Err(SyncError::WriteFailed { offset, source: err });
+                }
+                stats.bytes_written += data.len() as u64;
+            },
+            Frame::CompressedData { offset, algorithm, length, data } => {
+                if dry_run{
+                    return Err(SyncError::InvalidOptions("data received in dry run".to_string()));
+                }
+                let data = decompress(algorithm, &data, length as usize)?;
+                if let Err(err) = dst_file.write_all_at(&data, offset){
+                    return Err(SyncError::WriteFailed { offset, source: err });
+                }
+                stats.bytes_written += data.len() as u64;
+            },
+            Frame::End => {
+                if !dry_run{
+                    if let Err(err) = dst_file.sync_data(){
+                        return Err(SyncError::FlushFailed(dst_path.to_path_buf(), err));
+                    }
+                }
+                write_frame(writer, &Frame::Ok(stats.bytes_written))?;
+                writer.flush()?;
+                stats.elapsed = start_time.elapsed();
+                return Ok(stats);
+            },
+            frame => return Err(SyncError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected frame {:?}", frame)))),
+        }
+    }
+}
+
+/// Sync one client to dst_path, the client is told why if it fails
+fn serve_client(stream: &TcpStream, dst_path: &Path, key: Option<&EncryptionKey>) -> Result<SyncStats, SyncError> {
+    let (mut reader, mut writer): (Box<dyn Read>, Box<dyn Write>) = match key{
+        Some(key) => {
+            let (reader, writer) = match server_handshake(BufReader::new(stream), BufWriter::new(stream), key){
+                Ok(encrypted) => encrypted,
+                Err(err) => {
+                    // In plain text, for a client which does not encrypt
+                    let mut writer = BufWriter::new(stream);
+                    let _ = write_frame(&mut writer, &Frame::Error(err.to_string())).and_then(|_| writer.flush());
+                    return Err(SyncError::Io(err));
+                },
+            };
+            (Box::new(reader), Box::new(writer))
+        },
+        None => (Box::new(BufReader::new(stream)), Box::new(BufWriter::new(stream))),
+    };
+    let result = serve_frames(&mut reader, &mut writer, dst_path);
+    if let Err(err) = &result{
+        // Best effort, the connection may be the problem
+        let _ = write_frame(&mut writer, &Frame::Error(err.to_string())).and_then(|_| writer.flush());
+    }
+    result
+}
+
+/// Listen on addr (host:port) and sync each client to dst_path, one client at a time, until the process is killed.
+/// With a key, clients must encrypt with the same key.
+pub fn serve(addr: &str, dst_path: &Path, key: Option<&EncryptionKey>) -> Result<(), SyncError> {
+    let listener = TcpListener::bind(addr).map_err(|err| SyncError::RemoteFailed(addr.to_string(), err))?;
+    log::info!("Listening on {}, destination {:?}{}", addr, dst_path, if key.is_some() {", encrypted"} else {""});
+    for stream in listener.incoming(){
+        let stream = match stream{
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Warning: failed to accept a client: {}", err);
+                continue;
+            },
+        };
+        let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_else(|_| "?".to_string());
+        log::info!("Client {} connected", peer);
+        let _ = stream.set_nodelay(true);
+        match serve_client(&stream, dst_path, key){
+            Ok(stats) if stats.dry_run => log::info!("Client {}: dry run, {} bytes [{:.1} MB] compared in {:.2}s", peer, stats.bytes_compared, stats.bytes_compared as f64 / 1024. / 1024., stats.elapsed.as_secs_f64()),
+            Ok(stats) => log::info!("Client {}: {} bytes [{:.1} MB] written in {:.2}s", peer, stats.bytes_written, stats.bytes_written as f64 / 1024. / 1024., stats.elapsed.as_secs_f64()),
+            Err(err) => log::error!("Client {}: {}", peer, err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,79 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Order in which the source is synced: priority regions first, e.g. the boot sector and kernel of a VM image to
+//! make it bootable sooner, then the rest from the start.
+use std::fs;
+use std::path::Path;
+use crate::exclude::{normalize_ranges, ExcludeRange};
+use crate::SyncError;
+
+/// Read a CSV file of `start_byte,end_byte` lines, end excluded, in the order they are given.
+/// Empty lines and lines starting with # are ignored.
+pub fn read_priority_regions(path: &Path) -> Result<Vec<(u64, u64)>, SyncError> {
+    let content = match fs::read_to_string(path){
+        Ok(content) => content,
+        Err(err) => return Err(SyncError::InvalidOptions(format!("failed to read priority regions {}: {}", path.display(), err))),
+    };
+    let mut regions = Vec::new();
+    for (i, line) in content.lines().enumerate(){
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+        let region = line.split_once(',').and_then(|(start, end)| Some((start.trim().parse().ok()?, end.trim().parse().ok()?)));
+        match region{
+            Some((start, end)) if start < end => regions.push((start, end)),
+            _ => return Err(SyncError::InvalidOptions(format!("invalid priority region at line {} of {}: {:?}", i + 1, path.display(), line))),
+        }
+    }
+    Ok(regions)
+}
+
+/// Ranges [start, end) to sync in order to cover 0 .. total once: the priority regions in their order, without the
+/// parts already covered by a previous one, then the gaps between them from the start.
+pub fn build_offset_schedule(total: u64, priority: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut schedule = Vec::new();
+    // Regions scheduled so far, normalized
+    let mut done: Vec<ExcludeRange> = Vec::new();
+    for &(start, end) in priority{
+        let end = end.min(total);
+        let mut cursor = start;
+        while cursor < end{
+            match done.iter().find(|range| range.start <= cursor && cursor < range.end){
+                Some(range) => cursor = range.end,
+                None => {
+                    let next = done.iter().map(|range| range.start).filter(|&start| start > cursor).min().unwrap_or(end).min(end);
+                    schedule.push((cursor, next));
+                    cursor = next;
+                },
+            }
+        }
+        if start < end{
+            done.push(ExcludeRange { start, end });
+            done = normalize_ranges(done);
+        }
+    }
+    let mut cursor = 0;
+    for range in &done{
+        if cursor < range.start{
+            schedule.push((cursor, range.start));
+        }
+        cursor = range.end;
+    }
+    if cursor < total{
+        schedule.push((cursor, total));
+    }
+    schedule
+}
@@ -0,0 +1,147 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Checkpointed write: the sync range is split in segments synced and flushed one after the other, each completed
+//! segment is recorded in the checkpoint file and skipped on resume.
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::{filesize, sync_paths_multi, SyncError, SyncOptions, SyncStats};
+
+/// Content of the checkpoint file of a checkpointed write
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SegmentCheckpoint {
+    /// Start offsets of the segments synced and flushed to the destinations
+    pub completed_segments: Vec<u64>,
+    pub src_path: String,
+    pub dst_path: String,
+    /// Segments are only the same with the same size
+    pub segment_size: u64,
+}
+
+impl SegmentCheckpoint {
+    /// Read a checkpoint file, an empty checkpoint if there is none. Fail if it was made for other paths or segments.
+    pub fn read(checkpoint_path: &Path, src_abs: &str, dst_abs: &str, segment_size: u64) -> Result<Self, SyncError> {
+        let content = match fs::read_to_string(checkpoint_path){
+            Ok(content) => content,
+            Err(_err) => return Ok(SegmentCheckpoint { src_path: src_abs.to_string(), dst_path: dst_abs.to_string(), segment_size, ..Default::default() }),
+        };
+        let checkpoint: SegmentCheckpoint = match serde_json::from_str(&content){
+            Ok(checkpoint) => checkpoint,
+            Err(_err) => return Err(SyncError::InvalidCheckpoint(checkpoint_path.to_path_buf())),
+        };
+        if checkpoint.src_path != src_abs || checkpoint.dst_path != dst_abs{
+            return Err(SyncError::CheckpointMismatch {
+                checkpoint_path: checkpoint_path.to_path_buf(),
+                src_path: checkpoint.src_path,
+                dst_path: checkpoint.dst_path,
+            });
+        }
+        if checkpoint.segment_size != segment_size{
+            return Err(SyncError::InvalidOptions(format!("checkpoint {} was made with {} byte segments, not {}", checkpoint_path.display(), checkpoint.segment_size, segment_size)));
+        }
+        Ok(checkpoint)
+    }
+
+    /// Save the checkpoint through a temporary file renamed over it, so a crash never leaves a partial checkpoint
+    pub fn write(&self, checkpoint_path: &Path) -> Result<(), SyncError> {
+        let mut tmp_path = checkpoint_path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        serde_json::to_string(self).map_err(std::io::Error::from)
+            .and_then(|content| fs::write(&tmp_path, content))
+            .and_then(|_| fs::rename(&tmp_path, checkpoint_path))
+            .map_err(|err| SyncError::CheckpointWriteFailed(checkpoint_path.to_path_buf(), err))
+    }
+}
+
+/// Absolute path of a destination, which is created by the first segment: then its directory is canonicalized
+fn absolute_destination(dst_path: &Path) -> io::Result<PathBuf> {
+    if dst_path.exists(){
+        return fs::canonicalize(dst_path);
+    }
+    let Some(file_name) = dst_path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file path"));
+    };
+    let dir = match dst_path.parent(){
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(dir)?.join(file_name))
+}
+
+/// Sync the range of opts in segments of segment_size bytes, flush the destinations after each one and record it in
+/// the checkpoint file. Completed segments of a previous run are skipped. The checkpoint is removed when all are done.
+pub(crate) fn sync_segmented(src_path: &Path, dst_paths: &[PathBuf], opts: SyncOptions, segment_size: u64) -> Result<SyncStats, SyncError> {
+    let checkpoint_path = match &opts.checkpoint_path{
+        Some(checkpoint_path) => checkpoint_path.clone(),
+        None => return Err(SyncError::InvalidOptions("a checkpointed write needs a checkpoint file".to_string())),
+    };
+    if segment_size == 0 || !segment_size.is_multiple_of(opts.chunk_size as u64){
+        return Err(SyncError::InvalidOptions(format!("the segment size {} is not a multiple of the chunk size {}", segment_size, opts.chunk_size)));
+    }
+    if opts.dry_run || opts.output_delta.is_some(){
+        return Err(SyncError::InvalidOptions("a checkpointed write cannot be used in dry run or with a delta file".to_string()));
+    }
+    if !opts.priority_regions.is_empty(){
+        return Err(SyncError::InvalidOptions("a checkpointed write cannot be used with priority regions".to_string()));
+    }
+    // Each segment is a sync of its own, which would recreate these files and keep only the last segment
+    if opts.checksum_file.is_some() || opts.block_map.is_some() || opts.write_journal.is_some(){
+        return Err(SyncError::InvalidOptions("a checkpointed write cannot write a checksum file, a block map or a journal".to_string()));
+    }
+    let src_abs = fs::canonicalize(src_path)?.to_string_lossy().into_owned();
+    let mut dst_abs = Vec::new();
+    for dst_path in dst_paths{
+        match absolute_destination(dst_path){
+            Ok(path) => dst_abs.push(path.to_string_lossy().into_owned()),
+            Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+        }
+    }
+    let dst_abs = dst_abs.join(", ");
+    let mut checkpoint = SegmentCheckpoint::read(&checkpoint_path, &src_abs, &dst_abs, segment_size)?;
+    let src_size = filesize(src_path)?.saturating_sub(opts.source_offset);
+    let end = match opts.length{
+        Some(length) => opts.offset.saturating_add(length).min(src_size),
+        None => src_size,
+    };
+    let segments = end.saturating_sub(opts.offset).div_ceil(segment_size);
+    let mut stats = SyncStats::default();
+    for (i, start) in (opts.offset .. end).step_by(segment_size as usize).enumerate(){
+        if checkpoint.completed_segments.contains(&start){
+            log::info!("Segment {}/{} at offset {} already completed, skipped", i + 1, segments, start);
+            continue;
+        }
+        log::info!("Segment {}/{}: offset {} [{:.1} MB]", i + 1, segments, start, start as f64 / 1024. / 1024.);
+        let segment_opts = SyncOptions { offset: start, length: Some(segment_size.min(end - start)), checkpoint_path: None, segment_size: None, ..opts.clone() };
+        let segment_stats = sync_paths_multi(src_path, dst_paths, segment_opts)?;
+        let interrupted = segment_stats.interrupted;
+        stats.merge(segment_stats);
+        if interrupted{
+            // The segment is synced again from its start on resume
+            return Ok(stats);
+        }
+        // Consistent on disk before it is recorded
+        for dst_path in dst_paths{
+            if let Err(err) = OpenOptions::new().write(true).open(dst_path).and_then(|dst_file| dst_file.sync_data()){
+                return Err(SyncError::FlushFailed(dst_path.to_path_buf(), err));
+            }
+        }
+        checkpoint.completed_segments.push(start);
+        checkpoint.write(&checkpoint_path)?;
+    }
+    // Sync is complete, next run starts from the beginning
+    let _ = fs::remove_file(&checkpoint_path);
+    Ok(stats)
+}
@@ -0,0 +1,265 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Streaming mode: sync a source read from a pipe, e.g. stdin, to a destination file, or copy a source to a pipe,
+//! e.g. stdout. A pipe is read or written once in order, so most options of a file sync cannot be used.
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use crate::{filesize, find_dirty_ranges, is_block_device, platform, read_full_at, retry_io};
+use crate::{DestinationStats, FlushMode, SyncError, SyncOptions, SyncStats};
+
+/// Fill buf from reader, less only at the end of the stream
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len(){
+        match reader.read(&mut buf[len ..]){
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Progress line of a stream, with a percentage if its size is estimated
+fn display_stream_progress(pos: u64, size_estimate: Option<u64>, start_time: Instant){
+    let elapsed = start_time.elapsed().as_secs_f64().max(0.001);
+    let progress_pc = match size_estimate{
+        Some(size) if size > 0 => format!("{}% - ", (pos as f64 * 100. / size as f64).ceil().min(100.)),
+        _ => String::new(),
+    };
+    eprint!("\r{}{} [{:.1} MB] - {:.3} MB/s          ", progress_pc, pos, pos as f64 / 1024. / 1024., pos as f64 / elapsed / 1024. / 1024.);
+    let _ = io::stderr().flush();
+}
+
+/// First option of the list that is set, as an error
+fn check_unsupported(unsupported: &[(bool, &str)], stream: &str) -> Result<(), SyncError> {
+    match unsupported.iter().find(|(enabled, _)| *enabled){
+        Some((_, option)) => Err(SyncError::InvalidOptions(format!("{} is not supported {}", option, stream))),
+        None => Ok(()),
+    }
+}
+
+/// Sync the source read from src, e.g. stdin, to dst_path: each buffer is compared to the destination and only the
+/// differing chunks are written. A regular file destination is resized to the stream length at the end.
+/// The progress line goes to stderr, as stdout may be the other end of the pipe.
+pub fn sync_stream<R: Read>(src: R, dst_path: &Path, opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    sync_reader(src, None, dst_path, opts, "when reading the source from a stream")
+}
+
+/// Same as sync_stream, with the estimated source size for the progress, source is the kind of source for errors
+pub(crate) fn sync_reader<R: Read>(mut src: R, size_estimate: Option<u64>, dst_path: &Path, opts: SyncOptions, source: &str) -> Result<SyncStats, SyncError> {
+    let SyncOptions { buffer_size, chunk_size, quiet, no_progress, progress_interval, json, flush_mode, dry_run, retries, truncate_dest, stop_flag, .. } = opts;
+    check_unsupported(&[
+        (opts.threaded, "thread"),
+        (opts.buffer_count > 1, "buffer count"),
+        (opts.mmap, "mmap"),
+        (opts.direct, "direct"),
+        (opts.read_ahead.is_some(), "read ahead"),
+        (opts.atomic, "atomic"),
+        (opts.verify, "verify"),
+        (opts.write_verify_each, "write verify each"),
+        (opts.write_barrier, "write barrier"),
+        (opts.use_sendfile, "sendfile"),
+        (opts.ignore_errors, "ignore errors"),
+        (opts.checkpoint_path.is_some(), "resume"),
+        (opts.segment_size.is_some(), "checkpointed write"),
+        (opts.seed_from.is_some(), "seed from"),
+        (opts.output_delta.is_some(), "output delta"),
+        (opts.write_journal.is_some(), "write journal"),
+        (opts.checksum_file.is_some(), "checksum file"),
+        (opts.block_map.is_some(), "block map"),
+        (opts.progress_file.is_some(), "progress file"),
+        (opts.swap_endian.is_some(), "endianness swap"),
+        (opts.simulate_corruption.is_some(), "simulated corruption"),
+        (opts.sparse || opts.trim, "sparse and trim"),
+        (opts.metadata, "metadata"),
+        (!opts.priority_regions.is_empty(), "priority regions"),
+        (opts.offset != 0 || opts.length.is_some() || opts.source_offset != 0 || !opts.exclude_ranges.is_empty(), "offset, length and exclude ranges"),
+        (json, "json"),
+    ], source)?;
+    if chunk_size == 0{
+        return Err(SyncError::InvalidOptions("the chunk size cannot be 0".to_string()));
+    }
+    let existed = dst_path.exists();
+    let dst_file = match OpenOptions::new().read(true).write(!dry_run).create(!dry_run).truncate(false).open(dst_path){
+        Ok(dst_file) => dst_file,
+        Err(err) => return Err(SyncError::DestOpenFailed(dst_path.to_path_buf(), err)),
+    };
+    let dst_size = filesize(dst_path)?;
+    log::info!("Synching stream to {:?}", dst_path);
+    log::info!("{}: {} [{:.1} MB]", dst_path.display(), dst_size, dst_size as f64 / 1024. / 1024.);
+    log::info!("Buffer size: 1x {} [{:.1} MB] - stream mode", buffer_size, buffer_size as f64 / 1024. / 1024.);
+    log::info!("Chunk size: {} [{:.1} KB]", chunk_size, chunk_size as f64 / 1024.);
+    let mut stats = SyncStats { dry_run, ..Default::default() };
+    let mut buffer_src = vec![0u8; buffer_size];
+    let mut buffer_dst = vec![0u8; buffer_size];
+    let mut written_ranges = Vec::new();
+    let mut fp = 0u64;
+    let start_time = Instant::now();
+    let mut time2display = Instant::now();
+    loop{
+        let src_len = match read_full(&mut src, &mut buffer_src){
+            Ok(src_len) => src_len,
+            Err(err) => return Err(SyncError::SourceReadFailed { offset: fp, source: err }),
+        };
+        if src_len == 0{
+            break;
+        }
+        let dst_len = match retry_io(|| read_full_at(&dst_file, &mut buffer_dst[0 .. src_len], fp), retries){
+            Ok(dst_len) => dst_len,
+            Err(err) => return Err(SyncError::DestReadFailed { offset: fp, source: err }),
+        };
+        // Beyond the end of the destination is compared as zeros, like a new file
+        buffer_dst[dst_len .. src_len].fill(0);
+        let dirty_ranges = find_dirty_ranges(&buffer_src[0 .. src_len], &buffer_dst[0 .. src_len], chunk_size);
+        let chunks = src_len.div_ceil(chunk_size) as u64;
+        let chunks_different = dirty_ranges.iter().map(|(_, len)| len.div_ceil(chunk_size) as u64).sum::<u64>();
+        stats.chunks_different += chunks_different;
+        stats.chunks_identical += chunks - chunks_different;
+        let write_start = Instant::now();
+        for &(start, len) in &dirty_ranges{
+            let offset = fp + start as u64;
+            if !dry_run{
+                if let Err(err) = retry_io(|| dst_file.write_all_at(&buffer_src[start .. start + len], offset), retries){
+                    return Err(SyncError::WriteFailed { offset, source: err });
+                }
+            }
+            stats.bytes_written += len as u64;
+            stats.write_calls += 1;
+            *stats.write_sizes.entry(len).or_default() += 1;
+            written_ranges.push((offset, len));
+        }
+        stats.write_elapsed += write_start.elapsed();
+        fp += src_len as u64;
+        stats.bytes_compared += src_len as u64;
+        if !quiet && !no_progress && time2display.elapsed() >= progress_interval{
+            display_stream_progress(fp, size_estimate, start_time);
+            time2display = Instant::now();
+        }
+        if stop_flag.as_ref().is_some_and(|stop_flag| stop_flag.load(Ordering::Relaxed)){
+            stats.interrupted = true;
+            break;
+        }
+    }
+    if !quiet && !no_progress{
+        eprintln!(); // To skip line after the progress line
+    }
+    stats.elapsed = start_time.elapsed();
+    stats.end_offset = fp;
+    if !stats.interrupted && fp != dst_size && !is_block_device(dst_path){
+        if dry_run{
+            log::info!("DRY RUN: would truncate {:?} from {} to {} bytes", dst_path, dst_size, fp);
+        }else if existed && !truncate_dest{
+            return Err(SyncError::TruncateRefused { path: dst_path.to_path_buf(), dst_size, src_size: fp });
+        }else{
+            if existed{
+                log::warn!("Warning: truncating {:?} from {} to {} bytes, the stream length", dst_path, dst_size, fp);
+            }
+            if let Err(err) = platform::truncate_file(dst_file.as_raw_fd(), fp){
+                return Err(SyncError::TruncateFailed(dst_path.to_path_buf(), err));
+            }
+        }
+    }
+    if !dry_run{
+        let flush_start = Instant::now();
+        let result = match flush_mode{
+            FlushMode::None => Ok(()),
+            FlushMode::Fsync => dst_file.sync_all(),
+            FlushMode::Fdatasync => dst_file.sync_data(),
+        };
+        if let Err(err) = result{
+            return Err(SyncError::FlushFailed(dst_path.to_path_buf(), err));
+        }
+        stats.flush_elapsed = flush_start.elapsed();
+    }
+    stats.destinations.push(DestinationStats { path: dst_path.to_path_buf(), bytes_written: stats.bytes_written, written_ranges, error: None });
+    Ok(stats)
+}
+
+/// Copy src_path to dst, e.g. stdout, as is. There is no destination to compare with, everything is written.
+pub fn copy_to_stream<W: Write>(src_path: &Path, mut dst: W, opts: SyncOptions) -> Result<SyncStats, SyncError> {
+    let SyncOptions { buffer_size, quiet, no_progress, progress_interval, stop_flag, .. } = opts;
+    check_unsupported(&[
+        (opts.dry_run, "dry run"),
+        (opts.verify, "verify"),
+        (opts.write_verify_each, "write verify each"),
+        (opts.write_barrier, "write barrier"),
+        (opts.use_sendfile, "sendfile"),
+        (opts.atomic, "atomic"),
+        (opts.checkpoint_path.is_some(), "resume"),
+        (opts.segment_size.is_some(), "checkpointed write"),
+        (opts.seed_from.is_some(), "seed from"),
+        (opts.output_delta.is_some(), "output delta"),
+        (opts.write_journal.is_some(), "write journal"),
+        (opts.block_map.is_some(), "block map"),
+        (opts.sparse || opts.trim, "sparse and trim"),
+        (opts.metadata, "metadata"),
+        (opts.json, "json"),
+        (!opts.priority_regions.is_empty(), "priority regions"),
+        (opts.offset != 0 || opts.length.is_some() || opts.source_offset != 0 || !opts.exclude_ranges.is_empty(), "offset, length and exclude ranges"),
+    ], "when writing the destination to a stream")?;
+    let mut src_file = match File::open(src_path){
+        Ok(src_file) => src_file,
+        Err(err) => return Err(SyncError::SourceOpenFailed(src_path.to_path_buf(), err)),
+    };
+    let src_size = filesize(src_path)?;
+    log::info!("Copying {:?} to stream", src_path);
+    log::info!("{}: {} [{:.1} MB]", src_path.display(), src_size, src_size as f64 / 1024. / 1024.);
+    let mut stats = SyncStats::default();
+    let mut buffer = vec![0u8; buffer_size];
+    let start_time = Instant::now();
+    let mut time2display = Instant::now();
+    loop{
+        let len = match read_full(&mut src_file, &mut buffer){
+            Ok(len) => len,
+            Err(err) => return Err(SyncError::SourceReadFailed { offset: stats.bytes_compared, source: err }),
+        };
+        if len == 0{
+            break;
+        }
+        let write_start = Instant::now();
+        if let Err(err) = dst.write_all(&buffer[0 .. len]){
+            return Err(SyncError::WriteFailed { offset: stats.bytes_compared, source: err });
+        }
+        stats.write_elapsed += write_start.elapsed();
+        stats.bytes_compared += len as u64;
+        stats.bytes_written += len as u64;
+        stats.write_calls += 1;
+        *stats.write_sizes.entry(len).or_default() += 1;
+        if !quiet && !no_progress && time2display.elapsed() >= progress_interval{
+            display_stream_progress(stats.bytes_written, None, start_time);
+            time2display = Instant::now();
+        }
+        if stop_flag.as_ref().is_some_and(|stop_flag| stop_flag.load(Ordering::Relaxed)){
+            stats.interrupted = true;
+            break;
+        }
+    }
+    if let Err(err) = dst.flush(){
+        return Err(SyncError::WriteFailed { offset: stats.bytes_written, source: err });
+    }
+    if !quiet && !no_progress{
+        eprintln!();
+    }
+    stats.elapsed = start_time.elapsed();
+    stats.end_offset = stats.bytes_compared;
+    Ok(stats)
+}
@@ -0,0 +1,278 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Full screen terminal user interface to follow a sync: progress, speed, counters and a scrollable log.
+//! The sync runs in a thread and sends its `SyncEvent`s to the interface, which redraws 10 times per second.
+use std::io::{self, prelude::*};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use crate::{format_hms, SyncError, SyncEvent, SyncOptions, SyncStats};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// Shortest window of the current speed, shorter ones jump around with the buffer reads
+const SPEED_WINDOW: Duration = Duration::from_secs(1);
+/// Log lines kept for scrolling
+const MAX_LOG_LINES: usize = 10000;
+
+/// Log target that sends each log record to the interface, or writes it to stderr once the interface is closed
+pub struct LogPipe(Sender<String>);
+
+impl LogPipe {
+    /// Pipe and the receiver of its lines to give to run
+    pub fn new() -> (LogPipe, Receiver<String>) {
+        let (sender, receiver) = mpsc::channel();
+        (LogPipe(sender), receiver)
+    }
+}
+
+impl Write for LogPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.0.send(String::from_utf8_lossy(buf).into_owned()).is_err(){
+            io::stderr().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// What the interface shows
+struct TuiState {
+    title: String,
+    start_time: Instant,
+    offset: u64,
+    start: u64,
+    end: u64,
+    bytes_compared: u64,
+    bytes_written: u64,
+    chunks_different: u64,
+    errors: u64,
+    last_error: Option<String>,
+    /// Start of the current speed window, bytes compared then
+    speed_sample: (Instant, u64),
+    speed: f64,
+    peak_speed: f64,
+    log: Vec<Line<'static>>,
+    /// Log lines scrolled up from the bottom
+    scroll: usize,
+    log_height: usize,
+    stopping: bool,
+}
+
+impl TuiState {
+    fn new(title: String) -> Self {
+        let now = Instant::now();
+        TuiState {
+            title,
+            start_time: now,
+            offset: 0,
+            start: 0,
+            end: 0,
+            bytes_compared: 0,
+            bytes_written: 0,
+            chunks_different: 0,
+            errors: 0,
+            last_error: None,
+            speed_sample: (now, 0),
+            speed: 0.,
+            peak_speed: 0.,
+            log: Vec::new(),
+            scroll: 0,
+            log_height: 0,
+            stopping: false,
+        }
+    }
+
+    fn push_log(&mut self, line: Line<'static>){
+        if self.log.len() == MAX_LOG_LINES{
+            self.log.remove(0);
+        }
+        self.log.push(line);
+        if self.scroll > 0{
+            // Keep the scrolled view still
+            self.scroll += 1;
+        }
+    }
+
+    fn handle(&mut self, event: SyncEvent){
+        match event{
+            SyncEvent::Progress { offset, start, end, bytes_compared, bytes_written, chunks_different } => {
+                (self.offset, self.start, self.end) = (offset, start, end);
+                (self.bytes_compared, self.bytes_written, self.chunks_different) = (bytes_compared, bytes_written, chunks_different);
+                let (sample_time, sample_bytes) = self.speed_sample;
+                let elapsed = sample_time.elapsed();
+                if elapsed >= SPEED_WINDOW{
+                    self.speed = (bytes_compared - sample_bytes) as f64 / elapsed.as_secs_f64() / 1024. / 1024.;
+                    self.peak_speed = self.peak_speed.max(self.speed);
+                    self.speed_sample = (Instant::now(), bytes_compared);
+                }
+            },
+            SyncEvent::Error { offset, message } => {
+                self.errors += 1;
+                self.last_error = Some(format!("at offset {}: {}", offset, message));
+            },
+            SyncEvent::Done(_) => (),
+        }
+    }
+
+    fn scroll_by(&mut self, lines: isize){
+        let max_scroll = self.log.len().saturating_sub(self.log_height);
+        self.scroll = self.scroll.saturating_add_signed(lines).min(max_scroll);
+    }
+
+    fn render(&mut self, frame: &mut Frame){
+        let [title_area, gauge_area, stats_area, log_area, help_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(3),
+            Constraint::Length(6),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+        frame.render_widget(Line::from(self.title.as_str()).bold(), title_area);
+
+        let range = self.end.saturating_sub(self.start);
+        let done = self.offset.saturating_sub(self.start);
+        let ratio = if range == 0 {0.} else {(done as f64 / range as f64).clamp(0., 1.)};
+        let gauge = Gauge::default()
+            .block(Block::bordered().title(if self.stopping {" Progress - stopping "} else {" Progress "}))
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio(ratio)
+            .label(format!("{:.1}%", ratio * 100.));
+        frame.render_widget(gauge, gauge_area);
+
+        // Remaining time at the average speed, steadier than the current one
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        let remaining = if done == 0 {"--:--:--".to_string()} else {format_hms((elapsed * (range - done.min(range)) as f64 / done as f64) as u64)};
+        let mb = |bytes: u64| bytes as f64 / 1024. / 1024.;
+        let mut stats = vec![
+            Line::from(format!("Speed: {:.1} MB/s - Peak: {:.1} MB/s - Elapsed: {} - Remaining: {}", self.speed, self.peak_speed, format_hms(elapsed as u64), remaining)),
+            Line::from(format!("Compared: {} [{:.1} MB] - Written: {} [{:.1} MB]", self.bytes_compared, mb(self.bytes_compared), self.bytes_written, mb(self.bytes_written))),
+            Line::from(format!("Differing chunks: {} - Errors: {}", self.chunks_different, self.errors)),
+        ];
+        if let Some(last_error) = &self.last_error{
+            stats.push(Line::from(format!("Last error {}", last_error)).red());
+        }
+        frame.render_widget(Paragraph::new(stats).block(Block::bordered().title(" Stats ")), stats_area);
+
+        self.log_height = log_area.height.saturating_sub(2) as usize;
+        self.scroll_by(0);
+        let top = self.log.len().saturating_sub(self.log_height + self.scroll);
+        let title = if self.scroll > 0 {format!(" Log - {} lines below ", self.scroll)} else {" Log ".to_string()};
+        let log = Paragraph::new(self.log[top ..].to_vec()).block(Block::bordered().title(title));
+        frame.render_widget(log, log_area);
+        frame.render_widget(Line::from("q: stop - Up/Down/PgUp/PgDn: scroll log - End: follow log").dim(), help_area);
+    }
+}
+
+/// Run sync(opts) in a thread while showing its progress in the terminal, and return its result.
+/// logs are the lines of a LogPipe, shown in the log pane. q, Esc or Ctrl-C set the stop flag of opts
+/// (created if there is none), the sync then stops after the current buffer.
+pub fn run<F>(title: String, opts: SyncOptions, logs: Receiver<String>, sync: F) -> Result<SyncStats, SyncError>
+where F: FnOnce(SyncOptions) -> Result<SyncStats, SyncError> + Send + 'static {
+    let stop_flag = opts.stop_flag.clone().unwrap_or_default();
+    let (events_sender, events) = mpsc::channel();
+    // Progress is shown here, not printed
+    let opts = opts.stop_flag(Some(Arc::clone(&stop_flag))).events(Some(events_sender.clone())).no_progress(true).progress_bar(false);
+    let sync_thread = thread::spawn(move || {
+        let result = sync(opts);
+        let _ = events_sender.send(SyncEvent::Done(result));
+    });
+    let mut state = TuiState::new(title);
+    let result = match ratatui::try_init(){
+        Ok(mut terminal) => {
+            let result = tui_loop(&mut terminal, &mut state, &events, &logs, &stop_flag);
+            ratatui::restore();
+            result
+        },
+        Err(err) => Err(err),
+    };
+    drop(logs); // Logs go to stderr again
+    let result = match result{
+        Ok(result) => result,
+        Err(err) => {
+            log::warn!("Warning: terminal interface failed: {}, waiting for the sync to finish", err);
+            wait_done(&events)
+        },
+    };
+    let _ = sync_thread.join();
+    result
+}
+
+/// Redraw and handle keys until the sync is done
+fn tui_loop(terminal: &mut DefaultTerminal, state: &mut TuiState, events: &Receiver<SyncEvent>, logs: &Receiver<String>, stop_flag: &AtomicBool) -> io::Result<Result<SyncStats, SyncError>> {
+    loop{
+        loop{
+            match events.try_recv(){
+                Ok(SyncEvent::Done(result)) => return Ok(result),
+                Ok(event) => state.handle(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(Err(SyncError::Io(io::Error::other("sync thread stopped without a result")))),
+            }
+        }
+        while let Ok(text) = logs.try_recv(){
+            for line in text.lines(){
+                let warning = line.starts_with("Warning");
+                let line = Line::from(line.to_string());
+                state.push_log(if warning {line.yellow()} else {line});
+            }
+        }
+        terminal.draw(|frame| state.render(frame))?;
+        if !event::poll(REDRAW_INTERVAL)?{
+            continue;
+        }
+        // Resize needs nothing more than the next draw
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press{
+            continue;
+        }
+        let page = state.log_height.max(1) as isize;
+        match key.code{
+            KeyCode::Char('q') | KeyCode::Esc => (),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => (),
+            KeyCode::Up => { state.scroll_by(1); continue; },
+            KeyCode::Down => { state.scroll_by(-1); continue; },
+            KeyCode::PageUp => { state.scroll_by(page); continue; },
+            KeyCode::PageDown => { state.scroll_by(-page); continue; },
+            KeyCode::End => { state.scroll = 0; continue; },
+            _ => continue,
+        }
+        // In raw mode Ctrl-C is a key, not a signal
+        stop_flag.store(true, Ordering::Relaxed);
+        state.stopping = true;
+    }
+}
+
+/// Wait for the result of the sync without the interface
+fn wait_done(events: &Receiver<SyncEvent>) -> Result<SyncStats, SyncError> {
+    loop{
+        match events.recv(){
+            Ok(SyncEvent::Done(result)) => return result,
+            Ok(_) => (),
+            Err(_) => return Err(SyncError::Io(io::Error::other("sync thread stopped without a result"))),
+        }
+    }
+}
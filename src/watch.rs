@@ -0,0 +1,71 @@
+/*
+ This program is free software: you can redistribute it and/or modify it under
+ the terms of the GNU General Public License as published by the Free Software
+ Foundation, either version 3 of the License, or (at your option) any later
+ version.
+
+ This program is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY; without even the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ See the GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License along with
+ this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Watch the source for changes, to sync it again in watch mode. Linux only, with inotify.
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use inotify::{Inotify, WatchMask};
+
+/// Time between two checks of the stop flag while waiting for a change
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Inotify watch of the source path
+pub struct SourceWatcher {
+    inotify: Inotify,
+    buffer: [u8; 4096],
+}
+
+impl SourceWatcher {
+    /// Start watching path for writes. Create it before the sync so changes made during the sync are not missed.
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let inotify = Inotify::init()?;
+        inotify.watches().add(path, WatchMask::MODIFY | WatchMask::CLOSE_WRITE)?;
+        Ok(SourceWatcher { inotify, buffer: [0; 4096] })
+    }
+
+    /// True if events are pending, they are consumed
+    fn drain_events(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        loop{
+            match self.inotify.read_events(&mut self.buffer){
+                Ok(mut events) => changed |= events.next().is_some(),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Wait until the source changed and no other change happened for debounce.
+    /// Return false if stop_flag was set before.
+    pub fn wait_for_change(&mut self, debounce: Duration, stop_flag: &AtomicBool) -> io::Result<bool> {
+        let mut last_change = None;
+        loop{
+            if stop_flag.load(Ordering::Relaxed){
+                return Ok(false);
+            }
+            if self.drain_events()?{
+                last_change = Some(Instant::now());
+            }
+            match last_change{
+                Some(last_change) if last_change.elapsed() >= debounce => return Ok(true),
+                Some(last_change) => thread::sleep(POLL_INTERVAL.min(debounce.saturating_sub(last_change.elapsed()))),
+                None => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use localblocksync::{sync_paths, BufferPool, SyncOptions};
+
+/// Source and destination files of size bytes in the temporary directory, removed on drop
+struct TestFiles {
+    src: PathBuf,
+    dst: PathBuf,
+}
+
+impl TestFiles {
+    fn new(name: &str, size: usize) -> Self {
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!("localblocksync-{}-{}-src", name, std::process::id()));
+        let dst = dir.join(format!("localblocksync-{}-{}-dst", name, std::process::id()));
+        let data: Vec<u8> = (0 .. size).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(&src, data).unwrap();
+        let _ = fs::remove_file(&dst);
+        TestFiles { src, dst }
+    }
+}
+
+impl Drop for TestFiles {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.src);
+        let _ = fs::remove_file(&self.dst);
+    }
+}
+
+fn options(pool: &Arc<BufferPool>) -> SyncOptions {
+    SyncOptions::new().quiet(true).no_progress(true).buffer_size(64 * 1024).chunk_size(4096).buffer_pool(Some(Arc::clone(pool)))
+}
+
+#[test]
+fn sequential_syncs_reuse_buffers() {
+    let files = TestFiles::new("pool", 300 * 1024);
+    let pool = Arc::new(BufferPool::new());
+    sync_paths(&files.src, &files.dst, options(&pool)).unwrap();
+    assert_eq!(fs::read(&files.src).unwrap(), fs::read(&files.dst).unwrap());
+    // Source and destination buffers
+    assert_eq!(pool.allocations(), 2);
+    assert_eq!(pool.cached(), 2);
+    for _ in 0 .. 5{
+        fs::remove_file(&files.dst).unwrap();
+        sync_paths(&files.src, &files.dst, options(&pool)).unwrap();
+        assert_eq!(fs::read(&files.src).unwrap(), fs::read(&files.dst).unwrap());
+    }
+    assert_eq!(pool.allocations(), 2);
+    assert_eq!(pool.cached(), 2);
+}
+
+#[test]
+fn read_pipeline_reuses_buffers() {
+    let files = TestFiles::new("pool-pipeline", 300 * 1024);
+    let pool = Arc::new(BufferPool::new());
+    sync_paths(&files.src, &files.dst, options(&pool).buffer_count(3)).unwrap();
+    assert_eq!(pool.allocations(), 6);
+    for _ in 0 .. 5{
+        fs::remove_file(&files.dst).unwrap();
+        sync_paths(&files.src, &files.dst, options(&pool).buffer_count(3)).unwrap();
+        assert_eq!(fs::read(&files.src).unwrap(), fs::read(&files.dst).unwrap());
+    }
+    assert_eq!(pool.allocations(), 6);
+    assert_eq!(pool.cached(), 6);
+}
+
+#[test]
+fn buffers_of_another_size_are_allocated() {
+    let pool = Arc::new(BufferPool::new());
+    let buffer = pool.acquire(4096);
+    assert_eq!(buffer.len(), 4096);
+    drop(buffer);
+    let mut buffer = pool.acquire(4096);
+    assert_eq!(pool.allocations(), 1);
+    buffer[0] = 1;
+    drop(buffer);
+    // A reused buffer is zeroed again
+    assert!(pool.acquire(4096).iter().all(|&byte| byte == 0));
+    let _other = pool.acquire(8192);
+    assert_eq!(pool.allocations(), 2);
+}
@@ -0,0 +1,29 @@
+use std::time::Duration;
+use localblocksync::average_speed_eta;
+
+#[test]
+fn first_call_is_finite() {
+    // Called within the first second, even at the very start
+    let (speed_mb, remaining_secs) = average_speed_eta(1024. * 1024., 10. * 1024. * 1024., Duration::ZERO);
+    assert!(speed_mb.is_finite());
+    assert!(remaining_secs.is_some_and(f64::is_finite));
+    let (speed_mb, _) = average_speed_eta(1024. * 1024., 10. * 1024. * 1024., Duration::from_millis(500));
+    assert_eq!(speed_mb, 2.);
+}
+
+#[test]
+fn no_remaining_time_before_reading() {
+    let (speed_mb, remaining_secs) = average_speed_eta(0., 10. * 1024. * 1024., Duration::ZERO);
+    assert_eq!(speed_mb, 0.);
+    assert_eq!(remaining_secs, None);
+}
+
+#[test]
+fn remaining_time_from_average_speed() {
+    // A quarter done in 10 seconds
+    let (speed_mb, remaining_secs) = average_speed_eta(25. * 1024. * 1024., 100. * 1024. * 1024., Duration::from_secs(10));
+    assert_eq!(speed_mb, 2.5);
+    assert_eq!(remaining_secs, Some(30.));
+    let (_, remaining_secs) = average_speed_eta(100. * 1024. * 1024., 100. * 1024. * 1024., Duration::from_secs(10));
+    assert_eq!(remaining_secs, Some(0.));
+}